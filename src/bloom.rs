@@ -0,0 +1,124 @@
+/*
+Bloom filter over the windows of an SA's text, used by build_matches to skip the SA binary search
+for train windows that can't be in the val set.
+
+The filter is tied to one window shape (width and step, both in bytes) and is persisted next to the
+table as {data_file}.bloom.bin:
+- 4 bytes magic b"SABF"
+- u32 LE window width, u32 LE step, u32 LE number of hashes, u64 LE number of bits
+- the bits, as u64 LE words
+A file whose header disagrees with what's asked for is rebuilt over.
+
+Hashes are FNV-1a plus a fixed mixer (not std's hasher, which may change between releases), so a
+persisted filter stays valid across builds.
+*/
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Error, Result};
+use rayon::prelude::*;
+
+const BLOOM_MAGIC: &[u8; 4] = b"SABF";
+const BLOOM_HEADER_LEN: usize = 24;
+
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: u32
+}
+
+
+fn hash_pair(bytes: &[u8]) -> (u64, u64) {
+    // FNV-1a, then splitmix64 on that for the second hash (forced odd so probes never stall)
+    let mut h1: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h1 ^= *b as u64;
+        h1 = h1.wrapping_mul(0x100000001b3);
+    }
+    let mut h2 = h1.wrapping_add(0x9e3779b97f4a7c15);
+    h2 = (h2 ^ (h2 >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h2 = (h2 ^ (h2 >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h2 ^= h2 >> 31;
+    (h1, h2 | 1)
+}
+
+
+impl BloomFilter {
+    pub fn build(text: &[u8], width: usize, step: usize, bits_per_window: usize, num_hashes: u32) -> Self {
+        // Inserts every width-byte window of text starting at a multiple of step
+        let num_windows = (text.len() + 1).saturating_sub(width).div_ceil(step);
+        let num_words = std::cmp::max((num_windows * bits_per_window).div_ceil(64), 1);
+        let words: Vec<AtomicU64> = (0..num_words).map(|_| AtomicU64::new(0)).collect();
+        let num_bits = (num_words * 64) as u64;
+        (0..num_windows).into_par_iter().for_each(|i| {
+            let (h1, h2) = hash_pair(&text[i * step..i * step + width]);
+            for k in 0..num_hashes as u64 {
+                let bit = h1.wrapping_add(k.wrapping_mul(h2)) % num_bits;
+                words[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+            }
+        });
+        BloomFilter {bits: words.into_iter().map(|w| w.into_inner()).collect(), num_hashes}
+    }
+
+    pub fn contains(&self, window: &[u8]) -> bool {
+        let num_bits = (self.bits.len() * 64) as u64;
+        let (h1, h2) = hash_pair(window);
+        (0..self.num_hashes as u64).all(|k| {
+            let bit = h1.wrapping_add(k.wrapping_mul(h2)) % num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    fn to_bytes(&self, width: usize, step: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BLOOM_HEADER_LEN + self.bits.len() * 8);
+        bytes.extend_from_slice(BLOOM_MAGIC);
+        bytes.extend_from_slice(&(width as u32).to_le_bytes());
+        bytes.extend_from_slice(&(step as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.num_hashes.to_le_bytes());
+        bytes.extend_from_slice(&((self.bits.len() * 64) as u64).to_le_bytes());
+        for word in &self.bits {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], width: usize, step: usize, num_bits: u64, num_hashes: u32) -> Option<Self> {
+        // None unless the header matches the filter being asked for exactly
+        if bytes.len() < BLOOM_HEADER_LEN || &bytes[..4] != BLOOM_MAGIC {
+            return None;
+        }
+        let field = |start: usize, len: usize| bytes[start..start + len].iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        if field(4, 4) != width as u64 || field(8, 4) != step as u64 || field(12, 4) != num_hashes as u64
+            || field(16, 8) != num_bits || bytes.len() != BLOOM_HEADER_LEN + (num_bits / 8) as usize {
+            return None;
+        }
+        let bits = bytes[BLOOM_HEADER_LEN..].chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Some(BloomFilter {bits, num_hashes})
+    }
+}
+
+
+fn bloom_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.bloom.bin", data_file.display()))
+}
+
+
+pub fn load_or_build_bloom(data_file: &Path, text: &[u8], width: usize, step: usize, bits_per_window: usize,
+                           num_hashes: u32) -> Result<(BloomFilter, bool), Error> {
+    // Reuses the persisted filter if it was built with these settings, otherwise builds and persists
+    // a new one. Also returns whether it was rebuilt
+    let num_windows = (text.len() + 1).saturating_sub(width).div_ceil(step);
+    let num_bits = (std::cmp::max((num_windows * bits_per_window).div_ceil(64), 1) * 64) as u64;
+    let path = bloom_path(data_file);
+    if let Ok(bytes) = fs::read(&path) {
+        if let Some(bloom) = BloomFilter::from_bytes(&bytes, width, step, num_bits, num_hashes) {
+            return Ok((bloom, false));
+        }
+    }
+    let bloom = BloomFilter::build(text, width, step, bits_per_window, num_hashes);
+    fs::write(&path, bloom.to_bytes(width, step))?;
+    Ok((bloom, true))
+}
@@ -59,6 +59,9 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::cmp::Reverse;
 use std::convert::TryInto;
+use std::ops::Deref;
+use memmap2::Mmap;
+use anyhow::{anyhow, Error};
 
 extern crate filebuffer;
 extern crate zstd;
@@ -1650,36 +1653,164 @@ fn main()  -> std::io::Result<()> {
 =======================================================*/
 
 
-pub fn load_sa_into_memory(fpath: &PathBuf) -> (Vec<u8>, u64, Vec<u8>, u64, usize) {
+/* Tables written by BuildSa start with TABLE_MAGIC and the u32 LE size_width. Headerless tables
+ * (e.g. from cmd_make) still load, with size_width inferred as table_len / text_len. */
+const TABLE_MAGIC: &[u8; 4] = b"SATB";
+pub const TABLE_HEADER_LEN: usize = 8;
+
+pub fn table_header(size_width: usize) -> Vec<u8> {
+    let mut header = TABLE_MAGIC.to_vec();
+    header.extend((size_width as u32).to_le_bytes());
+    header
+}
+
+pub fn max_table_offset(size_width: usize) -> u64 {
+    // Largest text position a size_width-byte table entry can hold
+    if size_width >= 8 { u64::MAX } else { (1u64 << (8 * size_width)) - 1 }
+}
+
+fn table_layout(table_file: &mut File, size_text: u64) -> Result<(usize, u64, usize), Error> {
+    // Returns (header_len, size_table, size_width) for the table, where size_table excludes the header,
+    // validating its length against the text and that every text position fits in size_width bytes
+    let (header_len, size_table, size_width) = table_layout_unchecked(table_file, size_text)?;
+    if size_text > 0 && size_text - 1 > max_table_offset(size_width) {
+        return Err(anyhow!("Text is {} bytes but {}-byte table entries only reach offset {}. Rebuild the table with a wider size_width",
+                           size_text, size_width, max_table_offset(size_width)));
+    }
+    Ok((header_len, size_table, size_width))
+}
+
+fn table_layout_unchecked(table_file: &mut File, size_text: u64) -> Result<(usize, u64, usize), Error> {
+    let file_len = table_file.metadata()?.len();
+    let mut header = [0u8; TABLE_HEADER_LEN];
+    if file_len >= TABLE_HEADER_LEN as u64 {
+        table_file.read_exact(&mut header)?;
+        table_file.seek(std::io::SeekFrom::Start(0))?;
+    }
+    if &header[..4] == TABLE_MAGIC {
+        let size_width = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let body_len = file_len - TABLE_HEADER_LEN as u64;
+        if size_width == 0 || size_width > 8 {
+            return Err(anyhow!("Table header declares size_width {}, expected 1..=8", size_width));
+        }
+        if body_len % size_width as u64 != 0 || body_len / size_width as u64 != size_text {
+            return Err(anyhow!("Table body is {} bytes, which isn't {} entries of the declared size_width {}",
+                               body_len, size_text, size_width));
+        }
+        Ok((TABLE_HEADER_LEN, body_len, size_width))
+    } else {
+        if size_text == 0 || file_len % size_text != 0 || file_len / size_text > 8 {
+            return Err(anyhow!("Headerless table of {} bytes doesn't match text of {} bytes", file_len, size_text));
+        }
+        Ok((0, file_len, (file_len / size_text) as usize))
+    }
+}
+
+pub fn sa_layout(text_path: &PathBuf, table_path: &PathBuf) -> Result<(u64, usize), Error> {
+    // (size_text, size_width) of an SA without loading it
+    let size_text = fs::metadata(text_path)?.len();
+    let mut table_file = fs::File::open(table_path)?;
+    let (_, _, size_width) = table_layout(&mut table_file, size_text)?;
+    Ok((size_text, size_width))
+}
+
+// (text, size_text, table, size_table, size_width), as load_sa_into_memory gives them
+pub type SaInMemory = (Vec<u8>, u64, Vec<u8>, u64, usize);
+
+pub fn load_sa_into_memory(text_path: &PathBuf, table_path: &PathBuf) -> Result<SaInMemory, Error> {
     // Loads everything we need to get lookups (should be done once!)
-    // output is (text, size_text, table, size_table, size_width)
-    let metadata_text = fs::metadata(format!("{}", fpath.display())).unwrap();
-    let metadata_table = fs::metadata(format!("{}.table.bin", fpath.display())).unwrap();
+    // output is (text, size_text, table, size_table, size_width), where table excludes any header.
+    // The table is usually at {text_path}.table.bin, but needn't be
+    let metadata_text = fs::metadata(text_path)?;
+    let mut table_file = fs::File::open(table_path)?;
     let size_text = metadata_text.len();
-    let size_table = metadata_table.len();
-    assert!(size_table % (size_text) == 0);
-    let size_width: usize = (size_table / size_text).try_into().unwrap();
+    let (header_len, size_table, size_width) = table_layout(&mut table_file, size_text)?;
 
     let mut text = Vec::with_capacity(size_text as usize);
-    fs::File::open(format!("{}", fpath.display())).unwrap().read_to_end(&mut text).unwrap();
+    fs::File::open(text_path)?.read_to_end(&mut text)?;
 
     let mut table = Vec::with_capacity(size_table as usize);
-    fs::File::open(format!("{}.table.bin", fpath.display())).unwrap().read_to_end(&mut table).unwrap();    
-    (text, size_text, table, size_table, size_width)
+    table_file.seek(std::io::SeekFrom::Start(header_len as u64))?;
+    table_file.read_to_end(&mut table)?;
+    Ok((text, size_text, table, size_table, size_width))
+}
+
+pub fn load_sa_mmap(text_path: &PathBuf, table_path: &PathBuf) -> Result<(SaBytes, u64, SaBytes, u64, usize), Error> {
+    // Same as load_sa_into_memory, but maps text and table read-only instead of copying them into RAM.
+    // The files are used byte-for-byte, so they MUST be uncompressed on local disk
+    let text_file = fs::File::open(text_path)?;
+    let mut table_file = fs::File::open(table_path)?;
+    let size_text = text_file.metadata()?.len();
+    let (header_len, size_table, size_width) = table_layout(&mut table_file, size_text)?;
+
+    // Safety: these are read-only maps, and nothing should be rewriting an SA while we query it
+    let text = unsafe { Mmap::map(&text_file) }?;
+    let table = unsafe { Mmap::map(&table_file) }?;
+    Ok((SaBytes::Mapped(text, 0), size_text, SaBytes::Mapped(table, header_len), size_table, size_width))
+}
+
+pub enum SaBytes {
+    // Either an owned copy of an SA file or an mmap of it (plus a header offset); both deref to the raw bytes
+    Owned(Vec<u8>),
+    Mapped(Mmap, usize)
+}
+
+impl Deref for SaBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            SaBytes::Owned(bytes) => bytes,
+            SaBytes::Mapped(mmap, offset) => &mmap[*offset..]
+        }
+    }
 }
 
-pub fn load_size_object(size_object_path: &PathBuf) -> Vec<u64> {
+pub fn load_sa(text_path: &PathBuf, table_path: &PathBuf, mmap: bool) -> Result<(SaBytes, u64, SaBytes, u64, usize), Error> {
+    if mmap {
+        load_sa_mmap(text_path, table_path)
+    } else {
+        let (text, size_text, table, size_table, size_width) = load_sa_into_memory(text_path, table_path)?;
+        Ok((SaBytes::Owned(text), size_text, SaBytes::Owned(table), size_table, size_width))
+    }
+}
+
+pub struct SaShard {
+    // One shard of a sharded SA (or all of an unsharded one), whose text starts at offset in the full text
+    pub data_file: PathBuf,
+    pub offset: u64,
+    pub text: SaBytes,
+    pub size_text: u64,
+    pub table: SaBytes,
+    pub size_table: u64,
+    pub size_width: usize
+}
+
+pub fn load_size_object(size_object_path: &PathBuf) -> Result<Vec<u64>, Error> {
     // Loads the size object as a Vec<u64> 
     // This is basically a cumsum of size in bytes for all objects
     // (we want to binary search to find the document containing a particular index
-    let mut binary_data = Vec::new();
-    fs::File::open(size_object_path).unwrap().read_to_end(&mut binary_data).unwrap();
+    let binary_data = fs::read(size_object_path)
+        .map_err(|err| anyhow!("size object not found at {:?} ({}); was the SA built with this crate?", size_object_path, err))?;
+    if binary_data.len() % std::mem::size_of::<u64>() != 0 {
+        return Err(anyhow!("size object at {:?} is {} bytes, which isn't a whole number of u64 offsets. Is it truncated?",
+                           size_object_path, binary_data.len()));
+    }
     // Convert the binary data to a Vec<u64>
     let sizes: Vec<u64> = binary_data
         .chunks_exact(std::mem::size_of::<u64>())
         .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
         .collect();
-    sizes
+    Ok(sizes)
+}
+
+pub fn load_text_range(fpath: &PathBuf, start: u64, end: u64) -> Vec<u8> {
+    // Reads text[start..end] straight off disk, for when we only need a handful of docs
+    // and don't want to pull the whole text blob into memory
+    let mut file = fs::File::open(fpath).unwrap();
+    file.seek(std::io::SeekFrom::Start(start)).expect("Seek failed!");
+    let mut buf = vec![0u8; (end - start) as usize];
+    file.read_exact(&mut buf).unwrap();
+    buf
 }
 
 pub fn doc_lookup(pos: u64, size_object: &Vec<u64>) -> usize {
@@ -1704,17 +1835,74 @@ pub fn doc_lookup(pos: u64, size_object: &Vec<u64>) -> usize {
 }
 
 
-pub fn get_occurrences_memory(text: &[u8],
-                          size_text: u64,
-                          table: &[u8],
-                          size_table: u64,
-                          query: &[u8],
-                          size_width: usize) -> Vec<u64> {
+pub fn get_occurrences_memory<'a>(text: &[u8],
+                                  size_text: u64,
+                                  table: &'a [u8],
+                                  size_table: u64,
+                                  query: &[u8],
+                                  size_width: usize) -> impl Iterator<Item=u64> + 'a {
+    get_occurrences_memory_capped(text, size_text, table, size_table, query, size_width, None).unwrap()
+}
+
+pub fn get_occurrences_memory_capped<'a>(text: &[u8],
+                                         size_text: u64,
+                                         table: &'a [u8],
+                                         size_table: u64,
+                                         query: &[u8],
+                                         size_width: usize,
+                                         max_occurrences: Option<u64>) -> Option<impl Iterator<Item=u64> + 'a> {
+    // Same as get_occurrences_memory, but returns None if the query occurs more than max_occurrences times.
+    // Only the binary search runs up front; positions are read from the table as the iterator is pulled
+    let (start, end) = get_occurrence_range_memory(text, size_text, table, size_table, query, size_width);
+    if max_occurrences.is_some_and(|max| end - start > max) {
+        return None;
+    }
+    Some((start..end).map(move |idx| table_load(table, idx as usize, size_width) as u64))
+}
+
+pub fn get_occurrences_sharded<'a>(shards: impl IntoIterator<Item=&'a SaShard>,
+                                   query: &[u8],
+                                   max_occurrences: Option<u64>) -> Option<impl Iterator<Item=u64> + 'a> {
+    // get_occurrences_memory_capped over several shards, with positions rebased onto the full text.
+    // max_occurrences caps the total over all shards, so a sharded SA skips the same windows a whole one would
+    let ranges: Vec<(&SaShard, u64, u64)> = shards.into_iter()
+        .map(|shard| {
+            let (start, end) = get_occurrence_range_memory(&shard.text, shard.size_text, &shard.table, shard.size_table,
+                                                           query, shard.size_width);
+            (shard, start, end)
+        })
+        .filter(|(_, start, end)| start < end)
+        .collect();
+    if max_occurrences.is_some_and(|max| ranges.iter().map(|(_, start, end)| end - start).sum::<u64>() > max) {
+        return None;
+    }
+    Some(ranges.into_iter()
+        .flat_map(|(shard, start, end)| (start..end)
+            .map(move |idx| shard.offset + table_load(&shard.table, idx as usize, shard.size_width) as u64)))
+}
+
+pub fn count_occurrences_sharded<'a>(shards: impl IntoIterator<Item=&'a SaShard>, query: &[u8]) -> u64 {
+    // How many suffixes over all shards start with query, without reading any positions
+    shards.into_iter()
+        .map(|shard| {
+            let (start, end) = get_occurrence_range_memory(&shard.text, shard.size_text, &shard.table, shard.size_table,
+                                                           query, shard.size_width);
+            end - start
+        })
+        .sum()
+}
+
+fn get_occurrence_range_memory(text: &[u8],
+                               size_text: u64,
+                               table: &[u8],
+                               size_table: u64,
+                               query: &[u8],
+                               size_width: usize) -> (u64, u64) {
     // Hack of count_occurrences. :vomit-emoji:
-    // Actually gets the positions in text of where the query occurs
+    // Actually gets the [start, end) range of table indices whose suffixes start with the query
     let mut buf: &[u8];
     assert!(size_table % (size_width as u64) == 0);
-    let empty_output : Vec<u64> = Vec::new();
+    let empty_output = (0, 0);
     let mut low = 0;
     let mut high = size_table/(size_width as u64);
     while low < high {
@@ -1770,7 +1958,52 @@ pub fn get_occurrences_memory(text: &[u8],
         }
     }
 
-    (start..low)
-        .map(|idx| table_load(&table, idx as usize, size_width) as u64)
-        .collect()   
-}
\ No newline at end of file
+    (start, low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declared_table_widths_slice_correctly() {
+        let dir = std::env::temp_dir().join(format!("sa_decontamination_table_widths_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text = b"banana bandana";
+        let text_path = dir.join("text.bin");
+        fs::write(&text_path, text).unwrap();
+        let table = table::SuffixTable::new(&text[..]).into_parts().1.to_vec();
+        for size_width in [4, 5] {
+            let table_path = dir.join(format!("table_{}.bin", size_width));
+            let mut table_bytes = table_header(size_width);
+            table_bytes.extend(to_bytes(&table, size_width));
+            fs::write(&table_path, &table_bytes).unwrap();
+            let (text, size_text, table, size_table, loaded_width) = load_sa_into_memory(&text_path, &table_path).unwrap();
+            assert_eq!((loaded_width, size_table), (size_width, (size_width * text.len()) as u64));
+            let mut found: Vec<u64> = get_occurrences_memory(&text, size_text, &table, size_table, b"ana", loaded_width).collect();
+            found.sort();
+            assert_eq!(found, vec![1, 3, 11]);
+            // A byte short of the declared width is refused rather than read skewed
+            table_bytes.pop();
+            fs::write(&table_path, &table_bytes).unwrap();
+            assert!(load_sa_into_memory(&text_path, &table_path).is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn occurrences_are_read_lazily_and_capped() {
+        let text = b"ab ab ab ab ab cd";
+        let table = to_bytes(&table::SuffixTable::new(&text[..]).into_parts().1, 1);
+        let size_text = text.len() as u64;
+        let mut all: Vec<u64> = get_occurrences_memory(text, size_text, &table, size_text, b"ab", 1).collect();
+        all.sort();
+        assert_eq!(all, vec![0, 3, 6, 9, 12]);
+        // Pulling fewer positions than there are only reads those
+        assert_eq!(get_occurrences_memory(text, size_text, &table, size_text, b"ab", 1).take(2).count(), 2);
+        // Over max_occurrences there's no iterator at all, at it there is
+        assert!(get_occurrences_memory_capped(text, size_text, &table, size_text, b"ab", 1, Some(4)).is_none());
+        assert_eq!(get_occurrences_memory_capped(text, size_text, &table, size_text, b"ab", 1, Some(5)).unwrap().count(), 5);
+        assert_eq!(get_occurrences_memory(text, size_text, &table, size_text, b"zz", 1).count(), 0);
+    }
+}
@@ -17,22 +17,43 @@ We will ONLY ever be concerned about files that have extensions of:
 Compression schemes will always be inferred from extension
 */
 
-use std::fs::File;
-use crate::s3::{get_reader_from_s3, expand_s3_dir, write_cursor_to_s3};
-use anyhow::Error;
+use std::fs::{File, create_dir_all};
+use crate::s3::{get_reader_from_s3, get_bytes_from_s3, list_s3_page, write_cursor_to_s3, s3_object_exists, s3_object_size,
+                split_s3_path};
+use anyhow::{Context, Error};
 use anyhow::anyhow;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use crate::s3::is_s3;
+use tracing::{error, warn};
 use glob::glob;
 use flate2::read::MultiGzDecoder;
 use zstd::stream::read::Decoder as ZstdDecoder;
-use std::io::{BufReader, Cursor, Write, Read};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Write, Read};
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use zstd::stream::write::Encoder as ZstdEncoder;
+use crc32fast::hash as crc32;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinSet;
 
 const VALID_EXTS: &[&str] = &[".jsonl", ".jsonl.gz", ".jsonl.zstd", ".jsonl.zst"];
 
+
+fn s3_runtime() -> &'static tokio::runtime::Runtime {
+    // One runtime for every blocking s3 call, built on first use. block_on takes &self, so any thread
+    // (rayon workers included) can drive its own call on it at once
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build the s3 runtime"))
+}
+
 /*======================================================================
 =                              Listing files                           =
 ======================================================================*/
@@ -40,36 +61,81 @@ const VALID_EXTS: &[&str] = &[".jsonl", ".jsonl.gz", ".jsonl.zstd", ".jsonl.zst"
 
 
 
+trait S3Listing {
+    // The s3 calls expand_dirs makes, so listing a prefix can be run against keys held in memory
+    fn object_exists(&self, s3_uri: &Path) -> Result<bool, Error>;
+    fn list_page(&self, bucket: &str, prefix: &str, continuation_token: Option<String>
+                 ) -> Result<(Vec<String>, Option<String>), Error>;
+}
+
+
+struct LiveS3;
+
+impl S3Listing for LiveS3 {
+    fn object_exists(&self, s3_uri: &Path) -> Result<bool, Error> {
+        Ok(s3_runtime().block_on(s3_object_exists(s3_uri))?)
+    }
+
+    fn list_page(&self, bucket: &str, prefix: &str, continuation_token: Option<String>
+                 ) -> Result<(Vec<String>, Option<String>), Error> {
+        Ok(s3_runtime().block_on(list_s3_page(bucket, prefix, continuation_token))?)
+    }
+}
+
+
 pub(crate) fn expand_dirs(paths: Vec<PathBuf>, manual_ext: Option<&[&str]>) -> Result<Vec<PathBuf>, Error> {
+    expand_dirs_with(paths, manual_ext, &LiveS3)
+}
+
+
+fn expand_dirs_with(paths: Vec<PathBuf>, manual_ext: Option<&[&str]>, s3: &dyn S3Listing) -> Result<Vec<PathBuf>, Error> {
     // For local directories -> does a glob over each directory to get all files with given extension
-    // For s3 directories -> does an aws s3 ls to search for files
+    // For s3 directories -> pages through a listing of the keys under the prefix
     let exts = if !manual_ext.is_none() {
     	manual_ext.unwrap()
     } else {
     	VALID_EXTS
     };
     let mut files: Vec<PathBuf> = Vec::new();
-    let runtime = tokio::runtime::Runtime::new().unwrap();
     for path in paths {
         if is_s3(path.clone()) {
-            // Use async_std to block until we scour the s3 directory for files
-            runtime.block_on(async {
-                let s3_paths = expand_s3_dir(&path, exts).await.unwrap();
-                files.extend(s3_paths);                
-            });                
+            // Anything not naming a file is a directory-like prefix, so s3://bucket/data lists data/ and
+            // not data2/ too. A key without one of the wanted extensions that's an object itself is taken
+            // as the file it is
+            let path_str = path.to_string_lossy();
+            let prefix = match path_str.ends_with('/') || exts.iter().any(|ext| path_str.ends_with(ext)) {
+                true => path.clone(),
+                false => {
+                    if s3.object_exists(&path).with_context(|| format!("Failed to check {:?}", path))? {
+                        files.push(path.clone());
+                        continue;
+                    }
+                    PathBuf::from(format!("{}/", path_str))
+                }
+            };
+            let s3_paths = list_s3_files(s3, &prefix, exts).with_context(|| format!("Failed to list {:?}", path))?;
+            if s3_paths.is_empty() {
+                warn!("Found no files ending in any of {:?} under {:?}", exts, path);
+            }
+            files.extend(s3_paths);
         }
         else if path.is_dir() {
             let path_str = path
                 .to_str()
                 .ok_or_else(|| anyhow!("invalid path '{}'", path.to_string_lossy()))?;
+            let mut dir_files: Vec<PathBuf> = Vec::new();
         	for ext in exts {
         		let pattern = format!("{}/**/*{}", path_str, ext);
         		for entry in glob(&pattern).expect("Failed to read glob pattern") {
         			if let Ok(path) = entry {
-        				files.push(path)
+        				dir_files.push(path)
         			}
         		}
         	}
+            // Overlapping extensions (.gz and .jsonl.gz, say) would otherwise list a file twice
+            dir_files.sort();
+            dir_files.dedup();
+            files.extend(dir_files);
         } else {
             files.push(path.clone());
         }
@@ -78,23 +144,73 @@ pub(crate) fn expand_dirs(paths: Vec<PathBuf>, manual_ext: Option<&[&str]>) -> R
 }
 
 
+fn list_s3_files(s3: &dyn S3Listing, prefix: &Path, exts: &[&str]) -> Result<Vec<PathBuf>, Error> {
+    // Every key under an s3://bucket/prefix ending in one of exts, a page at a time
+    let (bucket, key_prefix) = split_s3_path(prefix);
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let (keys, next_token) = s3.list_page(&bucket, &key_prefix, continuation_token)?;
+        files.extend(keys.iter()
+            .filter(|key| exts.iter().any(|ext| key.ends_with(ext)))
+            .map(|key| PathBuf::from(format!("s3://{}/{}", bucket, key))));
+        match next_token {
+            Some(token) => continuation_token = Some(token),
+            None => return Ok(files)
+        }
+    }
+}
+
+
+pub(crate) fn path_exists(path: &PathBuf) -> bool {
+    // Local/s3 agnostic existence check for a single file
+    if is_s3(path) {
+        match s3_runtime().block_on(s3_object_exists(path)) {
+            Ok(exists) => exists,
+            Err(err) => {
+                error!("{:?}", err);
+                false
+            }
+        }
+    } else {
+        path.exists()
+    }
+}
+
+
 /*====================================================================
 =                           Reading files                            =
 ====================================================================*/
 
 
+pub(crate) fn path_size(path: &PathBuf) -> Result<u64, Error> {
+    // Local/s3 agnostic size of a single file in bytes, as stored (so compressed), without reading it
+    if is_s3(path) {
+        s3_runtime().block_on(s3_object_size(path))
+    } else {
+        Ok(std::fs::metadata(path).with_context(|| format!("Failed to stat {:?}", path))?.len())
+    }
+}
+
+
+pub(crate) fn is_stdin(path: &Path) -> bool {
+    // "-" stands for stdin (uncompressed jsonl only), and can only be consumed once per process
+    path.as_os_str() == "-"
+}
+
+
 pub(crate) fn read_pathbuf_to_mem(input_file: &PathBuf) -> Result<BufReader<Cursor<Vec<u8>>>, Error> {
-    // Generic method to read local or s3 file into memory
-    let reader = if is_s3(input_file) {
-        let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();   
-        match rt.block_on(get_reader_from_s3(input_file, Some(5))) {
+    // Generic method to read local or s3 file (or stdin) into memory
+    let reader = if is_stdin(input_file) {
+        let mut contents = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut contents)?;
+        BufReader::new(Cursor::new(contents))
+    } else if is_s3(input_file) {
+        match s3_runtime().block_on(get_reader_from_s3(input_file, None)) {
             Ok(result) => result,
             Err(err) => {
-                eprintln!("Error! {:?}", err);
-                return Err(err.into());
+                error!("{:?}", err);
+                return Err(err);
             }
         }
     } else {
@@ -106,6 +222,41 @@ pub(crate) fn read_pathbuf_to_mem(input_file: &PathBuf) -> Result<BufReader<Curs
 
 
 
+pub(crate) fn open_pathbuf_reader(input_file: &PathBuf) -> Result<Box<dyn Read>, Error> {
+    // read_pathbuf_streaming, for readers that don't need lines
+    Ok(Box::new(read_pathbuf_streaming(input_file)?))
+}
+
+
+pub(crate) fn read_pathbuf_streaming(input_file: &PathBuf) -> Result<Box<dyn BufRead>, Error> {
+    // Like read_pathbuf_to_mem, but decompressed as it's read instead of all at once, so a local file or stdin
+    // is never held whole and an s3 file only in its compressed form. For reading lines; the match and
+    // contaminate files that get deserialized in one go come through read_file_bytes
+    if is_stdin(input_file) {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    let source: Box<dyn Read> = match is_s3(input_file) {
+        true => Box::new(Cursor::new(s3_runtime().block_on(get_bytes_from_s3(input_file, None))?)),
+        false => Box::new(File::open(input_file).with_context(|| format!("Failed to open {:?}", input_file))?)
+    };
+    let ext = input_file.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+    Ok(match ext.as_str() {
+        "gz" => Box::new(BufReader::new(MultiGzDecoder::new(BufReader::new(source)))),
+        "zstd" | "zst" => Box::new(BufReader::new(ZstdDecoder::new(source)?)),
+        _ => Box::new(BufReader::new(source))
+    })
+}
+
+
+pub(crate) fn read_file_bytes(input_file: &PathBuf) -> Result<Vec<u8>, Error> {
+    // A whole local or s3 file (or stdin), decompressed, as owned bytes
+    let mut contents = Vec::new();
+    read_pathbuf_streaming(input_file)?.read_to_end(&mut contents)
+        .with_context(|| format!("Failed to read {:?}", input_file))?;
+    Ok(contents)
+}
+
+
 fn read_local_file_into_memory(input_file: &PathBuf) ->Result<Cursor<Vec<u8>>, Error>{
     // Takes a local file (must be local!) and reads it into a Cursor of bytes
     let mut file = File::open(input_file).expect("Failed to open file");
@@ -129,63 +280,359 @@ fn read_local_file_into_memory(input_file: &PathBuf) ->Result<Cursor<Vec<u8>>, E
 }
 
 
+// A prefetched file: its index in the list handed to prefetch_files, and its contents as read_pathbuf_to_mem gives them
+pub(crate) type Prefetched = (usize, Result<BufReader<Cursor<Vec<u8>>>, Error>);
+
+pub(crate) fn prefetch_files(paths: Vec<PathBuf>, concurrency: usize) -> (Receiver<Prefetched>, JoinHandle<Duration>) {
+    // Reads paths on a background runtime, at most concurrency at a time (s3 GETs async, anything else on a
+    // blocking thread), handing each over as soon as it's in, in whatever order they finish. A read only starts
+    // once one of concurrency slots is free, and a slot is only freed once its file is on the channel
+    // (which holds concurrency more), so at most 2 * concurrency files are held at once.
+    // Stops starting reads once the receiver is dropped. The handle gives the read time summed over files
+    let (sender, receiver) = tokio::sync::mpsc::channel(concurrency);
+    let handle = std::thread::spawn(move || {
+        s3_runtime().block_on(async move {
+            let slots = Arc::new(Semaphore::new(concurrency));
+            let read_nanos = Arc::new(AtomicU64::new(0));
+            let mut reads = JoinSet::new();
+            for (idx, path) in paths.into_iter().enumerate() {
+                let slot = slots.clone().acquire_owned().await.unwrap();
+                if sender.is_closed() {
+                    break;
+                }
+                let (sender, read_nanos) = (sender.clone(), read_nanos.clone());
+                reads.spawn(async move {
+                    let read_start = Instant::now();
+                    let data = match is_s3(&path) {
+                        true => get_reader_from_s3(&path, None).await,
+                        false => {
+                            let local_path = path.clone();
+                            tokio::task::spawn_blocking(move || read_pathbuf_to_mem(&local_path)).await
+                                .unwrap_or_else(|err| Err(anyhow!("Reader thread failed: {}", err)))
+                        }
+                    };
+                    read_nanos.fetch_add(read_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                    let _ = sender.send((idx, data.with_context(|| format!("Failed to read {:?}", path)))).await;
+                    drop(slot);
+                });
+            }
+            while reads.join_next().await.is_some() {}
+            Duration::from_nanos(read_nanos.load(Ordering::Relaxed))
+        })
+    });
+    (receiver, handle)
+}
+
+
 
 /*====================================================================
 =                          Writing files                             =
 ====================================================================*/
 
-pub(crate) fn get_output_filename(inputs: &[PathBuf], input_filename: &PathBuf, output_directory: &PathBuf) -> PathBuf {
+pub(crate) fn get_output_filename(inputs: &[PathBuf], input_filename: &Path, output_directory: &Path) -> PathBuf {
     // More fancy output-file naming that no longer assumes unique inputs
     let matching_prefix = inputs
         .iter()
         .find(|pfx| input_filename.starts_with(pfx))
         .expect("No matching prefix found?!?");
     let relative_path = input_filename.strip_prefix(matching_prefix).unwrap();
-    output_directory.clone().join(relative_path)
+    if relative_path.as_os_str().is_empty() {
+        // Input was a file rather than a directory, so keep just its name
+        return output_directory.join(input_filename.file_name().unwrap());
+    }
+    output_directory.join(relative_path)
 }
 
 
 pub(crate) fn write_mem_to_pathbuf(contents: &[u8], output_file: &PathBuf) -> Result<(), Error> {
-	let compressed_data = compress_data(contents.to_vec(), output_file);
+    write_mem_to_pathbuf_with_level(contents, output_file, None)
+}
+
+
+pub(crate) fn write_mem_to_pathbuf_with_level(contents: &[u8], output_file: &PathBuf, level: Option<i32>) -> Result<(), Error> {
+    write_to_pathbuf(output_file, level, |writer| Ok(writer.write_all(contents)?))
+}
+
+
+pub(crate) fn write_to_pathbuf(output_file: &PathBuf, level: Option<i32>,
+                               fill: impl FnOnce(&mut dyn Write) -> Result<(), Error>) -> Result<(), Error> {
+    // Hands fill a writer that compresses (per output_file's extension) on the way out. Local files are
+    // streamed through a BufWriter, so neither the raw nor the compressed bytes are ever held whole;
+    // s3 objects are PUT in one go, so those are compressed into memory first
     if is_s3(output_file) {
-        let cursor = Cursor::new(compressed_data);
-        let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();   
-        match rt.block_on(write_cursor_to_s3(&output_file, cursor)) {
+        let mut writer = CompressedWriter::new(Vec::new(), output_file, level)?;
+        fill(&mut writer)?;
+        let cursor = Cursor::new(writer.finish()?);
+        match s3_runtime().block_on(write_cursor_to_s3(&output_file, cursor)) {
             Ok(result) => result,
             Err(err) => {
-                eprintln!("Error! {:?}", err);
-                return Err(err.into());
+                error!("{:?}", err);
+                return Err(err);
             }
         };
     } else {
-        let mut file = File::create(output_file).expect(format!("Unable to create output file {:?}", output_file).as_str());
-        file.write_all(&compressed_data).expect(format!("Unable to write to {:?}", output_file).as_str());
-
+        if let Some(parent) = output_file.parent() {
+            create_dir_all(parent).with_context(|| format!("Unable to create output directory {:?}", parent))?;
+        }
+        let file = File::create(output_file).with_context(|| format!("Unable to create output file {:?}", output_file))?;
+        let mut writer = CompressedWriter::new(BufWriter::new(file), output_file, level)?;
+        fill(&mut writer).with_context(|| format!("Unable to write to {:?}", output_file))?;
+        writer.finish()?.flush().with_context(|| format!("Unable to write to {:?}", output_file))?;
     }
     Ok(())
 }
 
 
 
-fn compress_data(data: Vec<u8>, filename: &PathBuf) -> Vec<u8> {
-    // Given a filename with an extension, compresses a bytestream accordingly 
+pub(crate) const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+pub(crate) enum CompressedWriter<W: Write> {
+    // Compresses whatever is written to it per a filename's extension
     // {zst, zstd} -> zstandard, {gz} -> gzip, anything else -> nothing
-    let output_data = match filename.extension().unwrap().to_str() {
-        Some("gz") => {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&data).unwrap();            
-            encoder.finish().unwrap()
-        },
-        Some("zstd") | Some("zst") => {
-            let mut encoder = ZstdEncoder::new(Vec::new(), 0).unwrap();
-            encoder.write_all(&data).unwrap();            
-            encoder.finish().unwrap()
-        },
-        _ => {data}
-    };
-    output_data
+    Gzip(GzEncoder<W>),
+    Zstd(ZstdEncoder<'static, W>),
+    Raw(W)
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub(crate) fn new(inner: W, filename: &Path, level: Option<i32>) -> Result<Self, Error> {
+        // level is the codec's own: 0-9 for gzip (default 6), 1-22 for zstd (default DEFAULT_ZSTD_LEVEL)
+        Ok(match filename.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => match level {
+                Some(level) if !(0..=9).contains(&level) => return Err(anyhow!("gzip levels run 0-9, not {}", level)),
+                Some(level) => CompressedWriter::Gzip(GzEncoder::new(inner, Compression::new(level as u32))),
+                None => CompressedWriter::Gzip(GzEncoder::new(inner, Compression::default()))
+            },
+            Some("zstd") | Some("zst") => CompressedWriter::Zstd(ZstdEncoder::new(inner, level.unwrap_or(DEFAULT_ZSTD_LEVEL))?),
+            _ => CompressedWriter::Raw(inner)
+        })
+    }
+
+    pub(crate) fn finish(self) -> Result<W, Error> {
+        // Writes out the codec's trailer. Dropping the writer instead would lose any error doing so
+        Ok(match self {
+            CompressedWriter::Gzip(encoder) => encoder.finish()?,
+            CompressedWriter::Zstd(encoder) => encoder.finish()?,
+            CompressedWriter::Raw(inner) => inner
+        })
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressedWriter::Gzip(encoder) => encoder.write(buf),
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+            CompressedWriter::Raw(inner) => inner.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(encoder) => encoder.flush(),
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+            CompressedWriter::Raw(inner) => inner.flush()
+        }
+    }
+}
+
+
+pub(crate) fn compress_data(data: Vec<u8>, filename: &Path, level: Option<i32>) -> Result<Vec<u8>, Error> {
+    // Given a filename with an extension, compresses a bytestream accordingly, in memory
+    let mut writer = CompressedWriter::new(Vec::new(), filename, level)?;
+    writer.write_all(&data)?;
+    writer.finish()
+}
+
+
+
+
+/*====================================================================
+=                          Record framing                            =
+====================================================================*/
+/*
+Streamed records are framed as [len: u32 LE][crc32: u32 LE][payload; len bytes]
+so that a corrupt record can be detected and skipped, and a truncated stream
+is still readable up to its last complete record.
+*/
+
+const FRAME_HEADER_LEN: usize = 8;
+
+pub(crate) fn frame_record(payload: &[u8], buf: &mut Vec<u8>) {
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(crc32(payload).to_le_bytes());
+    buf.extend(payload);
 }
 
+
+pub(crate) fn unframe_records(data: &[u8], strict: bool) -> Result<Vec<&[u8]>, Error> {
+    // Walks the frames in data, skipping (and logging) any that fail their crc.
+    // In strict mode any bad or truncated record is an error instead
+    let mut records: Vec<&[u8]> = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        if offset + FRAME_HEADER_LEN > data.len() {
+            if strict {
+                return Err(anyhow!("Truncated record header at byte {}", offset));
+            }
+            warn!("Truncated record header at byte {}, stopping", offset);
+            break;
+        }
+        let len = u32::from_le_bytes(data[offset..offset+4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[offset+4..offset+8].try_into().unwrap());
+        let start = offset + FRAME_HEADER_LEN;
+        if start + len > data.len() {
+            if strict {
+                return Err(anyhow!("Truncated record at byte {} ({} of {} bytes present)", offset, data.len() - start, len));
+            }
+            warn!("Truncated record at byte {} ({} of {} bytes present), stopping", offset, data.len() - start, len);
+            break;
+        }
+        let payload = &data[start..start+len];
+        if crc32(payload) == crc {
+            records.push(payload);
+        } else if strict {
+            return Err(anyhow!("CRC mismatch for record at byte {}", offset));
+        } else {
+            warn!("CRC mismatch for record at byte {}, skipping", offset);
+        }
+        offset = start + len;
+    }
+    Ok(records)
+}
+
+
+pub(crate) fn for_each_record(reader: &mut dyn Read, strict: bool, mut f: impl FnMut(&[u8]) -> Result<(), Error>) -> Result<(), Error> {
+    // unframe_records over a stream, handing f one record at a time so only one is ever in memory
+    let mut offset: usize = 0;
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    let mut payload: Vec<u8> = Vec::new();
+    loop {
+        let header_read = read_up_to(reader, &mut header)?;
+        if header_read == 0 {
+            return Ok(());
+        }
+        if header_read < FRAME_HEADER_LEN {
+            if strict {
+                return Err(anyhow!("Truncated record header at byte {}", offset));
+            }
+            warn!("Truncated record header at byte {}, stopping", offset);
+            return Ok(());
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        payload.resize(len, 0);
+        let payload_read = read_up_to(reader, &mut payload)?;
+        if payload_read < len {
+            if strict {
+                return Err(anyhow!("Truncated record at byte {} ({} of {} bytes present)", offset, payload_read, len));
+            }
+            warn!("Truncated record at byte {} ({} of {} bytes present), stopping", offset, payload_read, len);
+            return Ok(());
+        }
+        if crc32(&payload) == crc {
+            f(&payload)?;
+        } else if strict {
+            return Err(anyhow!("CRC mismatch for record at byte {}", offset));
+        } else {
+            warn!("CRC mismatch for record at byte {}, skipping", offset);
+        }
+        offset += FRAME_HEADER_LEN + len;
+    }
+}
+
+
+fn read_up_to(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, Error> {
+    // Fills as much of buf as the stream has left, returning how much that was
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into())
+        }
+    }
+    Ok(filled)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s3_calls_share_one_runtime() {
+        assert!(std::ptr::eq(s3_runtime(), s3_runtime()));
+        // Usable from several threads at once
+        let sums: Vec<u64> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4u64).map(|i| scope.spawn(move || s3_runtime().block_on(async move { i * 2 }))).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(sums, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn expand_dirs_lists_each_wanted_file_once() {
+        let dir = std::env::temp_dir().join(format!("sa_decontamination_expand_{}", std::process::id()));
+        create_dir_all(dir.join("nested")).unwrap();
+        for name in ["a.jsonl", "nested/b.jsonl.gz", "c.txt"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let listed = expand_dirs(vec![dir.clone()], None).unwrap();
+        assert_eq!(listed, vec![dir.join("a.jsonl"), dir.join("nested/b.jsonl.gz")]);
+        // .gz and .jsonl.gz both match b, which is still listed once
+        let listed = expand_dirs(vec![dir.clone()], Some(&[".gz", ".jsonl.gz"])).unwrap();
+        assert_eq!(listed, vec![dir.join("nested/b.jsonl.gz")]);
+        // Files are taken as they are, whatever their extension
+        assert_eq!(expand_dirs(vec![dir.join("c.txt")], None).unwrap(), vec![dir.join("c.txt")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct FakeS3 {
+        // One bucket's keys, in listing order, handed out two to a page. Each page asked for is logged
+        keys: Vec<&'static str>,
+        pages: std::cell::RefCell<Vec<(String, Option<String>)>>
+    }
+
+    impl S3Listing for FakeS3 {
+        fn object_exists(&self, s3_uri: &Path) -> Result<bool, Error> {
+            Ok(self.keys.contains(&split_s3_path(s3_uri).1.as_str()))
+        }
+
+        fn list_page(&self, _bucket: &str, prefix: &str, continuation_token: Option<String>
+                     ) -> Result<(Vec<String>, Option<String>), Error> {
+            self.pages.borrow_mut().push((prefix.to_string(), continuation_token.clone()));
+            let under: Vec<String> = self.keys.iter().filter(|key| key.starts_with(prefix)).map(|key| key.to_string()).collect();
+            let start = continuation_token.map_or(0, |token| token.parse().unwrap());
+            let end = std::cmp::min(start + 2, under.len());
+            Ok((under[start..end].to_vec(), (end < under.len()).then(|| end.to_string())))
+        }
+    }
+
+    #[test]
+    fn expand_dirs_mixes_local_dirs_and_s3_prefixes() {
+        let dir = std::env::temp_dir().join(format!("sa_decontamination_expand_s3_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("local.jsonl"), b"").unwrap();
+        let s3 = FakeS3 {keys: vec!["data/_SUCCESS", "data/nested/part-2.jsonl.zst", "data/part-0.jsonl.gz", "data/part-0.jsonl.gz.crc",
+                                    "data/part-1.jsonl.gz", "data2/other.jsonl.gz", "logs/run.jsonl", "notes.txt"],
+                         pages: Default::default()};
+        let inputs: Vec<PathBuf> = [dir.display().to_string().as_str(), "s3://bucket/data", "s3://bucket/logs/", "s3://bucket/notes.txt",
+                                    "s3://bucket/data/part-1.jsonl.gz"].into_iter().map(PathBuf::from).collect();
+        let listed = expand_dirs_with(inputs, None, &s3).unwrap();
+        // data isn't an object, so it's listed as data/ (leaving out data2/) over three pages, keeping only jsonl keys.
+        // notes.txt is an object and taken as it is, and a key with a wanted extension is listed as its own prefix
+        let expected: Vec<PathBuf> = [dir.join("local.jsonl").display().to_string().as_str(), "s3://bucket/data/nested/part-2.jsonl.zst",
+                                      "s3://bucket/data/part-0.jsonl.gz", "s3://bucket/data/part-1.jsonl.gz", "s3://bucket/logs/run.jsonl",
+                                      "s3://bucket/notes.txt", "s3://bucket/data/part-1.jsonl.gz"].into_iter().map(PathBuf::from).collect();
+        assert_eq!(listed, expected);
+        let page = |prefix: &str, token: Option<&str>| (prefix.to_string(), token.map(String::from));
+        assert_eq!(s3.pages.take(), vec![page("data/", None), page("data/", Some("2")), page("data/", Some("4")), page("logs/", None),
+                                         page("data/part-1.jsonl.gz", None)]);
+        // --ext filters listed keys the same way it does local files
+        let listed = expand_dirs_with(vec![PathBuf::from("s3://bucket/data/")], Some(&[".zst"]), &s3).unwrap();
+        assert_eq!(listed, vec![PathBuf::from("s3://bucket/data/nested/part-2.jsonl.zst")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
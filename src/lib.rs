@@ -0,0 +1,6255 @@
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use dashmap::{DashMap, DashSet};
+use crate::io::{expand_dirs, read_pathbuf_to_mem, read_file_bytes, write_mem_to_pathbuf, path_exists, frame_record, unframe_records,
+                get_output_filename, compress_data, is_stdin, write_mem_to_pathbuf_with_level, write_to_pathbuf,
+                open_pathbuf_reader, read_pathbuf_streaming, for_each_record, path_size, prefetch_files};
+use crate::s3::is_s3;
+use crate::bloom::{BloomFilter, load_or_build_bloom};
+use crate::dedup::{to_bytes, table_header, sa_layout, load_sa, load_sa_mmap, get_occurrences_memory, get_occurrences_sharded,
+                   count_occurrences_sharded, load_size_object, doc_lookup, load_text_range, SaShard};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result, Error};
+use rayon::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use clap::{Args, ValueEnum};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use console::Term;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use tokenizers::Tokenizer;
+use unicode_normalization::UnicodeNormalization;
+use tracing::{info, info_span, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
+use std::borrow::Cow;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+
+
+pub mod s3;
+pub mod io;
+pub mod dedup;
+pub mod table;
+pub mod bloom;
+
+
+
+/*=================================================================
+=                                 ARGS                            =
+=================================================================*/
+
+
+#[derive(Args, Debug)]
+pub struct BuildSaArgs {
+    /// jsonl files (or dirs of them) holding the val set. Docs are numbered in sorted-path, then line, order
+    #[arg(required=true, long, num_args=1..)]
+    pub input: Vec<PathBuf>,
+
+    /// Where the SA text goes. The table and size object are written next to it as <output>.table.bin and <output>.size,
+    /// and how it was built (the crate version, inputs, doc count, text length, table width and text settings)
+    /// as <output>.meta.json
+    #[arg(required=true, long)]
+    pub output: PathBuf,
+
+    /// JSON field holding the document text. Dotted paths (e.g. document.text) descend into nested objects,
+    /// and a [] step (e.g. messages[].content) takes the rest of the path from every element of an array,
+    /// joined by --field-separator. Repeat it to match several fields as one text, joined the same way
+    #[arg(long, default_value="text")]
+    pub text_field: Vec<String>,
+
+    /// Goes between the fields of a doc when more than one --text-field is present, and between array elements
+    #[arg(long, default_value="\n\n")]
+    pub field_separator: String,
+
+    /// HF tokenizer.json. If given, the SA text is the token id stream instead of raw bytes
+    #[arg(long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// JSON field (dotted paths ok) holding each doc as an array of token ids. The SA text is then those
+    /// ids, --token-width bytes LE apiece, and --text-field is ignored
+    #[arg(long, conflicts_with_all=["tokenizer", "normalize"])]
+    pub input_ids_field: Option<String>,
+
+    /// Read the input as plain text, each line (newline dropped) one doc, with no JSON parsing. Dirs are
+    /// searched for .txt files (plain, .gz or .zst/.zstd) instead of jsonl
+    #[arg(long, default_value_t=false, conflicts_with_all=["input_ids_field", "prefix_field", "text_field"])]
+    pub raw_lines: bool,
+
+    /// Encoding of the input files, decoded to UTF-8 before anything else reads them. The SA text, and so every
+    /// position and size recorded against it, is in bytes of the decoded UTF-8
+    #[arg(long, value_enum, default_value_t=TextEncoding::Utf8)]
+    pub encoding: TextEncoding,
+
+    /// Bytes per id with --input-ids-field: 2 covers vocabs up to 65536, 4 covers anything
+    #[arg(long, default_value_t=2, requires="input_ids_field")]
+    pub token_width: usize,
+
+    /// JSON field (e.g. a doc id or title) to put in front of each doc's text, followed by --prefix-separator.
+    /// Each doc's prefix length goes in <output>.body_offsets, which mark_contaminates picks up so that
+    /// positions and coverage refer to the body alone
+    #[arg(long, conflicts_with="input_ids_field")]
+    pub prefix_field: Option<String>,
+
+    #[arg(long, default_value="\n", requires="prefix_field")]
+    pub prefix_separator: String,
+
+    /// JSON field (dotted, as with --text-field) naming each doc, e.g. its benchmark example id. Names are
+    /// written to <output>.ids.json.gz, one per doc in val_doc_id order, and mark_contaminates, query_matches
+    /// and lookup add them to their JSONL records as val_doc_name. A doc without the field is named <path>:<line>
+    #[arg(long, conflicts_with="raw_lines")]
+    pub id_field: Option<String>,
+
+    /// Comma-separated normalization steps applied, in order, to each doc before matching. The val set
+    /// and trainset must use the same steps, since SA offsets only make sense within one scheme
+    #[arg(long, value_enum, value_delimiter=',')]
+    pub normalize: Vec<NormalizeStep>,
+
+    /// Build the table over text with A-Z folded to a-z, keeping the original at <output>.original for spans,
+    /// spotchecks and query output. Only ASCII is folded, so both texts have the same length and every offset
+    /// means the same in each, but accented capitals still won't match (--normalize lowercase folds those, at
+    /// the cost of showing lowercased text). build_matches needs --case-insensitive too
+    #[arg(long, default_value_t=false, conflicts_with_all=["tokenizer", "input_ids_field"])]
+    pub case_insensitive: bool,
+
+    /// Tag each --input, in order (so one tag per input), with the benchmark its docs come from. The SA meta records
+    /// each tag's doc ranges, and mark_contaminates then also writes each tag's contaminates on their own, with
+    /// a source_report.json of per-tag contamination rates. Tags can hold letters, digits, '-', '_' and '.'
+    #[arg(long, num_args=1..)]
+    pub source_tag: Vec<String>,
+
+    /// Split the table into this many shards of whole docs, each its own SA at <output>.shard_NN, listed in
+    /// <output>.shards.json. The full text, size object and meta are still written at <output>.
+    /// build_matches maps shards instead of loading them, so the val set needn't fit in RAM, but every
+    /// window costs one search per shard, the text is stored twice (whole and split), and windows running
+    /// across a shard boundary (always a doc boundary) aren't found
+    #[arg(long, default_value_t=1)]
+    pub shards: usize,
+
+    /// The match_size this SA is meant to be matched at, recorded in its meta. build_matches defaults to it
+    #[arg(long)]
+    pub match_size: Option<usize>
+}
+
+
+#[derive(Args, Debug)]
+pub struct BuildMatchesArgs {
+    /// SA to match against. Repeat it to match several val sets in one read of the trainset; each then
+    /// gets its own match set in <output>/sa_NN (NN is its position here) for mark_contaminates to use
+    #[arg(required=true, long)]
+    pub data_file: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// jsonl files or dirs of them. "-" reads a single jsonl stream from stdin (recorded in paths.json as "-",
+    /// so later steps that re-read train lines, like --spotcheck, can't see it)
+    #[arg(required=true, long, num_args=1..)]
+    pub trainset: Vec<PathBuf>,
+
+    /// Glob over trainset file paths (e.g. "data/eval_mix/*") whose files are left out, so they're never
+    /// queried. Dropped before path ids are assigned, so the ids of the rest stay contiguous. Repeatable
+    #[arg(long, conflicts_with="self_match")]
+    pub exclude: Vec<String>,
+
+    /// Extension of the files to read out of trainset dirs and s3 prefixes (e.g. jsonl.gz), instead of the
+    /// default .jsonl, .jsonl.gz, .jsonl.zstd and .jsonl.zst. Files named outright are read whatever their
+    /// extension. Repeatable
+    #[arg(long)]
+    pub ext: Vec<String>,
+
+    #[arg(required=true, long)]
+    pub output: PathBuf,
+
+    /// Names every output <prefix>.<name> (<prefix>.matches.bin.gz, <prefix>.paths.json.gz, the manifest...), so
+    /// several runs can share one --output. Later steps read the set through <prefix>.matches.bin.gz, or through
+    /// <prefix>.matches.meta.json for a --stream run's shards
+    #[arg(long)]
+    pub prefix: Option<String>,
+
+    /// Defaults to the --match-size data_file was built with, or 10 if it recorded none
+    #[arg(long)]
+    pub match_size: Option<usize>,
+
+    /// JSON field holding the document text. Dotted paths (e.g. document.text) descend into nested objects,
+    /// and a [] step (e.g. messages[].content) takes the rest of the path from every element of an array,
+    /// joined by --field-separator. Repeat it to match several fields as one text, joined the same way
+    #[arg(long, default_value="text")]
+    pub text_field: Vec<String>,
+
+    /// Goes between the fields of a doc when more than one --text-field is present, and between array elements
+    #[arg(long, default_value="\n\n")]
+    pub field_separator: String,
+
+    /// HuggingFace tokenizer json. If set, docs are matched as token-id streams (2 or 4 bytes LE per token,
+    /// depending on vocab size) and match_size counts tokens. data_file must be built over the same stream
+    #[arg(long)]
+    pub tokenizer: Option<PathBuf>,
+
+    /// JSON field (dotted paths ok) holding each train doc as an array of token ids, matched as-is with no
+    /// tokenizer run, so match_size counts tokens. data_file must be built with the same --input-ids-field
+    /// encoding (BuildSa --input-ids-field and --token-width)
+    #[arg(long, conflicts_with_all=["tokenizer", "char_windows", "normalize", "min_doc_len"])]
+    pub input_ids_field: Option<String>,
+
+    /// Each trainset line (newline dropped) is the doc text as is, with no JSON parsing. Without --ext, dirs
+    /// are searched for .txt files (plain, .gz or .zst/.zstd). data_file must be built with BuildSa --raw-lines
+    #[arg(long, default_value_t=false, conflicts_with_all=["input_ids_field", "text_field"])]
+    pub raw_lines: bool,
+
+    /// Each trainset file is one doc: a single JSON object, which may span lines (or with --raw-lines, the whole
+    /// file as text). Matches record it as line 0 of its file. Without --ext, dirs are searched for .json files
+    /// (plain, .gz or .zst/.zstd), or .txt ones with --raw-lines
+    #[arg(long, default_value_t=false, conflicts_with="self_match")]
+    pub doc_per_file: bool,
+
+    /// Take a trainset line that's a bare JSON string (e.g. "hello") as the doc text, ignoring --text-field.
+    /// Without it such lines, like any other top-level JSON that isn't an object, are skipped as malformed
+    #[arg(long, default_value_t=false, conflicts_with_all=["raw_lines", "input_ids_field"])]
+    pub json_string_as_text: bool,
+
+    /// Encoding of the trainset files, decoded to UTF-8 before lines are parsed and windowed. Train offsets
+    /// count bytes of the decoded line. Independent of the encoding data_file was built from
+    #[arg(long, value_enum, default_value_t=TextEncoding::Utf8)]
+    pub encoding: TextEncoding,
+
+    /// Bytes per id with --input-ids-field. Must be the width data_file was built with
+    #[arg(long, default_value_t=2, requires="input_ids_field")]
+    pub token_width: usize,
+
+    /// Build windows of match_size unicode chars rather than bytes, so no match starts or ends inside a codepoint
+    #[arg(long, default_value_t=false, conflicts_with="tokenizer")]
+    pub char_windows: bool,
+
+    /// Advance windows by this many units (bytes/tokens/chars) instead of 1. With stride > 1 merged intervals
+    /// can leave gaps, so coverage in mark_contaminates becomes a lower bound on the true coverage
+    #[arg(long, default_value_t=1)]
+    pub stride: usize,
+
+    /// Look up only this fraction of windows, for a quick triage of a trainset. Which ones is fixed by a hash of
+    /// each window's position (file, line, offset), so reruns over the same trainset keep the same windows.
+    /// Coverage in mark_contaminates is then an estimate, and the run is marked sampled in its meta and stats
+    #[arg(long, default_value_t=1.0)]
+    pub sample_rate: f64,
+
+    /// Log and skip trainset lines that aren't valid json, rather than failing the whole run
+    #[arg(long, default_value_t=false)]
+    pub skip_errors: bool,
+
+    /// mmap the SA text/table instead of reading them into memory. Both must be uncompressed local files
+    #[arg(long, default_value_t=false)]
+    pub mmap: bool,
+
+    /// Exit immediately if all outputs and a matching manifest already exist
+    #[arg(long, default_value_t=false)]
+    pub skip_if_done: bool,
+
+    /// Stream matches to one matches.NN.bin.gz shard per worker thread as each trainset file finishes,
+    /// instead of holding every match in memory. Output must be local. Which shard a file lands in depends
+    /// on scheduling, so shard bytes vary between runs (the contaminates built from them don't)
+    #[arg(long, default_value_t=false)]
+    pub stream: bool,
+
+    /// Pick up an interrupted run where it left off. Finished trainset files (keyed on path, size and mtime) are
+    /// recorded in <output>/progress.json: by --stream runs as they go, by other runs when Ctrl-C stops them
+    /// early, next to the partial matches. With --resume those files are skipped, stream shards are cut back to
+    /// their last recorded length, and files that changed since are dropped from the matches and redone
+    #[arg(long, default_value_t=false)]
+    pub resume: bool,
+
+    /// Minimum number of trainset files handed to a worker at once. Raise this when the trainset is
+    /// many small files and scheduling overhead dominates
+    #[arg(long, default_value_t=1)]
+    pub file_chunk: usize,
+
+    /// Skip any window that occurs more than this many times in the val set. Such stop-grams
+    /// (boilerplate, stock phrases) say little about contamination but cost a lot of matches
+    #[arg(long)]
+    pub max_occurrences: Option<u64>,
+
+    /// Skip train docs whose text (all --text-field values joined, before --normalize) is under this many
+    /// bytes without looking them up. Only saves time; line numbers still count the skipped lines
+    #[arg(long, default_value_t=0)]
+    pub min_doc_len: usize,
+
+    /// Cap on a train doc's text (or input ids) in bytes, before --normalize, so one giant line can't stall
+    /// a worker windowing it. What happens to longer docs is set by --long-lines
+    #[arg(long)]
+    pub max_line_bytes: Option<usize>,
+
+    /// Docs over --max-line-bytes are cut back to it (on a char boundary, or a token one for input ids) or skipped
+    #[arg(long, value_enum, default_value_t=LongLineMode::Truncate, requires="max_line_bytes")]
+    pub long_lines: LongLineMode,
+
+    /// Stop collecting a train doc's matches (per SA) once it has this many, and record it as saturated:
+    /// mark_contaminates then flags every val doc its kept matches hit, whatever the threshold. Bounds the
+    /// memory one highly repetitive doc can take
+    #[arg(long)]
+    pub max_matches_per_doc: Option<usize>,
+
+    /// data_file is an SA BuildSa built over this very trainset (same files, same fields), to find docs
+    /// duplicated within one corpus. Each train line is then also a val doc, and the matches record which,
+    /// so mark_contaminates drops every doc's matches against itself and only flags docs that other docs cover
+    #[arg(long, default_value_t=false)]
+    pub self_match: bool,
+
+    /// Also record where in its train line each match starts, and the line's length, in train_offsets.bin.gz
+    /// next to the matches, which mark_contaminates --train-coverage-threshold needs. Costs another 32 bytes
+    /// per match (matches take 24), both in memory while collecting and on disk
+    #[arg(long, default_value_t=false, conflicts_with_all=["stream", "char_windows"])]
+    pub train_offsets: bool,
+
+    /// Check each window against a Bloom filter of the val set's windows before searching the SA, so
+    /// windows that can't match skip the binary search. The filter is built on first use and kept as
+    /// <data_file>.bloom.bin. Doesn't change the matches found
+    #[arg(long, default_value_t=false, conflicts_with="char_windows")]
+    pub bloom: bool,
+
+    /// Bloom filter bits per val window. 10 bits with 7 hashes lets about 1% of absent windows through
+    #[arg(long, default_value_t=10)]
+    pub bloom_bits: usize,
+
+    #[arg(long, default_value_t=7)]
+    pub bloom_hashes: u32,
+
+    /// Comma-separated normalization steps applied, in order, to each train line before matching. Must be
+    /// the same steps data_file was built with (see BuildSa --normalize)
+    #[arg(long, value_enum, value_delimiter=',')]
+    pub normalize: Vec<NormalizeStep>,
+
+    /// Fold A-Z to a-z in each train line before windowing it, for a data_file built with --case-insensitive
+    /// (and only for one). Train offsets still point into the unfolded line
+    #[arg(long, default_value_t=false, conflicts_with_all=["tokenizer", "input_ids_field"])]
+    pub case_insensitive: bool,
+
+    /// Size up the run without doing it: count and size the trainset, collect matches from a few
+    /// sample files, and log the projected match count and output size. Writes nothing
+    #[arg(long, default_value_t=false)]
+    pub dry_run: bool,
+
+    /// Advance the progress bar by each trainset file's size (stat, or HEAD on s3) rather than one tick per
+    /// file, so a few huge files don't throw off the ETA. Sizes are as stored, so compressed
+    #[arg(long, default_value_t=false)]
+    pub progress_bytes: bool,
+
+    /// Read trainset files on a pool of their own, this many at a time, ahead of the workers that window them,
+    /// so s3 latency overlaps with compute instead of stalling each worker. At most twice this many read files
+    /// wait in memory. Without it each worker reads its own files
+    #[arg(long)]
+    pub s3_concurrency: Option<usize>,
+
+    /// Write a JSON histogram of how many SA positions each window occurs at, per data_file, in power-of-two
+    /// buckets (0, 1, 2-3, 4-7, ...), to choose --max-occurrences from. Counts are taken before
+    /// --max-occurrences drops any window; windows the Bloom filter rules out count as 0. Only covers the
+    /// files read by this run
+    #[arg(long)]
+    pub occurrence_histogram: Option<PathBuf>,
+
+    /// Write matches as bincode (path_id, line_num, sa_pos) tuples, 24 bytes each before compression, instead of
+    /// delta-coded varints (about 3 bytes each), for tools built before the varint format. Either format reads
+    /// back the same matches
+    #[arg(long, default_value_t=false)]
+    pub legacy_match_format: bool,
+
+    /// Write the start and end (unix seconds) and duration of each phase (setup, sa_load, collect, serialize)
+    /// as JSON, with the process's peak RSS as of each phase's end (Linux only, null elsewhere)
+    #[arg(long)]
+    pub profile_json: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub compression: CompressionArgs
+}
+
+
+#[derive(Args, Debug)]
+pub struct MergeMatchesArgs {
+    /// build_matches output dirs to combine (or, for a --prefix run, its <prefix>.matches.meta.json). Each must have
+    /// a matches.meta.json, and all of them must agree on data_file, match_size, tokenization, normalization, stride
+    /// and max_occurrences
+    #[arg(required=true, long, num_args=1..)]
+    pub match_dir: Vec<PathBuf>,
+
+    #[arg(required=true, long)]
+    pub output: PathBuf,
+
+    /// Write the merged matches in the bincode format, as for build_matches
+    #[arg(long, default_value_t=false)]
+    pub legacy_match_format: bool,
+
+    #[command(flatten)]
+    pub compression: CompressionArgs
+}
+
+
+#[derive(Args, Debug)]
+pub struct MarkContaminatesArgs {
+    #[arg(required=true, long)]
+    pub data_file: PathBuf, //used to infer where the size file lives
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// matches.bin.gz, or the output directory of a --stream run holding matches.NN.bin.gz shards. A set built
+    /// with --prefix is read through <prefix>.matches.bin.gz, or <prefix>.matches.meta.json for its shards
+    #[arg(required=true, long)]
+    pub match_location: PathBuf,
+
+    #[arg(required=true, long)]
+    pub output: PathBuf,
+
+    /// Names the outputs <prefix>.contaminates.bin.gz, <prefix>.mark_contaminates.manifest.json and so on, so
+    /// several runs can share one --output
+    #[arg(long)]
+    pub prefix: Option<String>,
+
+    /// Fraction of the val doc (per --metric) a train line's matches must reach. Can be left out when
+    /// --min-distinct-windows is given, to flag on window counts alone
+    #[arg(long, required_unless_present="min_distinct_windows")]
+    pub threshold: Option<f64>,
+
+    /// Also require (or, with --threshold-mode or, accept) at least this many bytes of merged match coverage,
+    /// irrespective of doc length. In token mode this counts token bytes
+    #[arg(long)]
+    pub min_overlap_bytes: Option<usize>,
+
+    /// Also require (or accept) at least this many distinct match starts in the val doc's body from one train
+    /// line, however much they overlap. Simpler to reason about than a fraction for short fixed-length questions
+    #[arg(long)]
+    pub min_distinct_windows: Option<usize>,
+
+    /// How --threshold, --min-overlap-bytes and --min-distinct-windows combine: every one given must pass (and)
+    /// or any one suffices (or)
+    #[arg(long, value_enum, default_value_t=ThresholdMode::And)]
+    pub threshold_mode: ThresholdMode,
+
+    /// Also require a flagged pair's matches to cover at least this fraction of the train line, so a long
+    /// train doc that merely quotes a val doc doesn't count. Needs matches built with --train-offsets.
+    /// Train lines saturated at --max-matches-per-doc are exempt
+    #[arg(long)]
+    pub train_coverage_threshold: Option<f64>,
+
+    /// JSON list of {"min_len": N, "threshold": t} buckets overriding --threshold by doc (body) length.
+    /// Each bucket covers the half-open range [min_len, next bucket's min_len); docs shorter than every
+    /// min_len keep --threshold
+    #[arg(long)]
+    pub threshold_by_length: Option<PathBuf>,
+
+    /// File of phrases, one per line, that val and train sets share legitimately (licenses, instructions).
+    /// A merged match interval whose text is one of them, or inside one, doesn't count towards coverage or
+    /// --min-overlap-bytes. Phrases are normalized and tokenized the way the SA was. Not applied to ngram-fraction
+    #[arg(long)]
+    pub ignore_phrases: Option<PathBuf>,
+
+    /// What --threshold is a fraction of: body bytes covered by merged matches (coverage), or the distinct
+    /// n-grams of the body that matched over all its distinct n-grams (ngram-fraction), which one long
+    /// repeated passage can't inflate
+    #[arg(long, value_enum, default_value_t=MatchMetric::Coverage)]
+    pub metric: MatchMetric,
+
+    /// Merge matched intervals at most this many bytes apart into one covered region (gap included), so a
+    /// small edit inside an otherwise copied passage doesn't split it. Only affects the coverage metric
+    #[arg(long, default_value_t=0)]
+    pub gap_tolerance: usize,
+
+    /// Leave merged intervals shorter than this many bytes out of coverage and --min-overlap-bytes, so scattered
+    /// coincidental windows count for less than one long copied passage. Defaults to one window, which every
+    /// merged interval already spans. Only affects the coverage metric
+    #[arg(long)]
+    pub min_span: Option<usize>,
+
+    /// Whether matched intervals that only touch merge (touching), or must share at least a byte (overlapping).
+    /// Coverage is the same either way; it changes how --dump-spans and --spotcheck split up spans
+    #[arg(long, value_enum, default_value_t=MergeAdjacency::Touching)]
+    pub merge_adjacency: MergeAdjacency,
+
+    /// How matches are grouped by val doc. map fills nested concurrent maps as matches are read; sort reads them
+    /// a record at a time into one (val doc, train line, position) vector and sorts it, which takes less memory
+    /// than map when most train lines only hit a val doc a few times. external sorts on disk instead: matches
+    /// are read a record at a time and spilled in sorted runs to --spill-dir, then merged back and scored a batch
+    /// of val docs at a time, so memory stays around a run's worth (128MB) whatever the match count. It can't be
+    /// used with --spotcheck or --dump-spans, which need every group at hand. The contaminates are the same every way
+    #[arg(long, value_enum, default_value_t=GroupMode::Map)]
+    pub group_mode: GroupMode,
+
+    /// Where --group-mode external writes its sorted runs (about 32 bytes per match), removed once merged.
+    /// Defaults to the system temp dir
+    #[arg(long)]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Defaults to the match_size recorded in matches.meta.json. If given, must agree with it
+    #[arg(long)]
+    pub match_size: Option<usize>,
+
+    /// Score at most this many distinct match starts per (val doc, train line) pair, keeping the earliest, so a
+    /// degenerate pair (say, a val doc of one repeated character) can't stall the merge. A capped pair's coverage
+    /// is a lower bound. Capped pairs are counted in the log
+    #[arg(long)]
+    pub max_group_starts: Option<usize>,
+
+    /// JSON field(s) holding the train doc text (used when re-reading train lines, e.g. for --spotcheck).
+    /// Defaults to the fields, and --field-separator to the separator, the matches were built with
+    #[arg(long)]
+    pub text_field: Vec<String>,
+
+    #[arg(long)]
+    pub field_separator: Option<String>,
+
+    /// Per-doc header lengths (same u64 layout as the .size object). Positions are then
+    /// measured relative to each doc's body, and coverage is computed against body length.
+    /// Defaults to <data_file>.body_offsets when the SA was built with --prefix-field
+    #[arg(long)]
+    pub body_offsets: Option<PathBuf>,
+
+    /// JSON of {benchmark_name: [first_val_doc_id, end_val_doc_id)}. If present, also writes
+    /// a per-benchmark benchmark_report.json. With BuildMatches over several --data-file, a benchmark
+    /// can be {"data_file_id": N, "val_docs": [first, end)} instead, and only counts for set N's matches
+    #[arg(long)]
+    pub benchmark_map: Option<PathBuf>,
+
+    /// Sample this many contaminated pairs and write them with surrounding context
+    #[arg(long, requires="spotcheck_output")]
+    pub spotcheck: Option<usize>,
+
+    /// Where the spotcheck jsonl goes
+    #[arg(long)]
+    pub spotcheck_output: Option<PathBuf>,
+
+    /// Write the merged matched substrings of every contaminated val doc to this jsonl
+    #[arg(long)]
+    pub dump_spans: Option<PathBuf>,
+
+    /// Truncate each span in --dump-spans to this many chars
+    #[arg(long, default_value_t=1000)]
+    pub max_span_chars: usize,
+
+    /// paths.json.gz from build_matches. Defaults to the one next to match_location
+    #[arg(long)]
+    pub paths: Option<PathBuf>,
+
+    /// Write a JSONL of train lines ranked by how many distinct val docs each one contaminates
+    #[arg(long)]
+    pub train_report: Option<PathBuf>,
+
+    /// Write how many distinct train files contaminate each contaminated val doc, and a histogram of val docs
+    /// by that count. Many files hitting the same docs means the val set leaked broadly, not through one file
+    #[arg(long)]
+    pub overlap_report: Option<PathBuf>,
+
+    /// Write run totals as JSON: doc and contaminate counts, the contamination rate, mean/median body
+    /// coverage of flagged docs (by the union of their contaminating matches), whether the matches were sampled
+    /// (build_matches --sample-rate), and seconds per phase
+    #[arg(long)]
+    pub stats_json: Option<PathBuf>,
+
+    /// Write the start and end (unix seconds) and duration of each phase (load, group, merge, save) as JSON,
+    /// with the process's peak RSS as of each phase's end (Linux only, null elsewhere)
+    #[arg(long)]
+    pub profile_json: Option<PathBuf>,
+
+    /// Also write the ids of the contaminated val docs here, one per line in ascending order. If the SA was
+    /// built with --id-field, each line is the id, a tab, and the doc's name
+    #[arg(long)]
+    pub contaminated_ids: Option<PathBuf>,
+
+    /// Write a JSONL of {val_doc_id, total_windows, matched_windows, covered_bytes, doc_bytes} for every val doc
+    /// with any match, flagged or not, to pick --threshold from. matched_windows counts distinct match starts, and
+    /// covered_bytes is the body coverage of all of them together (with --gap-tolerance). Sizes are of the body
+    #[arg(long)]
+    pub window_stats: Option<PathBuf>,
+
+    /// Write a JSONL of {val_doc_id, contaminated, max_coverage, n_matching_train_docs} for every val doc in the
+    /// size object, matched or not, to cut a clean split from. max_coverage is the most body any one matching train
+    /// line covers (with --gap-tolerance), and docs without matches get 0.0 and 0
+    #[arg(long)]
+    pub full_verdict: Option<PathBuf>,
+
+    /// Output format for contaminates. jsonl resolves train paths, bincode is the compact default
+    #[arg(long, value_enum, default_value_t=ContaminateFormat::Bincode)]
+    pub format: ContaminateFormat,
+
+    /// Abort on a corrupt or truncated match record instead of skipping it
+    #[arg(long, default_value_t=false)]
+    pub strict: bool,
+
+    /// Exit immediately if all outputs and a matching manifest already exist
+    #[arg(long, default_value_t=false)]
+    pub skip_if_done: bool,
+
+    #[command(flatten)]
+    pub compression: CompressionArgs
+}
+
+
+#[derive(Args, Debug)]
+pub struct FilterArgs {
+    #[arg(required=true, long)]
+    pub contaminates: PathBuf,
+
+    /// SA text the contaminates were computed against. Used for the doc count/sizes, and as the
+    /// doc source (written as {"text": ...} jsonl) when --input isn't given
+    #[arg(required=true, long)]
+    pub data_file: PathBuf,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// Original val-set jsonl files/dirs the SA was built from. Like BuildSa, their files are taken in sorted
+    /// path order (whatever order they're given in) to number the docs. Output keeps their sharding
+    #[arg(long, num_args=1..)]
+    pub input: Vec<PathBuf>,
+
+    #[arg(required=true, long)]
+    pub output: PathBuf,
+
+    /// Keep only the contaminated docs instead
+    #[arg(long, default_value_t=false)]
+    pub invert: bool,
+
+    /// Per-doc header lengths, as for mark_contaminates. Docs written from the SA text leave their header out.
+    /// Defaults to <data_file>.body_offsets when the SA was built with --prefix-field
+    #[arg(long)]
+    pub body_offsets: Option<PathBuf>,
+}
+
+
+#[derive(Args, Debug)]
+pub struct QueryMatchesArgs {
+    /// contaminates.bin.gz (or .jsonl.gz) from mark_contaminates
+    #[arg(required=true, long)]
+    pub contaminates: PathBuf,
+
+    /// paths.json.gz from the build_matches run the contaminates came from
+    #[arg(required=true, long)]
+    pub paths: PathBuf,
+
+    /// Train file, or a glob pattern over train paths (e.g. "data/shard_0*.jsonl.gz"), to query
+    #[arg(required=true, long)]
+    pub train_path: String,
+
+    /// SA text the contaminates were computed against. If given, each val doc's text is included too
+    #[arg(long)]
+    pub data_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// Per-doc header lengths, as for mark_contaminates. The included text is each doc's body.
+    /// Defaults to <data_file>.body_offsets when the SA was built with --prefix-field
+    #[arg(long, requires="data_file")]
+    pub body_offsets: Option<PathBuf>,
+
+    /// jsonl output file. Defaults to stdout
+    #[arg(long)]
+    pub output: Option<PathBuf>
+}
+
+
+#[derive(Args, Debug)]
+pub struct LookupArgs {
+    #[arg(required=true, long)]
+    pub data_file: PathBuf,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// String to look up. It's normalized (and tokenized) the way data_file's text was before searching
+    #[arg(long, required_unless_present="query_file", conflicts_with="query_file")]
+    pub query: Option<String>,
+
+    /// File whose whole contents, trailing newline included, are the query
+    #[arg(long)]
+    pub query_file: Option<PathBuf>,
+
+    /// Most occurrences to list. The counts always cover all of them
+    #[arg(long, default_value_t=100)]
+    pub limit: usize,
+
+    /// Per-doc header lengths, as for mark_contaminates. Occurrences inside a header are left out, and
+    /// each one's body_offset is where it starts in its doc's body (0 if it starts in the header).
+    /// Defaults to <data_file>.body_offsets when the SA was built with --prefix-field
+    #[arg(long)]
+    pub body_offsets: Option<PathBuf>,
+
+    #[arg(long, default_value_t=false)]
+    pub mmap: bool,
+
+    /// jsonl output file, one occurrence per line. Defaults to stdout
+    #[arg(long)]
+    pub output: Option<PathBuf>
+}
+
+
+#[derive(Args, Debug)]
+pub struct SweepArgs {
+    /// SA to sweep against. Train lines are assembled, normalized and tokenized as recorded in its meta
+    #[arg(required=true, long)]
+    pub data_file: PathBuf,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// jsonl files or dirs of them to sample from
+    #[arg(required=true, long, num_args=1..)]
+    pub trainset: Vec<PathBuf>,
+
+    /// Only read this many of the (sorted) trainset files, evenly spaced through them
+    #[arg(long)]
+    pub sample_files: Option<usize>,
+
+    /// match_size values to try, in the SA's units (bytes or tokens)
+    #[arg(required=true, long, num_args=1..)]
+    pub match_size: Vec<usize>,
+
+    /// Coverage thresholds to try, as for mark_contaminates --threshold
+    #[arg(required=true, long, num_args=1..)]
+    pub threshold: Vec<f64>,
+
+    /// Encoding of the trainset files, as in BuildMatches
+    #[arg(long, value_enum, default_value_t=TextEncoding::Utf8)]
+    pub encoding: TextEncoding,
+
+    /// Per-doc header lengths, as for mark_contaminates: coverage is of each doc's body.
+    /// Defaults to <data_file>.body_offsets when the SA was built with --prefix-field
+    #[arg(long)]
+    pub body_offsets: Option<PathBuf>,
+
+    #[arg(long, default_value_t=false)]
+    pub mmap: bool,
+
+    /// CSV of match_size,threshold,contaminated_docs,num_docs,contamination_rate. Defaults to stdout
+    #[arg(long)]
+    pub output: Option<PathBuf>
+}
+
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    #[arg(required=true, long)]
+    pub data_file: PathBuf,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// Query length in bytes (in token mode, match_size times the token width)
+    #[arg(long, default_value_t=10)]
+    pub match_size: usize,
+
+    /// Lookups to time for each of the random and sampled query sets
+    #[arg(long, default_value_t=10000)]
+    pub num_queries: usize,
+
+    /// Map the SA instead of reading it into memory, to compare the two loaders
+    #[arg(long, default_value_t=false)]
+    pub mmap: bool,
+
+    #[arg(long, default_value_t=1234)]
+    pub seed: u64
+}
+
+
+#[derive(Args, Debug)]
+pub struct VerifySaArgs {
+    #[arg(required=true, long)]
+    pub data_file: PathBuf,
+
+    #[command(flatten)]
+    pub sa_paths: SaPathArgs,
+
+    /// Check every adjacent pair of suffixes is in order, instead of --samples random ones.
+    /// Can take a while on text with long repeats
+    #[arg(long, default_value_t=false)]
+    pub full: bool,
+
+    #[arg(long, default_value_t=100000)]
+    pub samples: usize,
+
+    #[arg(long, default_value_t=false)]
+    pub mmap: bool,
+
+    #[arg(long, default_value_t=1234)]
+    pub seed: u64
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct SaPathArgs {
+    /// SA text, if it isn't at --data-file itself. --data-file still names the SA: its meta and other
+    /// sidecar files (ids, body offsets, the original text of a --case-insensitive SA) are found next to it
+    #[arg(long)]
+    pub text: Option<PathBuf>,
+
+    /// SA table, if it isn't at <data-file>.table.bin. Not allowed for a sharded SA, whose shards each have their own
+    #[arg(long)]
+    pub table: Option<PathBuf>,
+
+    /// Size object, if it isn't at <data-file>.size
+    #[arg(long)]
+    pub size: Option<PathBuf>
+}
+
+impl SaPathArgs {
+    fn is_set(&self) -> bool {
+        self.text.is_some() || self.table.is_some() || self.size.is_some()
+    }
+
+    fn resolve(&self, data_file: &PathBuf) -> Result<SaFiles, Error> {
+        // data_file's files with these overrides applied. Only data_file's own: a sharded SA's shard files keep
+        // their usual paths
+        if self.table.is_some() && path_exists(&shards_path(data_file)) {
+            return Err(anyhow!("--table can't be used with the sharded SA {:?}, whose shards each have their own table", data_file));
+        }
+        let defaults = SaFiles::new(data_file);
+        let sa = SaFiles {data_file: data_file.clone(), text: self.text.clone().unwrap_or(defaults.text),
+                          table: self.table.clone().unwrap_or(defaults.table), size: self.size.clone().unwrap_or(defaults.size)};
+        if self.is_set() {
+            info!("Reading {:?} from text {:?}, table {:?} and size object {:?}", data_file, sa.text, sa.table, sa.size);
+        }
+        Ok(sa)
+    }
+
+    fn manifest(&self) -> Value {
+        json!({"text": self.text, "table": self.table, "size": self.size})
+    }
+}
+
+#[derive(Args, Clone, Copy, Debug)]
+pub struct CompressionArgs {
+    /// Compression for the binary and paths outputs. Readers pick a decoder from the file extension
+    #[arg(long, value_enum, default_value_t=OutputCompression::Gzip)]
+    pub compression: OutputCompression,
+
+    /// Level for --compression gzip (0-9, default 6) or zstd (1-22, default 3). Lower is faster, higher is smaller,
+    /// though gzip 9 rarely pays on match files: about 3x as long as 6 for well under 1% smaller.
+    /// --zstd-level is its older name
+    #[arg(long="compression-level", alias="zstd-level")]
+    pub level: Option<i32>
+}
+
+impl CompressionArgs {
+    pub fn name(&self, base: &str) -> String {
+        // e.g. matches.bin -> matches.bin.zst
+        format!("{}{}", base, self.compression.extension())
+    }
+
+    fn write(&self, contents: &[u8], output_file: &PathBuf) -> Result<(), Error> {
+        write_mem_to_pathbuf_with_level(contents, output_file, self.level)
+    }
+
+    fn write_matches(&self, matches: &[(usize, usize, u64)], legacy_format: bool, output_file: &PathBuf) -> Result<(), Error> {
+        // Serializes straight into the (compressing) file writer, so there's no serialized copy of matches
+        write_to_pathbuf(output_file, self.level, |writer| write_matches(matches, legacy_format, writer))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputCompression {
+    Gzip,
+    Zstd,
+    None
+}
+
+impl OutputCompression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputCompression::Gzip => ".gz",
+            OutputCompression::Zstd => ".zst",
+            OutputCompression::None => ""
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ContaminateFormat {
+    Bincode,
+    Jsonl
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ThresholdMode {
+    And,
+    Or
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum MergeAdjacency {
+    // Whether intervals that only touch (one ends where the next starts) merge, or they must overlap
+    Touching,
+    Overlapping
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LongLineMode {
+    // What build_matches does with a train doc over --max-line-bytes
+    Truncate,
+    Skip
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TextEncoding {
+    // What input files are decoded from. Latin-1 is ISO-8859-1 (every byte its own code point), not windows-1252
+    #[value(name="utf-8")]
+    Utf8,
+    #[value(name="latin-1")]
+    Latin1,
+    #[value(name="utf-16le")]
+    Utf16le
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum GroupMode {
+    // How mark_contaminates gathers each val doc's matches: nested concurrent maps, one sorted vector, or
+    // sorted runs on disk merged back a val doc at a time
+    Map,
+    Sort,
+    External
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum MatchMetric {
+    Coverage,
+    NgramFraction
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ProgressMode {
+    /// Draw progress bars only when stderr is a terminal
+    Auto,
+    Always,
+    Never
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per event, for log scrapers
+    Json
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum NormalizeStep {
+    /// Unicode lowercasing
+    Lowercase,
+    /// Unicode NFKC (folds compatibility forms, e.g. full-width chars and ligatures)
+    Nfkc,
+    /// Collapse each run of whitespace to a single space
+    Whitespace,
+    /// Drop everything that isn't alphanumeric or whitespace
+    Punctuation
+}
+
+/*=================================================================
+=                              UTILITIES                          =
+=================================================================*/
+
+static PROGRESS_MODE: AtomicU8 = AtomicU8::new(ProgressMode::Auto as u8);
+
+pub fn set_progress_mode(mode: ProgressMode) {
+    // Process-wide, since every subcommand builds its own bars
+    PROGRESS_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+
+pub fn init_logging(format: LogFormat) {
+    // Logs go to stdout at info unless RUST_LOG says otherwise. Every span logs its duration when it closes,
+    // which is where phase timings come from
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stdout);
+    match format {
+        LogFormat::Text => builder.with_ansi(Term::stdout().is_term()).init(),
+        LogFormat::Json => builder.json().init()
+    }
+}
+
+
+fn build_pbar(num_items: usize, units: &str) -> ProgressBar {
+    styled_pbar(num_items as u64, units, " {human_pos}/{human_len}")
+}
+
+
+fn build_bytes_pbar(num_bytes: u64, units: &str) -> ProgressBar {
+    // Same bar, counting in KiB/MiB/GiB
+    styled_pbar(num_bytes, units, " {bytes}/{total_bytes}")
+}
+
+
+fn styled_pbar(len: u64, units: &str, counts: &str) -> ProgressBar {
+    // Bars always go to stderr, so stdout stays clean for logs. A hidden bar's inc() is just an atomic add
+    let draw_target = match PROGRESS_MODE.load(Ordering::Relaxed) {
+        m if m == ProgressMode::Never as u8 => return ProgressBar::hidden(),
+        m if m == ProgressMode::Always as u8 => ProgressDrawTarget::term_like(Box::new(Term::stderr())),
+        _ => ProgressDrawTarget::stderr()
+    };
+    let mut template = String::from(units);
+    template.push_str(counts);
+    template.push_str(" [{elapsed_precise}/{duration_precise}] [{wide_bar:.cyan/blue}]");
+    let pbar = ProgressBar::with_draw_target(Some(len), draw_target)
+        .with_style(
+            ProgressStyle::with_template(&template).unwrap()
+        );
+    pbar.inc(0);
+    pbar
+}
+
+
+fn run_already_done(output: &Path, manifest_name: &str, params: &Value, outputs: &[&str]) -> bool {
+    // A run is done iff every expected output exists AND the manifest (written last) records
+    // exactly the params we were asked to run with
+    let manifest_path = output.join(manifest_name);
+    if !outputs.iter().all(|f| path_exists(&output.join(f))) || !path_exists(&manifest_path) {
+        return false;
+    }
+    let manifest: Value = match read_pathbuf_to_mem(&manifest_path).map(serde_json::from_reader) {
+        Ok(Ok(manifest)) => manifest,
+        _ => return false
+    };
+    manifest["params"] == *params
+}
+
+
+enum TokenSource {
+    // Where a doc's token ids come from: a tokenizer run over its text, or an int array already in the json
+    Tokenizer(Box<Tokenizer>, PathBuf),
+    InputIds(String)
+}
+
+
+pub struct TokenEncoder {
+    source: TokenSource,
+    pub token_width: usize
+}
+
+impl TokenEncoder {
+    pub fn load(path: &PathBuf) -> Result<Self, Error> {
+        let tokenizer = Tokenizer::from_file(path).map_err(|e| anyhow!("Failed to load tokenizer {:?}: {}", path, e))?;
+        let token_width = if tokenizer.get_vocab_size(true) <= (u16::MAX as usize) + 1 { 2 } else { 4 };
+        Ok(TokenEncoder { source: TokenSource::Tokenizer(Box::new(tokenizer), path.clone()), token_width })
+    }
+
+    pub fn input_ids(field: &str, token_width: usize) -> Result<Self, Error> {
+        if !(1..=4).contains(&token_width) {
+            return Err(anyhow!("--token-width must be between 1 and 4 bytes, not {}", token_width));
+        }
+        Ok(TokenEncoder { source: TokenSource::InputIds(field.to_string()), token_width })
+    }
+
+    pub fn reads_input_ids(&self) -> bool {
+        matches!(self.source, TokenSource::InputIds(_))
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        // Token ids as a flat little-endian byte stream, token_width bytes apiece
+        let tokenizer = match &self.source {
+            TokenSource::Tokenizer(tokenizer, _) => tokenizer,
+            TokenSource::InputIds(field) => return Err(anyhow!("Docs are read pre-tokenized from {:?}, not encoded from text", field))
+        };
+        let encoding = tokenizer.encode(text, false).map_err(|e| anyhow!("Failed to tokenize: {}", e))?;
+        Ok(encoding.get_ids().iter()
+            .flat_map(|id| id.to_le_bytes()[..self.token_width].to_vec())
+            .collect())
+    }
+
+    pub fn encode_ids(&self, json: &Value) -> Result<Option<Vec<u8>>, Error> {
+        // The int array at the (dotted) input ids field in the same byte stream encode builds, or None if
+        // the field is missing. Ids that don't fit in token_width bytes are an error, not truncated
+        let field = match &self.source {
+            TokenSource::InputIds(field) => field,
+            TokenSource::Tokenizer(_, path) => return Err(anyhow!("Docs are encoded by {:?}, not read pre-tokenized", path))
+        };
+        let ids = match field.split('.').try_fold(json, |value, key| value.get(key)) {
+            Some(Value::Array(ids)) => ids,
+            Some(other) => return Err(anyhow!("{:?} holds {}, not an array of token ids", field, other)),
+            None => return Ok(None)
+        };
+        let max_id = u64::MAX >> (64 - 8 * self.token_width);
+        let mut bytes = Vec::with_capacity(ids.len() * self.token_width);
+        for id in ids {
+            match id.as_u64() {
+                Some(id) if id <= max_id => bytes.extend_from_slice(&id.to_le_bytes()[..self.token_width]),
+                _ => return Err(anyhow!("Token id {} in {:?} isn't an integer that fits in {} bytes", id, field, self.token_width))
+            }
+        }
+        Ok(Some(bytes))
+    }
+}
+
+
+fn tokenization_meta(encoder: &Option<TokenEncoder>, char_windows: bool) -> Value {
+    match (encoder, char_windows) {
+        (Some(TokenEncoder {source: TokenSource::Tokenizer(_, path), token_width}), _) =>
+            json!({"mode": "tokens", "tokenizer": path, "token_width": token_width}),
+        (Some(TokenEncoder {source: TokenSource::InputIds(field), token_width}), _) =>
+            json!({"mode": "input_ids", "input_ids_field": field, "token_width": token_width}),
+        (None, true) => json!({"mode": "chars"}),
+        (None, false) => json!({"mode": "bytes"})
+    }
+}
+
+
+// Bump whenever the matches.bin.gz/matches.meta.json layout changes. Version 3 ties the meta to its paths.json
+// with a run_id, and writes varint match records
+const MATCH_META_VERSION: u64 = 3;
+
+
+fn new_run_id() -> String {
+    // 128 random bits, shared by the paths.json and matches.meta.json one build_matches (or merge) run writes
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+
+fn check_paths_run(paths_file: &PathBuf, run_id: Option<&str>, labeled: &str) -> Result<(), Error> {
+    // Errors if paths_file wasn't written by the run labeled (matches or contaminates) came from, since its path
+    // ids would then name the wrong train files. Files from before run ids were recorded can't be checked
+    let (_, paths_run_id) = load_paths(paths_file)?;
+    match (run_id, paths_run_id) {
+        (Some(run_id), Some(paths_run_id)) if run_id != paths_run_id => Err(anyhow!(
+            "{:?} is from build_matches run {}, but {} is from run {}, so its path ids would name the wrong train \
+             files. Use the paths file written alongside them", paths_file, paths_run_id, labeled, run_id)),
+        _ => Ok(())
+    }
+}
+
+fn match_dir(match_location: &Path) -> PathBuf {
+    // match_location is either matches.bin.gz or a directory of shards; either way this is where the sidecars live
+    if match_location.is_dir() || match_location.to_string_lossy().ends_with('/') {
+        match_location.to_path_buf()
+    } else {
+        match_location.parent().unwrap().to_path_buf()
+    }
+}
+
+
+fn match_prefix(match_location: &PathBuf) -> String {
+    // A set built with --prefix P is named by one of its files (P.matches.bin.gz, P.matches.meta.json), and
+    // every sidecar shares its "P." prefix. Unprefixed sets and directories give ""
+    if match_dir(match_location) == *match_location {
+        return String::new();
+    }
+    let name = match_location.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    name.rfind("matches.").map(|i| name[..i].to_string()).unwrap_or_default()
+}
+
+
+fn load_match_meta(match_location: &PathBuf) -> Result<Option<Value>, Error> {
+    // matches.meta.json sits next to matches.bin.gz. Older runs don't have one (None)
+    let meta_path = match_dir(match_location).join(format!("{}matches.meta.json", match_prefix(match_location)));
+    if !path_exists(&meta_path) {
+        return Ok(None);
+    }
+    let meta: Value = serde_json::from_reader(read_pathbuf_to_mem(&meta_path)?)?;
+    match meta["version"].as_u64() {
+        Some(version) if version <= MATCH_META_VERSION => Ok(Some(meta)),
+        version => Err(anyhow!("{:?} has version {:?}, but this build only reads up to version {}", 
+                               meta_path, version, MATCH_META_VERSION))
+    }
+}
+
+
+fn resolve_match_size(meta: &Option<Value>, match_size: Option<usize>) -> Result<usize, Error> {
+    // The match_size the matches were built with wins; a user-supplied one can only confirm it
+    let meta_match_size = meta.as_ref().and_then(|m| m["match_size"].as_u64()).map(|m| m as usize);
+    match (meta_match_size, match_size) {
+        (Some(built), Some(given)) if built != given => 
+            Err(anyhow!("--match-size {} disagrees with match_size {} the matches were built with", given, built)),
+        (Some(built), _) => Ok(built),
+        (None, Some(given)) => Ok(given),
+        (None, None) => Err(anyhow!("No match_size recorded for these matches, so --match-size is required"))
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub enum WindowSpec {
+    // How far a match starting at some val text position extends: a fixed number of bytes
+    // (bytes, or match_size tokens of token_width bytes each), or match_size chars whose byte length depends on the text
+    Bytes(usize),
+    Tokens(usize, usize),
+    Chars(usize)
+}
+
+impl WindowSpec {
+    pub fn interval(&self, start: usize, doc_text: &[u8]) -> (usize, usize) {
+        // doc_text is only read for Chars, where it's the val doc the match landed in
+        match *self {
+            WindowSpec::Bytes(width) => (start, start + width),
+            WindowSpec::Tokens(num_tokens, token_width) => (start, start + num_tokens * token_width),
+            WindowSpec::Chars(num_chars) => {
+                let mut end = start;
+                for _ in 0..num_chars {
+                    if end >= doc_text.len() {
+                        break;
+                    }
+                    end += 1;
+                    // Skip UTF-8 continuation bytes (0b10xxxxxx) to land on the next char boundary
+                    while end < doc_text.len() && (doc_text[end] & 0xC0) == 0x80 {
+                        end += 1;
+                    }
+                }
+                (start, end)
+            }
+        }
+    }
+
+    pub fn min_width(&self) -> usize {
+        match *self {
+            WindowSpec::Bytes(width) => width,
+            WindowSpec::Tokens(num_tokens, token_width) => num_tokens * token_width,
+            WindowSpec::Chars(num_chars) => num_chars
+        }
+    }
+
+    pub fn max_width(&self) -> usize {
+        match *self {
+            WindowSpec::Bytes(width) => width,
+            WindowSpec::Tokens(num_tokens, token_width) => num_tokens * token_width,
+            WindowSpec::Chars(num_chars) => 4 * num_chars
+        }
+    }
+
+    pub fn num_windows(&self, size: usize, text: &[u8]) -> usize {
+        // How many full windows fit in size bytes. Only Chars reads text, which must then be those bytes
+        match *self {
+            WindowSpec::Bytes(width) => (size + 1).saturating_sub(width),
+            WindowSpec::Tokens(num_tokens, token_width) => (size / token_width + 1).saturating_sub(num_tokens),
+            WindowSpec::Chars(num_chars) => (text.iter().filter(|b| (**b & 0xC0) != 0x80).count() + 1).saturating_sub(num_chars)
+        }
+    }
+
+    pub fn window_starts(&self, text: &[u8]) -> Vec<usize> {
+        // Every position in text where a full window starts
+        match *self {
+            WindowSpec::Bytes(width) => (0..(text.len() + 1).saturating_sub(width)).collect(),
+            WindowSpec::Tokens(num_tokens, token_width) => (0..(text.len() + 1).saturating_sub(num_tokens * token_width))
+                .step_by(token_width).collect(),
+            WindowSpec::Chars(num_chars) => {
+                let boundaries: Vec<usize> = (0..text.len()).filter(|i| (text[*i] & 0xC0) != 0x80).collect();
+                boundaries[..(boundaries.len() + 1).saturating_sub(num_chars)].to_vec()
+            }
+        }
+    }
+}
+
+
+fn meta_window_spec(meta: &Option<Value>, match_size: usize) -> Result<WindowSpec, Error> {
+    // Intervals are match_size units wide. Runs without metadata were always byte-level
+    let meta = match meta {
+        Some(meta) => meta,
+        None => return Ok(WindowSpec::Bytes(match_size))
+    };
+    match meta["tokenization"]["mode"].as_str() {
+        Some("bytes") => Ok(WindowSpec::Bytes(match_size)),
+        Some("tokens") | Some("input_ids") => meta["tokenization"]["token_width"].as_u64().map(|w| WindowSpec::Tokens(match_size, w as usize))
+            .ok_or_else(|| anyhow!("Token-built matches have no token_width")),
+        Some("chars") => Ok(WindowSpec::Chars(match_size)),
+        mode => Err(anyhow!("Unknown tokenization mode {:?}", mode))
+    }
+}
+
+
+pub fn extract_text<'a>(json: &'a Value, text_field: &str, separator: &str) -> Option<Cow<'a, str>> {
+    // Follows a dotted path like "document.text" down into json, None if any step is missing or the leaf isn't a string.
+    // A "[]" step maps the rest of the path over an array and joins whatever strings it finds, None if there are none
+    let descend = |path: &str| match path.is_empty() {
+        true => Some(json),
+        false => path.split('.').try_fold(json, |value, key| value.get(key))
+    };
+    match text_field.split_once("[]") {
+        Some((array_path, rest)) => {
+            let rest = rest.strip_prefix('.').unwrap_or(rest);
+            let texts: Vec<Cow<str>> = descend(array_path)?.as_array()?.iter()
+                .filter_map(|item| match rest.is_empty() {
+                    true => item.as_str().map(Cow::Borrowed),
+                    false => extract_text(item, rest, separator)
+                })
+                .collect();
+            join_texts(texts, separator)
+        },
+        None => descend(text_field).and_then(|value| value.as_str()).map(Cow::Borrowed)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MissingText {
+    // Why a line has no text, from least to most there, so the most telling of several fields is the one reported
+    Absent,
+    Null,
+    NotString
+}
+
+
+fn json_kind(json: &Value) -> &'static str {
+    match json {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object"
+    }
+}
+
+
+pub fn missing_text(json: &Value, text_fields: &[String]) -> MissingText {
+    // Why extract_fields found nothing at any of text_fields
+    text_fields.iter().map(|field| missing_field(json, field)).max().unwrap_or(MissingText::Absent)
+}
+
+
+fn missing_field(json: &Value, text_field: &str) -> MissingText {
+    // Walks the path the way extract_text does. An empty array counts as absent, there being nothing in it
+    let descend = |path: &str| match path.is_empty() {
+        true => Some(json),
+        false => path.split('.').try_fold(json, |value, key| value.get(key))
+    };
+    let leaf = |value: Option<&Value>| match value {
+        None => MissingText::Absent,
+        Some(Value::Null) => MissingText::Null,
+        Some(_) => MissingText::NotString
+    };
+    match text_field.split_once("[]") {
+        Some((array_path, rest)) => match descend(array_path) {
+            Some(Value::Array(items)) => {
+                let rest = rest.strip_prefix('.').unwrap_or(rest);
+                items.iter()
+                    .map(|item| match rest.is_empty() {
+                        true => leaf(Some(item)),
+                        false => missing_field(item, rest)
+                    })
+                    .max()
+                    .unwrap_or(MissingText::Absent)
+            },
+            value => leaf(value)
+        },
+        None => leaf(descend(text_field))
+    }
+}
+
+
+fn join_texts<'a>(mut texts: Vec<Cow<'a, str>>, separator: &str) -> Option<Cow<'a, str>> {
+    match texts.len() {
+        0 => None,
+        1 => texts.pop(),
+        _ => Some(Cow::Owned(texts.iter().map(|text| text.as_ref()).collect::<Vec<&str>>().join(separator)))
+    }
+}
+
+
+pub fn extract_fields<'a>(json: &'a Value, text_fields: &[String], separator: &str) -> Option<Cow<'a, str>> {
+    // The present fields joined by separator, or None when none of them are
+    join_texts(text_fields.iter().filter_map(|field| extract_text(json, field, separator)).collect(), separator)
+}
+
+
+pub fn normalize_text<'a>(text: &'a str, steps: &[NormalizeStep]) -> Cow<'a, str> {
+    let mut text = Cow::Borrowed(text);
+    for step in steps {
+        text = Cow::Owned(match step {
+            NormalizeStep::Lowercase => text.to_lowercase(),
+            NormalizeStep::Nfkc => text.nfkc().collect(),
+            NormalizeStep::Whitespace => text.split_whitespace().collect::<Vec<_>>().join(" "),
+            NormalizeStep::Punctuation => text.chars().filter(|c| c.is_alphanumeric() || c.is_whitespace()).collect()
+        });
+    }
+    text
+}
+
+
+fn fold_case(text: Cow<'_, str>, case_insensitive: bool) -> Cow<'_, str> {
+    // --case-insensitive folds A-Z only, which keeps the length, so offsets into the folded text hold for the original
+    match case_insensitive && text.bytes().any(|b| b.is_ascii_uppercase()) {
+        true => Cow::Owned(text.to_ascii_lowercase()),
+        false => text
+    }
+}
+
+
+fn normalization_meta(steps: &[NormalizeStep]) -> Value {
+    json!(steps.iter().map(|s| s.to_possible_value().unwrap().get_name().to_string()).collect::<Vec<_>>())
+}
+
+
+fn parse_normalization(meta: &Value) -> Result<Vec<NormalizeStep>, Error> {
+    meta.as_array().ok_or_else(|| anyhow!("Normalization {} isn't a list of steps", meta))?.iter()
+        .map(|step| step.as_str().and_then(|s| NormalizeStep::from_str(s, false).ok())
+             .ok_or_else(|| anyhow!("Unknown normalization step {}", step)))
+        .collect()
+}
+
+
+fn sa_meta_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.meta.json", data_file.display()))
+}
+
+
+fn load_sa_meta(data_file: &Path) -> Result<Value, Error> {
+    // SAs built elsewhere have no meta, which reads as {}
+    let meta_path = sa_meta_path(data_file);
+    if !path_exists(&meta_path) {
+        return Ok(json!({}));
+    }
+    Ok(serde_json::from_reader(read_pathbuf_to_mem(&meta_path)?)?)
+}
+
+
+fn check_sa_version(data_file: &PathBuf) -> Result<(), Error> {
+    // SAs from before the meta recorded a version always pass
+    if let Some(version) = load_sa_meta(data_file)?["version"].as_str().filter(|v| *v != env!("CARGO_PKG_VERSION")) {
+        warn!("{:?} was built by version {} but this is version {}. Rebuild it if matching looks off",
+              data_file, version, env!("CARGO_PKG_VERSION"));
+    }
+    Ok(())
+}
+
+
+fn resolve_sa_match_size(data_file: &[PathBuf], match_size: Option<usize>) -> Result<usize, Error> {
+    // A given match_size wins, otherwise the one the SA(s) were built for, otherwise 10
+    let mut sa_match_sizes: Vec<(usize, &PathBuf)> = Vec::new();
+    for sa_file in data_file {
+        if let Some(sa_match_size) = load_sa_meta(sa_file)?["match_size"].as_u64() {
+            sa_match_sizes.push((sa_match_size as usize, sa_file));
+        }
+    }
+    match (match_size, sa_match_sizes.first()) {
+        (Some(given), _) => {
+            for (sa_match_size, sa_file) in sa_match_sizes.iter().filter(|(sa_match_size, _)| *sa_match_size != given) {
+                warn!("{:?} was built for --match-size {}, but matching at {}", sa_file, sa_match_size, given);
+            }
+            Ok(given)
+        },
+        (None, Some(&(first, first_file))) => match sa_match_sizes.iter().find(|(sa_match_size, _)| *sa_match_size != first) {
+            Some((other, other_file)) => Err(anyhow!("{:?} was built for --match-size {} but {:?} for {}, so --match-size is required",
+                                                     first_file, first, other_file, other)),
+            None => Ok(first)
+        },
+        (None, None) => Ok(10)
+    }
+}
+
+
+pub struct SaTextEncoder {
+    // Turns text into the bytes it would be in an SA's text (normalized, case folded and tokenized as the SA was),
+    // with the SA's meta and tokenizer loaded once
+    normalize: Vec<NormalizeStep>,
+    case_insensitive: bool,
+    encoder: Option<TokenEncoder>
+}
+
+impl SaTextEncoder {
+    pub fn load(data_file: &PathBuf) -> Result<Self, Error> {
+        let sa_meta = load_sa_meta(data_file)?;
+        let encoder = match sa_meta["tokenization"]["mode"].as_str() {
+            Some("tokens") => {
+                let tokenizer = sa_meta["tokenization"]["tokenizer"].as_str()
+                    .ok_or_else(|| anyhow!("{:?} was tokenized but doesn't record its tokenizer", data_file))?;
+                Some(TokenEncoder::load(&PathBuf::from(tokenizer))?)
+            },
+            Some("input_ids") => return Err(anyhow!("{:?} was built from input ids, so there's no tokenizer to encode text with", data_file)),
+            _ => None
+        };
+        Ok(SaTextEncoder {normalize: parse_normalization(&load_sa_normalization(data_file)?)?,
+                          case_insensitive: sa_case_insensitive(data_file)?, encoder})
+    }
+
+    pub fn unit_width(&self) -> usize {
+        // The width of one unit of the encoded bytes
+        self.encoder.as_ref().map_or(1, |encoder| encoder.token_width)
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        let text = fold_case(normalize_text(text, &self.normalize), self.case_insensitive);
+        match &self.encoder {
+            Some(encoder) => encoder.encode(&text),
+            None => Ok(text.as_bytes().to_vec())
+        }
+    }
+}
+
+
+fn sa_case_insensitive(data_file: &Path) -> Result<bool, Error> {
+    Ok(load_sa_meta(data_file)?["case_insensitive"].as_bool().unwrap_or(false))
+}
+
+
+fn display_text_file(sa: &SaFiles) -> Result<PathBuf, Error> {
+    // Where val text is read to be shown: a --case-insensitive SA's original text, otherwise the SA text itself
+    Ok(match sa_case_insensitive(&sa.data_file)? {
+        true => original_text_path(&sa.data_file),
+        false => sa.text.clone()
+    })
+}
+
+
+fn load_sa_sources(data_file: &PathBuf) -> Result<Vec<(String, usize, usize)>, Error> {
+    // The (tag, first_doc, end_doc) runs from BuildSa --source-tag, none if it wasn't given
+    let sa_meta = load_sa_meta(data_file)?;
+    sa_meta["sources"].as_array().map_or(&Vec::new(), |sources| sources).iter()
+        .map(|source| match (source["tag"].as_str(), source["first_doc"].as_u64(), source["end_doc"].as_u64()) {
+            (Some(tag), Some(first_doc), Some(end_doc)) => Ok((tag.to_string(), first_doc as usize, end_doc as usize)),
+            _ => Err(anyhow!("Bad source {} in the meta of {:?}", source, data_file))
+        })
+        .collect()
+}
+
+
+fn load_sa_normalization(data_file: &Path) -> Result<Value, Error> {
+    // The normalization steps BuildSa applied to the val set. SAs without meta are raw text
+    Ok(load_sa_meta(data_file)?.get("normalize").cloned().unwrap_or(json!([])))
+}
+
+
+fn check_sa_tokenization(data_file: &PathBuf, encoder: &Option<TokenEncoder>) -> Result<(), Error> {
+    // The SA text and the train windows have to be the same unit stream (bytes, or ids of one width).
+    // Char windows run over the byte stream, and SAs from before tokenization was recorded are taken on trust
+    let sa_meta = load_sa_meta(data_file)?;
+    let ours = tokenization_meta(encoder, false);
+    let stream = |tokenization: &Value| (tokenization["mode"].clone(), tokenization["token_width"].clone());
+    if !sa_meta["tokenization"].is_null() && stream(&sa_meta["tokenization"]) != stream(&ours) {
+        return Err(anyhow!("{:?} was built over {} but the trainset is read as {}", data_file, sa_meta["tokenization"], ours));
+    }
+    Ok(())
+}
+
+
+fn check_match_tokenization(data_file: &PathBuf, match_meta: &Option<Value>) -> Result<(), Error> {
+    // Matches only make sense against an SA over the same unit stream they were built against, so byte-built
+    // matches are refused for a token-built SA and the other way round. Either side without a record is taken on trust
+    let sa_meta = load_sa_meta(data_file)?;
+    let stream = |tokenization: &Value| match tokenization["mode"].as_str() {
+        Some("tokens") | Some("input_ids") => Some(("tokens", tokenization["token_width"].as_u64())),
+        Some(_) => Some(("bytes", None)),
+        None => None
+    };
+    let match_tokenization = match_meta.as_ref().map_or(&Value::Null, |m| &m["tokenization"]);
+    if let (Some(sa_stream), Some(match_stream)) = (stream(&sa_meta["tokenization"]), stream(match_tokenization)) {
+        if sa_stream != match_stream {
+            return Err(anyhow!("The matches were built over {} but {:?} was built over {}", match_tokenization, data_file,
+                               sa_meta["tokenization"]));
+        }
+    }
+    Ok(())
+}
+
+
+fn fields_meta(text_fields: &[String], separator: &str) -> Value {
+    json!({"text_fields": text_fields, "field_separator": separator})
+}
+
+
+fn check_sa_fields(data_file: &Path, text_fields: &[String], separator: &str, raw_lines: bool) -> Result<(), Error> {
+    // Train lines must be assembled the way the val docs were. SAs from before multi-field support
+    // don't say, so they're taken on trust
+    let sa_meta = load_sa_meta(data_file)?;
+    let sa_raw_lines = sa_meta["raw_lines"].as_bool().unwrap_or(false);
+    if sa_raw_lines != raw_lines {
+        return Err(anyhow!("data_file was built {} --raw-lines, so the trainset has to be read {} it too",
+                           if sa_raw_lines { "with" } else { "without" }, if sa_raw_lines { "with" } else { "without" }));
+    }
+    if raw_lines {
+        return Ok(());
+    }
+    let sa_fields = json!({"text_fields": sa_meta["text_fields"], "field_separator": sa_meta["field_separator"]});
+    if !sa_meta["text_fields"].is_null() && sa_fields != fields_meta(text_fields, separator) {
+        return Err(anyhow!("data_file was built from fields {} but --text-field/--field-separator give {}",
+                           sa_fields, fields_meta(text_fields, separator)));
+    }
+    Ok(())
+}
+
+
+fn load_path_lookup(paths_file: &PathBuf) -> Result<Vec<PathBuf>, Error> {
+    Ok(load_paths(paths_file)?.0)
+}
+
+
+fn load_paths(paths_file: &PathBuf) -> Result<(Vec<PathBuf>, Option<String>), Error> {
+    // paths.json is {run_id, match_size, tokenization, paths}, with paths indexed by path_idx. Runs before match
+    // meta version 3 wrote just the list and have no run_id, and ones before version 2 a {path -> path_idx} map,
+    // which is inverted into the same vec
+    let paths: Value = serde_json::from_reader(read_pathbuf_to_mem(paths_file)?)?;
+    if let (Some(run_id), true) = (paths["run_id"].as_str(), paths["paths"].is_array()) {
+        return Ok((serde_json::from_value(paths["paths"].clone())?, Some(run_id.to_string())));
+    }
+    if paths.is_array() {
+        return Ok((serde_json::from_value(paths)?, None));
+    }
+    let path_map: HashMap<PathBuf, usize> = serde_json::from_value(paths)
+        .with_context(|| format!("{:?} is neither a list of paths nor a {{path: path_idx}} map", paths_file))?;
+    let mut paths = vec![PathBuf::new(); path_map.len()];
+    for (path, idx) in path_map {
+        paths[idx] = path;
+    }
+    Ok((paths, None))
+}
+
+
+#[derive(Clone, Debug)]
+pub struct SaFiles {
+    // Where an SA's text, table and size object are. data_file still names the SA, and its meta and other
+    // sidecars are always found next to it
+    pub data_file: PathBuf,
+    pub text: PathBuf,
+    pub table: PathBuf,
+    pub size: PathBuf
+}
+
+impl SaFiles {
+    pub fn new(data_file: &Path) -> Self {
+        // The files BuildSa writes for data_file
+        SaFiles {data_file: data_file.to_path_buf(), text: data_file.to_path_buf(), table: table_path(data_file), size: size_object_path(data_file)}
+    }
+}
+
+
+fn size_object_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.size", data_file.display()))
+}
+
+
+fn table_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.table.bin", data_file.display()))
+}
+
+
+fn shards_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.shards.json", data_file.display()))
+}
+
+
+pub fn load_sa_shards(sa: &SaFiles, mmap: bool) -> Result<Vec<SaShard>, Error> {
+    // A sharded SA (one with a shards.json) maps each of its shards, whatever mmap says, since not fitting in
+    // memory is why it was sharded. Anything else is a single shard at offset 0, loaded per mmap
+    let data_file = &sa.data_file;
+    let manifest_path = shards_path(data_file);
+    if !path_exists(&manifest_path) {
+        let (text, size_text, table, size_table, size_width) = load_sa(&sa.text, &sa.table, mmap)?;
+        return Ok(vec![SaShard {data_file: data_file.clone(), offset: 0, text, size_text, table, size_table, size_width}]);
+    }
+    let manifest: Value = serde_json::from_reader(read_pathbuf_to_mem(&manifest_path)?)?;
+    let shard_dir = data_file.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    manifest["shards"].as_array()
+        .ok_or_else(|| anyhow!("{:?} doesn't list any shards", manifest_path))?
+        .iter()
+        .map(|entry| {
+            let shard_file = shard_dir.join(entry["data_file"].as_str().unwrap_or_default());
+            let (text, size_text, table, size_table, size_width) = load_sa_mmap(&shard_file, &table_path(&shard_file))
+                .with_context(|| format!("Failed to load shard {:?}", shard_file))?;
+            let offset = entry["start"].as_u64().unwrap_or(0);
+            if entry["end"].as_u64() != Some(offset + size_text) {
+                return Err(anyhow!("Shard {:?} is {} bytes but {:?} says it covers {}..{}",
+                                   shard_file, size_text, manifest_path, entry["start"], entry["end"]));
+            }
+            Ok(SaShard {data_file: shard_file, offset, text, size_text, table, size_table, size_width})
+        })
+        .collect()
+}
+
+
+fn body_offsets_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.body_offsets", data_file.display()))
+}
+
+
+fn load_header_lens(data_file: &PathBuf, body_offsets: Option<&PathBuf>, num_docs: usize) -> Result<Vec<u64>, Error> {
+    // Each doc's header length, from body_offsets or else the ones an SA built with --prefix-field wrote itself.
+    // All 0 without either
+    let sa_body_offsets = match load_sa_meta(data_file)?["prefix_field"].is_null() {
+        true => None,
+        false => Some(body_offsets_path(data_file))
+    };
+    let header_lens: Vec<u64> = match body_offsets.or(sa_body_offsets.as_ref()) {
+        Some(p) => load_size_object(p).with_context(|| format!("Failed to load body offsets {:?}", p))?,
+        None => vec![0; num_docs]
+    };
+    if header_lens.len() < num_docs {
+        return Err(anyhow!("Body offsets have {} entries but {:?} has {} docs", header_lens.len(), data_file, num_docs));
+    }
+    Ok(header_lens)
+}
+
+
+fn original_text_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.original", data_file.display()))
+}
+
+
+fn doc_names_path(data_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.ids.json.gz", data_file.display()))
+}
+
+
+fn doc_name(json: &Value, id_field: &str) -> Option<String> {
+    // The value at a dotted id_field, with numbers and the like written out as JSON. None if it's missing or null
+    match id_field.split('.').try_fold(json, |value, key| value.get(key))? {
+        Value::Null => None,
+        Value::String(name) => Some(name.clone()),
+        value => Some(value.to_string())
+    }
+}
+
+
+fn load_doc_names(data_file: &Path, num_docs: usize) -> Result<Option<Vec<String>>, Error> {
+    // The --id-field names BuildSa recorded, if it was given one
+    let names_path = doc_names_path(data_file);
+    if !path_exists(&names_path) {
+        return Ok(None);
+    }
+    let names: Vec<String> = serde_json::from_reader(read_pathbuf_to_mem(&names_path)?)
+        .with_context(|| format!("Failed to parse doc names {:?}", names_path))?;
+    if names.len() != num_docs {
+        return Err(anyhow!("{:?} names {} docs, but the SA has {}. Was it written by a different BuildSa run?",
+                           names_path, names.len(), num_docs));
+    }
+    Ok(Some(names))
+}
+
+
+fn add_doc_name(record: &mut Value, doc_names: &Option<Vec<String>>, val_doc_id: usize) {
+    if let Some(doc_names) = doc_names {
+        record["val_doc_name"] = json!(doc_names[val_doc_id]);
+    }
+}
+
+
+fn write_sa_text(output: &PathBuf, text: &[u8], sizes: &[u64], sa_meta: &Value) -> Result<(), Error> {
+    // Writes text, size object, and meta raw to local disk, which is what load_sa reads. The meta gets the
+    // doc count and text length of this text, so a shard's describe the shard
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut sa_meta = sa_meta.clone();
+    sa_meta["num_docs"] = json!(sizes.len().saturating_sub(1));
+    sa_meta["text_bytes"] = json!(text.len());
+    let size_bytes: Vec<u8> = sizes.iter().flat_map(|s| s.to_ne_bytes()).collect();
+    std::fs::write(output, text).with_context(|| format!("Failed to write {:?}", output))?;
+    std::fs::write(size_object_path(output), size_bytes)
+        .with_context(|| format!("Failed to write {:?}", size_object_path(output)))?;
+    std::fs::write(sa_meta_path(output), serde_json::to_vec(&sa_meta)?)
+        .with_context(|| format!("Failed to write {:?}", sa_meta_path(output)))?;
+    Ok(())
+}
+
+
+fn write_sa(output: &PathBuf, text: &[u8], sizes: &[u64], sa_meta: &Value) -> Result<usize, Error> {
+    // Builds the table over text and writes it out along with everything write_sa_text does. Returns its size_width
+    let sa_span = info_span!("suffix_array").entered();
+    let table = table::SuffixTable::new(text).into_parts().1;
+    let size_width = cmp::max(((text.len() as f64).log2() / 8.0).ceil() as usize, 1);
+    drop(sa_span);
+    let mut sa_meta = sa_meta.clone();
+    sa_meta["size_width"] = json!(size_width);
+    write_sa_text(output, text, sizes, &sa_meta)?;
+    let mut table_bytes = table_header(size_width);
+    table_bytes.extend(to_bytes(&table, size_width));
+    std::fs::write(table_path(output), table_bytes)
+        .with_context(|| format!("Failed to write {:?}", table_path(output)))?;
+    Ok(size_width)
+}
+
+
+fn check_size_object(size_object: &[u64], sa: &SaFiles) -> Result<(), Error> {
+    // Doc offsets have to cover exactly the SA text, whose length the table has already been checked
+    // to address. Otherwise positions past the end would wrap or land in the wrong doc silently
+    // A sharded SA has no table of its own, just the text
+    let data_file = &sa.data_file;
+    let size_text = match path_exists(&shards_path(data_file)) {
+        true => std::fs::metadata(&sa.text).with_context(|| format!("Failed to check SA at {:?}", data_file))?.len(),
+        false => sa_layout(&sa.text, &sa.table).with_context(|| format!("Failed to check SA at {:?}", data_file))?.0
+    };
+    let total = size_object.last().copied().unwrap_or(0);
+    if total != size_text {
+        return Err(anyhow!("Size object for {:?} covers {} bytes but the SA text is {} bytes. Was it built from this SA?",
+                           data_file, total, size_text));
+    }
+    Ok(())
+}
+
+
+fn prefixed_name(prefix: &Option<String>, name: &str) -> String {
+    // --prefix P names an output P.<name>
+    match prefix {
+        Some(prefix) => format!("{}.{}", prefix, name),
+        None => name.to_string()
+    }
+}
+
+
+fn check_prefix(prefix: &Option<String>) -> Result<(), Error> {
+    match prefix {
+        Some(prefix) if prefix.is_empty() || prefix.contains('/') => 
+            Err(anyhow!("--prefix {:?} must be a non-empty file name prefix, without any /", prefix)),
+        _ => Ok(())
+    }
+}
+
+
+fn write_manifest(output: &Path, manifest_name: &str, params: &Value, outputs: &[&str]) -> Result<(), Error> {
+    // Should be called only after every output has been written
+    let manifest = json!({"params": params, "outputs": outputs});
+    write_mem_to_pathbuf(&serde_json::to_vec(&manifest)?, &output.join(manifest_name))
+}
+
+
+fn peak_rss_bytes() -> Option<u64> {
+    // The kernel's high-water mark for this process, so a phase's peak is whatever it is by the phase's end
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status.lines().find_map(|line| line.strip_prefix("VmHWM:"))?.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+
+struct PhaseProfile {
+    // Phase timings for --profile-json and --stats-json, taken off the Instant each phase starts from.
+    // Timestamps are the run's wall-clock start plus the monotonic offset, so one can't jump mid-run
+    started: (Instant, SystemTime),
+    phases: Vec<Value>,
+    phase_secs: BTreeMap<&'static str, f64>
+}
+
+
+impl PhaseProfile {
+    fn new() -> Self {
+        PhaseProfile {started: (Instant::now(), SystemTime::now()), phases: Vec::new(), phase_secs: BTreeMap::new()}
+    }
+
+    fn timestamp(&self, at: Instant) -> f64 {
+        let wall = self.started.1 + at.saturating_duration_since(self.started.0);
+        wall.duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64())
+    }
+
+    fn record(&mut self, phase: &'static str, start: Instant) {
+        let end = Instant::now();
+        let secs = end.saturating_duration_since(start).as_secs_f64();
+        self.phases.push(json!({"phase": phase, "start": self.timestamp(start), "end": self.timestamp(end),
+                                "secs": secs, "peak_rss_bytes": peak_rss_bytes()}));
+        self.phase_secs.insert(phase, secs);
+    }
+
+    fn write(&self, command: &str, profile_json: &PathBuf) -> Result<(), Error> {
+        let end = Instant::now();
+        let profile = json!({"command": command, "version": env!("CARGO_PKG_VERSION"),
+                             "start": self.timestamp(self.started.0), "end": self.timestamp(end),
+                             "secs": end.saturating_duration_since(self.started.0).as_secs_f64(),
+                             "peak_rss_bytes": peak_rss_bytes(), "phases": self.phases});
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&profile)?, profile_json)
+    }
+}
+
+
+
+
+/*=================================================================
+=                      MATCH BUILDER HELERS                       =
+=================================================================*/
+
+// What --raw-lines looks for in dirs, in place of jsonl
+const RAW_LINE_EXTS: &[&str] = &[".txt", ".txt.gz", ".txt.zstd", ".txt.zst"];
+
+// What --doc-per-file looks for in dirs, one JSON doc to a file
+const DOC_FILE_EXTS: &[&str] = &[".json", ".json.gz", ".json.zstd", ".json.zst"];
+
+impl TextEncoding {
+    fn name(self) -> String {
+        // As given on the command line and recorded in metas
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+
+    fn from_meta(meta: &Value) -> Result<Self, Error> {
+        // A meta without an encoding is from UTF-8 input
+        match meta["encoding"].as_str() {
+            Some(name) => TextEncoding::from_str(name, false).map_err(|err| anyhow!("Unknown encoding in meta: {}", err)),
+            None => Ok(TextEncoding::Utf8)
+        }
+    }
+}
+
+fn read_decoded(path: &PathBuf, encoding: TextEncoding) -> Result<Box<dyn BufRead>, Error> {
+    // read_pathbuf_streaming, with the (decompressed) contents decoded to UTF-8
+    decode_to_utf8(read_pathbuf_streaming(path)?, encoding, path)
+}
+
+fn decode_to_utf8(mut data: Box<dyn BufRead>, encoding: TextEncoding, path: &PathBuf) -> Result<Box<dyn BufRead>, Error> {
+    // UTF-8 streams through untouched. Anything else is decoded whole before splitting lines, which keeps
+    // UTF-16's two-byte newlines intact. Malformed UTF-16 becomes U+FFFD
+    let mut read_whole = || -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        data.read_to_end(&mut bytes).with_context(|| format!("Failed to read {:?}", path))?;
+        Ok(bytes)
+    };
+    let decoded = match encoding {
+        TextEncoding::Utf8 => return Ok(data),
+        TextEncoding::Latin1 => {
+            let bytes = read_whole()?;
+            let mut decoded = vec![0; bytes.len() * 2];
+            let len = encoding_rs::mem::convert_latin1_to_utf8(&bytes, &mut decoded);
+            decoded.truncate(len);
+            decoded
+        },
+        TextEncoding::Utf16le => {
+            let bytes = read_whole()?;
+            let (text, had_errors) = encoding_rs::UTF_16LE.decode_with_bom_removal(&bytes);
+            if had_errors {
+                warn!("Replaced malformed UTF-16LE in {:?} with U+FFFD", path);
+            }
+            text.into_owned().into_bytes()
+        }
+    };
+    Ok(Box::new(Cursor::new(decoded)))
+}
+
+// Matches are written as a sequence of crc-framed bincode chunks of at most this many tuples
+const MATCHES_PER_RECORD: usize = 1 << 20;
+
+fn match_shard_name(shard: usize, compression: &CompressionArgs) -> String {
+    compression.name(&format!("matches.{:02}.bin", shard))
+}
+
+
+fn file_fingerprint(path: &PathBuf) -> Value {
+    // What a resumed run compares to decide if a finished file changed. s3 and stdin inputs have none,
+    // so they're only ever keyed on path
+    match std::fs::metadata(path) {
+        Ok(metadata) => json!({"size": metadata.len(),
+                               "modified": metadata.modified().ok()
+                                   .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                                   .map(|d| d.as_nanos() as u64)}),
+        Err(_) => Value::Null
+    }
+}
+
+
+fn done_set_matches(done: &serde_json::Map<String, Value>, num_sets: usize, progress_path: &PathBuf) -> Result<Vec<usize>, Error> {
+    // Progress from before there were sets holds a single count, which is only the one set's
+    let mut set_matches: Vec<usize> = vec![0; num_sets];
+    for (path, entry) in done {
+        let counts: Vec<u64> = match &entry["num_matches"] {
+            Value::Array(counts) => counts.iter().map(|count| count.as_u64().unwrap_or(0)).collect(),
+            Value::Number(count) if num_sets == 1 => vec![count.as_u64().unwrap_or(0)],
+            other => return Err(anyhow!("{:?} records {} matches for {:?}, not one count per --data-file. Rerun without \
+                                         --resume to start over", progress_path, other, path))
+        };
+        if counts.len() != num_sets {
+            return Err(anyhow!("{:?} records match counts for {} SAs for {:?}, but there are {} --data-file",
+                               progress_path, counts.len(), path, num_sets));
+        }
+        for (set, count) in counts.into_iter().enumerate() {
+            set_matches[set] += count as usize;
+        }
+    }
+    Ok(set_matches)
+}
+
+
+// Stream workers keep progress current in memory after every file, but rewriting all of progress.json that often
+// is quadratic in the number of files, so it goes to disk at most this often (and once more when they're done)
+const PROGRESS_WRITE_SECS: f64 = 10.0;
+
+fn write_progress(progress: &Value, progress_path: &PathBuf) -> Result<(), Error> {
+    // Write-then-rename, so a crash mid-write leaves the previous progress.json intact
+    let tmp_path = PathBuf::from(format!("{}.tmp", progress_path.display()));
+    std::fs::write(&tmp_path, serde_json::to_vec(progress)?)?;
+    std::fs::rename(&tmp_path, progress_path)?;
+    Ok(())
+}
+
+
+fn drop_paths_from_shard(shard_path: &PathBuf, path_ids: &HashSet<usize>, legacy_format: bool, level: Option<i32>
+                         ) -> Result<(), Error> {
+    // Rewrites a (already truncated) shard without any matches from path_ids
+    let shard_bytes = read_file_bytes(shard_path)?;
+    let mut kept = deserialize_matches(&shard_bytes, true)?;
+    kept.retain(|(path_id, _, _)| !path_ids.contains(path_id));
+    let mut rewritten = compress_data(Vec::new(), shard_path, level)?;
+    rewritten.extend(compress_data(serialize_matches(&kept, legacy_format)?, shard_path, level)?);
+    std::fs::write(shard_path, rewritten)?;
+    Ok(())
+}
+
+
+fn match_files(match_location: &PathBuf, meta: &Option<Value>) -> Result<Vec<PathBuf>, Error> {
+    // A directory must hold exactly the shards its meta says were written, so stale shards from
+    // an earlier run with more threads don't get silently mixed in
+    // A prefixed set's meta file stands for the directory, restricted to that prefix's shards
+    let stands_for_dir = match_location.to_string_lossy().ends_with("matches.meta.json");
+    if match_dir(match_location) != *match_location && !stands_for_dir {
+        return Ok(vec![match_location.clone()]);
+    }
+    let prefix = match_prefix(match_location);
+    let mut shards: Vec<PathBuf> = expand_dirs(vec![match_dir(match_location)], Some(&[".bin", ".bin.gz", ".bin.zst"]))?
+        .into_iter()
+        .filter(|p| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with(&format!("{}matches.", prefix))))
+        .collect();
+    shards.sort();
+    let num_shards = match meta.as_ref().and_then(|m| m["num_shards"].as_u64()) {
+        Some(num_shards) => num_shards,
+        // A non-streamed run's dir holds its single matches.bin file
+        None if shards.len() == 1 && shards[0].file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with(&format!("{}matches.bin", prefix))) => 1,
+        None => return Err(anyhow!("{:?} is a directory, but its matches.meta.json doesn't record num_shards", match_location))
+    };
+    if shards.len() as u64 != num_shards {
+        return Err(anyhow!("Found {} match shards in {:?}, but matches.meta.json says {} were written",
+                           shards.len(), match_location, num_shards));
+    }
+    Ok(shards)
+}
+
+
+// sa_pos of the sentinel match recorded for a train line that hit --max-matches-per-doc. mark_contaminates
+// flags every val doc the line's kept matches land in, whatever their coverage
+pub const SATURATED: u64 = u64::MAX;
+
+
+// What build_matches --train-offsets keeps for each match next to matches.bin: (path_id, line_num, sa_pos,
+// the window's offset in the train line, the train line's length), offsets and length in bytes of the
+// windowed (normalized, encoded) line. mark_contaminates --train-coverage-threshold reads these
+pub type TrainOffset = (usize, usize, u64, u32, u32);
+
+
+// Opens a varint match record. A bincode record opens with its u64 LE length, which is at most MATCHES_PER_RECORD
+// and so always has a zero fourth byte, so readers can tell the two apart record by record
+const VARINT_MATCHES_MAGIC: &[u8; 4] = b"SAVM";
+
+pub fn serialize_matches(matches: &[(usize, usize, u64)], legacy_format: bool) -> Result<Vec<u8>, Error> {
+    let mut output: Vec<u8> = Vec::new();
+    write_matches(matches, legacy_format, &mut output)?;
+    Ok(output)
+}
+
+
+pub fn write_matches(matches: &[(usize, usize, u64)], legacy_format: bool, writer: &mut dyn Write) -> Result<(), Error> {
+    // The serialize_matches layout, written a record at a time. Records are varint coded unless legacy_format
+    // asks for the bincode tuples older builds read
+    let mut record: Vec<u8> = Vec::new();
+    let mut payload: Vec<u8> = Vec::new();
+    for chunk in matches.chunks(MATCHES_PER_RECORD) {
+        record.clear();
+        match legacy_format {
+            true => frame_record(&bincode::serialize(chunk)?, &mut record),
+            false => {
+                payload.clear();
+                encode_match_record(chunk, &mut payload);
+                frame_record(&payload, &mut record);
+            }
+        }
+        writer.write_all(&record)?;
+    }
+    Ok(())
+}
+
+
+pub fn deserialize_matches(data: &[u8], strict: bool) -> Result<Vec<(usize, usize, u64)>, Error> {
+    let mut matches: Vec<(usize, usize, u64)> = Vec::new();
+    for record in unframe_records(data, strict)? {
+        matches.extend(decode_match_record(record)?);
+    }
+    Ok(matches)
+}
+
+
+fn encode_match_record(chunk: &[(usize, usize, u64)], buf: &mut Vec<u8>) {
+    // Each match is stored against the one before it: the change in path id, then the line (as a change if the
+    // path is the same), then sa_pos (as a change if the line is too). Changes are zigzagged, so any order
+    // round-trips, but build_matches writes each path's matches sorted and so mostly small positive steps
+    buf.extend_from_slice(VARINT_MATCHES_MAGIC);
+    push_varint(chunk.len() as u64, buf);
+    let (mut prev_path, mut prev_line, mut prev_pos) = (0, 0, 0);
+    for &(path_id, line_num, sa_pos) in chunk {
+        let same_path = path_id == prev_path;
+        let same_line = same_path && line_num == prev_line;
+        push_varint(zigzag((path_id as u64).wrapping_sub(prev_path as u64)), buf);
+        push_varint(if same_path { zigzag((line_num as u64).wrapping_sub(prev_line as u64)) } else { line_num as u64 }, buf);
+        push_varint(if same_line { zigzag(sa_pos.wrapping_sub(prev_pos)) } else { sa_pos }, buf);
+        (prev_path, prev_line, prev_pos) = (path_id, line_num, sa_pos);
+    }
+}
+
+
+pub fn decode_match_record(record: &[u8]) -> Result<Vec<(usize, usize, u64)>, Error> {
+    // One framed record's matches, in either encoding
+    if !record.starts_with(VARINT_MATCHES_MAGIC) {
+        return Ok(bincode::deserialize(record)?);
+    }
+    let mut offset = VARINT_MATCHES_MAGIC.len();
+    let num_matches = read_varint(record, &mut offset)? as usize;
+    let mut matches: Vec<(usize, usize, u64)> = Vec::with_capacity(cmp::min(num_matches, MATCHES_PER_RECORD));
+    let (mut prev_path, mut prev_line, mut prev_pos) = (0usize, 0usize, 0u64);
+    for _ in 0..num_matches {
+        let path_id = (prev_path as u64).wrapping_add(unzigzag(read_varint(record, &mut offset)?)) as usize;
+        let same_path = path_id == prev_path;
+        let line_num = match (same_path, read_varint(record, &mut offset)?) {
+            (true, step) => (prev_line as u64).wrapping_add(unzigzag(step)) as usize,
+            (false, line_num) => line_num as usize
+        };
+        let sa_pos = match (same_path && line_num == prev_line, read_varint(record, &mut offset)?) {
+            (true, step) => prev_pos.wrapping_add(unzigzag(step)),
+            (false, sa_pos) => sa_pos
+        };
+        matches.push((path_id, line_num, sa_pos));
+        (prev_path, prev_line, prev_pos) = (path_id, line_num, sa_pos);
+    }
+    if offset != record.len() {
+        return Err(anyhow!("Match record has {} bytes left over after its {} matches", record.len() - offset, num_matches));
+    }
+    Ok(matches)
+}
+
+
+fn push_varint(mut value: u64, buf: &mut Vec<u8>) {
+    // LEB128: 7 bits a byte, low bits first, high bit set on all but the last
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+
+fn read_varint(data: &[u8], offset: &mut usize) -> Result<u64, Error> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*offset).ok_or_else(|| anyhow!("Match record ends partway through a varint"))?;
+        *offset += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Varint in match record runs past 64 bits"))
+}
+
+
+fn zigzag(step: u64) -> u64 {
+    // A wrapped difference read as signed, folded so small steps either way are small numbers
+    let step = step as i64;
+    ((step << 1) ^ (step >> 63)) as u64
+}
+
+
+fn unzigzag(value: u64) -> u64 {
+    ((value >> 1) as i64 ^ -((value & 1) as i64)) as u64
+}
+
+
+pub fn write_train_offsets(offsets: &[TrainOffset], writer: &mut dyn Write) -> Result<(), Error> {
+    // Same crc-framed bincode records as matches
+    let mut record: Vec<u8> = Vec::new();
+    for chunk in offsets.chunks(MATCHES_PER_RECORD) {
+        record.clear();
+        frame_record(&bincode::serialize(chunk)?, &mut record);
+        writer.write_all(&record)?;
+    }
+    Ok(())
+}
+
+
+pub fn deserialize_train_offsets(data: &[u8], strict: bool) -> Result<Vec<TrainOffset>, Error> {
+    let mut offsets: Vec<TrainOffset> = Vec::new();
+    for record in unframe_records(data, strict)? {
+        let chunk: Vec<TrainOffset> = bincode::deserialize(record)?;
+        offsets.extend(chunk);
+    }
+    Ok(offsets)
+}
+
+
+#[derive(Default)]
+pub struct CollectStats {
+    // Lines skipped in collect_matches, by reason. No text is split up by MissingText, plus lines whose text is ""
+    pub text_absent: usize,
+    pub text_null: usize,
+    pub text_not_string: usize,
+    pub text_empty: usize,
+    pub malformed: usize,
+    // Empty or whitespace-only json lines, passed over without parsing
+    pub blank: usize,
+    // Lines too short to hold a single window
+    pub skipped_short: usize,
+    // Lines whose text was under min_doc_len bytes
+    pub skipped_min_len: usize,
+    // Windows skipped for occurring more than max_occurrences times, counted once per SA
+    pub common_windows: usize,
+    // Windows looked up, windows --sample-rate left out, and how many (window, SA) searches the Bloom filter ruled out
+    pub windows: usize,
+    pub unsampled: usize,
+    pub bloom_skipped: usize,
+    // Lines that hit max_matches_per_doc in at least one SA
+    pub saturated: usize,
+    // Lines over max_line_bytes, cut short or skipped
+    pub truncated_long: usize,
+    pub skipped_long: usize,
+    // Windows by how often they occur, per SA: bucket 0 is never, bucket k > 0 is [2^(k-1), 2^k) times.
+    // Only kept with occurrence_histogram
+    pub occurrence_buckets: Vec<Vec<usize>>,
+    // Seconds build_matches' workers spent getting files (reading them, or waiting on the prefetcher) and
+    // windowing and searching them, summed over files
+    pub read_secs: f64,
+    pub compute_secs: f64
+}
+
+impl CollectStats {
+    pub fn missing_text(&self) -> usize {
+        self.text_absent + self.text_null + self.text_not_string
+    }
+
+    fn tally_occurrences(&mut self, sa_idx: usize, num_occurrences: u64) {
+        let bucket = (u64::BITS - num_occurrences.leading_zeros()) as usize;
+        if self.occurrence_buckets.len() <= sa_idx {
+            self.occurrence_buckets.resize(sa_idx + 1, Vec::new());
+        }
+        let buckets = &mut self.occurrence_buckets[sa_idx];
+        if buckets.len() <= bucket {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    }
+
+    fn count_missing(&mut self, reason: MissingText) {
+        match reason {
+            MissingText::Absent => self.text_absent += 1,
+            MissingText::Null => self.text_null += 1,
+            MissingText::NotString => self.text_not_string += 1
+        }
+    }
+
+    pub fn add(&mut self, other: &CollectStats) {
+        self.text_absent += other.text_absent;
+        self.text_null += other.text_null;
+        self.text_not_string += other.text_not_string;
+        self.text_empty += other.text_empty;
+        self.malformed += other.malformed;
+        self.blank += other.blank;
+        self.skipped_short += other.skipped_short;
+        self.skipped_min_len += other.skipped_min_len;
+        self.common_windows += other.common_windows;
+        self.unsampled += other.unsampled;
+        self.windows += other.windows;
+        self.bloom_skipped += other.bloom_skipped;
+        self.saturated += other.saturated;
+        self.truncated_long += other.truncated_long;
+        self.skipped_long += other.skipped_long;
+        self.read_secs += other.read_secs;
+        self.compute_secs += other.compute_secs;
+        if self.occurrence_buckets.len() < other.occurrence_buckets.len() {
+            self.occurrence_buckets.resize(other.occurrence_buckets.len(), Vec::new());
+        }
+        for (buckets, other_buckets) in self.occurrence_buckets.iter_mut().zip(&other.occurrence_buckets) {
+            if buckets.len() < other_buckets.len() {
+                buckets.resize(other_buckets.len(), 0);
+            }
+            for (count, other_count) in buckets.iter_mut().zip(other_buckets) {
+                *count += other_count;
+            }
+        }
+    }
+}
+
+
+pub struct MatchParams<'a> {
+    // Everything about how train lines are turned into SA queries, fixed for a whole build_matches run
+    pub match_size: usize,
+    pub text_fields: &'a [String],
+    pub field_separator: &'a str,
+    pub raw_lines: bool,
+    pub doc_per_file: bool,
+    pub json_string_as_text: bool,
+    pub encoding: TextEncoding,
+    pub encoder: &'a Option<TokenEncoder>,
+    pub char_windows: bool,
+    pub normalize: &'a [NormalizeStep],
+    pub case_insensitive: bool,
+    pub stride: usize,
+    pub sample_rate: f64,
+    pub skip_errors: bool,
+    pub max_occurrences: Option<u64>,
+    pub min_doc_len: usize,
+    pub max_matches_per_doc: Option<usize>,
+    pub max_line_bytes: Option<usize>,
+    pub long_lines: LongLineMode,
+    pub train_offsets: bool,
+    pub occurrence_histogram: bool
+}
+
+
+pub struct LoadedSa {
+    // One val set's SA as build_matches queries it, each shard with its own Bloom filter if --bloom is on
+    pub shards: Vec<(SaShard, Option<BloomFilter>)>
+}
+
+impl LoadedSa {
+    pub fn load(data_file: &PathBuf, mmap: bool) -> Result<Self, Error> {
+        // The SA at data_file (every shard, if it's sharded) without Bloom filters, for querying outside build_matches
+        let shards = load_sa_shards(&SaFiles::new(data_file), mmap).with_context(|| format!("Failed to load SA at {:?}", data_file))?;
+        Ok(LoadedSa {shards: shards.into_iter().map(|shard| (shard, None)).collect()})
+    }
+}
+
+
+pub fn is_contaminated(sa: &LoadedSa, text: &[u8], match_size: usize, threshold: f64) -> (bool, f32) {
+    // Checks one in-memory doc against sa the way build_matches and mark_contaminates would: its match_size-byte
+    // windows found anywhere in the SA's text are merged, then the fraction of text they cover has to reach threshold.
+    // Returns the verdict and that fraction. text isn't normalized or tokenized here, so it has to be in the form
+    // the SA's text is (and a tokenized SA's windows are match_size bytes, not tokens). Text shorter than
+    // match_size has no windows at all, so like a train line that short it can't match: (false, 0.0)
+    let intervals: Vec<(usize, usize)> = text.windows(cmp::max(match_size, 1)).enumerate()
+        .filter(|(_, window)| sa.shards.iter()
+            .filter(|(_, bloom)| bloom.as_ref().is_none_or(|bloom| bloom.contains(window)))
+            .any(|(shard, _)| count_occurrences_sharded(std::iter::once(shard), window) > 0))
+        .map(|(start, _)| (start, start + match_size))
+        .collect();
+    let num_windows = intervals.len();
+    let covered: usize = _merge_intervals(intervals, true, 0, MergeAdjacency::Touching).iter().map(|(s, e)| e - s).sum();
+    let coverage = covered as f64 / cmp::max(text.len(), 1) as f64;
+    let threshold = ContaminationThreshold {fraction: Some(threshold), min_overlap_bytes: None, min_distinct_windows: None,
+                                            mode: ThresholdMode::And, metric: MatchMetric::Coverage, gap_tolerance: 0,
+                                            min_span: None, adjacency: MergeAdjacency::Touching, by_length: Vec::new(),
+                                            ignore_phrases: None, max_starts: None, score_all: false};
+    (num_windows > 0 && threshold.passes(covered, text.len(), covered, num_windows, text.len()), coverage as f32)
+}
+
+
+fn path_sample_seed(path: &Path) -> u64 {
+    // FNV-1a of the path as given, so a file keeps its sampled windows whatever else is in the trainset
+    // (its path_idx shifts as files come and go)
+    path.to_string_lossy().bytes().fold(0xcbf29ce484222325, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+
+fn sampled_window(path_seed: u64, line_num: usize, query_idx: usize, sample_rate: f64) -> bool {
+    // Whether --sample-rate keeps the window at (path, line_num, query_idx), the path by its path_sample_seed.
+    // splitmix64 of the position is the same on every run and thread, and spread evenly enough that about
+    // sample_rate of windows pass
+    let mut h = path_seed.wrapping_mul(0x9e3779b97f4a7c15) ^ (line_num as u64).rotate_left(21) ^ (query_idx as u64).rotate_left(42);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^= h >> 31;
+    ((h >> 11) as f64 / (1u64 << 53) as f64) < sample_rate
+}
+
+
+// One train file's matches and (with --train-offsets) offsets, a vec of each per SA, and how collecting them went
+pub type FileMatches = (Vec<Vec<(usize, usize, u64)>>, Vec<Vec<TrainOffset>>, CollectStats);
+
+pub fn collect_matches(path: &PathBuf, path_idx: usize, sas: &[LoadedSa], params: &MatchParams
+                   ) -> Result<FileMatches, Error> {
+    let data = read_pathbuf_streaming(path).with_context(|| format!("Failed to read {:?}", path))?;
+    collect_matches_from(data, path, path_idx, sas, params)
+}
+
+
+pub fn collect_matches_from(data: Box<dyn BufRead>, path: &PathBuf, path_idx: usize, sas: &[LoadedSa],
+                            params: &MatchParams
+                            ) -> Result<FileMatches, Error> {
+    // collect_matches over path's contents, already opened (or read, by build_matches' prefetcher).
+    // Each document might match with format
+    // (trainset_path_id, line_num, suffix_array_idx), with one vec of these per SA in sas.
+    // Lines are read and windowed once, then every window is looked up in each SA.
+    // Also returns each match's train offsets if asked for (empty otherwise), and counts of the lines and windows we had to skip
+    let &MatchParams {match_size, text_fields, field_separator, raw_lines, doc_per_file, json_string_as_text, encoding, encoder, char_windows, normalize, case_insensitive, stride, sample_rate, skip_errors,
+                      max_occurrences, min_doc_len, max_matches_per_doc, max_line_bytes, long_lines, train_offsets,
+                      occurrence_histogram} = params;
+
+    let mut output: Vec<Vec<(usize, usize, u64)>> = sas.iter().map(|_| Vec::new()).collect();
+    let mut offset_output: Vec<Vec<TrainOffset>> = sas.iter().map(|_| Vec::new()).collect();
+    let mut stats = CollectStats::default();
+    let path_seed = path_sample_seed(path);
+    let data = decode_to_utf8(data, encoding, path)?;
+    // A file that's one doc is read as a single line 0, newlines and all
+    let lines: Box<dyn Iterator<Item=std::io::Result<String>>> = match doc_per_file {
+        true => Box::new(std::iter::once(std::io::read_to_string(data))),
+        false => Box::new(data.lines())
+    };
+
+    for (line_num, line) in lines.enumerate() {
+        let line = line.with_context(|| format!("Failed to read line {} of {:?}", line_num, path))?;
+        // Line numbers still count a stripped BOM's line and skipped blank ones, so they stay the file's own
+        let line = match line_num {
+            0 => line.strip_prefix('\u{feff}').unwrap_or(&line),
+            _ => &line
+        };
+        if !raw_lines && line.trim().is_empty() {
+            stats.blank += 1;
+            continue;
+        }
+        // A JSON string never decodes to more bytes than it takes up in the line, so with one field a short
+        // line can be skipped unparsed. Joined fields (several of them, or a "[]" step's array elements) gain
+        // separators, so those have to wait for extraction
+        if text_fields.len() == 1 && !text_fields[0].contains("[]") && line.len() < min_doc_len {
+            stats.skipped_min_len += 1;
+            continue;
+        }
+        // Raw lines have no JSON to parse, only the text itself
+        let json: Value = match raw_lines {
+            true => Value::Null,
+            false => match serde_json::from_str(line) {
+                Ok(json) => json,
+                Err(err) if skip_errors => {
+                    warn!("Skipping malformed line {} of {:?}: {}", line_num, path, err);
+                    stats.malformed += 1;
+                    continue;
+                },
+                Err(err) => return Err(err).with_context(|| format!("Malformed json on line {} of {:?}", line_num, path))
+            }
+        };
+        // A bare string or number parses fine but has no fields to read, so it's skipped like unparseable json
+        if !raw_lines && !json.is_object() && !(json_string_as_text && json.is_string()) {
+            warn!("Skipping line {} of {:?}: top-level json is {}, not an object", line_num, path, json_kind(&json));
+            stats.malformed += 1;
+            continue;
+        }
+        // Pre-tokenized lines skip text extraction; their ids already are the unit stream. Their line_text
+        // stays empty, as it's only read for char windows, which can't be combined with input ids
+        let fields_text: Cow<str>;
+        let line_text: Cow<str>;
+        // Windows are match_size units wide and step stride units at a time, where a unit is a byte or a token
+        let (line_bytes, unit_width): (Cow<[u8]>, usize) = match encoder {
+            Some(encoder) if encoder.reads_input_ids() => {
+                line_text = Cow::Borrowed("");
+                match encoder.encode_ids(&json).with_context(|| format!("Line {} of {:?}", line_num, path))? {
+                    Some(mut ids) => {
+                        if let Some(max) = max_line_bytes.filter(|max| ids.len() > *max) {
+                            match long_lines {
+                                LongLineMode::Skip => {
+                                    stats.skipped_long += 1;
+                                    continue;
+                                },
+                                LongLineMode::Truncate => {
+                                    stats.truncated_long += 1;
+                                    ids.truncate(max - max % encoder.token_width);
+                                }
+                            }
+                        }
+                        (Cow::Owned(ids), encoder.token_width)
+                    },
+                    None => {
+                        // encode_ids errors on anything but a missing field or an array
+                        stats.text_absent += 1;
+                        continue;
+                    }
+                }
+            },
+            _ => {
+                let extracted = match raw_lines {
+                    true => Some(Cow::Borrowed(line)),
+                    false => match json.as_str() {
+                        Some(text) => Some(Cow::Borrowed(text)),
+                        None => extract_fields(&json, text_fields, field_separator)
+                    }
+                };
+                fields_text = match extracted {
+                    Some(fields_text) if fields_text.is_empty() => {
+                        stats.text_empty += 1;
+                        continue;
+                    },
+                    Some(fields_text) => fields_text,
+                    None => {
+                        stats.count_missing(missing_text(&json, text_fields));
+                        continue;
+                    }
+                };
+                if fields_text.len() < min_doc_len {
+                    stats.skipped_min_len += 1;
+                    continue;
+                }
+                // Cut back to the last char boundary at or under the cap, so the kept text is still valid UTF-8
+                let mut text_end = fields_text.len();
+                if let Some(max) = max_line_bytes.filter(|max| text_end > *max) {
+                    match long_lines {
+                        LongLineMode::Skip => {
+                            stats.skipped_long += 1;
+                            continue;
+                        },
+                        LongLineMode::Truncate => {
+                            stats.truncated_long += 1;
+                            text_end = (0..=max).rev().find(|end| fields_text.is_char_boundary(*end)).unwrap_or(0);
+                        }
+                    }
+                }
+                line_text = fold_case(normalize_text(&fields_text[..text_end], normalize), case_insensitive);
+                match encoder {
+                    Some(encoder) => (Cow::Owned(encoder.encode(&line_text).with_context(|| format!("Line {} of {:?}", line_num, path))?),
+                                      encoder.token_width),
+                    None => (Cow::Borrowed(line_text.as_bytes()), 1)
+                }
+            }
+        };
+        let line_units = match char_windows {
+            true => line_text.chars().count(),
+            false => line_bytes.len() / unit_width
+        };
+        if line_units < match_size {
+            stats.skipped_short += 1;
+            continue;
+        }
+        if train_offsets && line_bytes.len() > u32::MAX as usize {
+            return Err(anyhow!("Line {} of {:?} is {} bytes, too long for --train-offsets. Cap it with --max-line-bytes",
+                               line_num, path, line_bytes.len()));
+        }
+        let queries: Vec<&[u8]> = if char_windows {
+            // Windows run between char boundaries, so each is match_size chars but a variable number of bytes
+            let bounds: Vec<usize> = line_text.char_indices().map(|(i, _)| i).chain(std::iter::once(line_text.len())).collect();
+            (0..bounds.len().saturating_sub(match_size)).step_by(stride)
+                .map(|i| &line_bytes[bounds[i]..bounds[i + match_size]])
+                .collect()
+        } else {
+            line_bytes.windows(match_size * unit_width).step_by(stride * unit_width).collect()
+        };
+        // Sampled out windows are dropped before any search, keeping their query_idx for train offsets
+        let line_windows = queries.len();
+        let queries: Vec<(usize, &[u8])> = queries.into_iter().enumerate()
+            .filter(|(query_idx, _)| sample_rate >= 1.0 || sampled_window(path_seed, line_num, *query_idx, sample_rate))
+            .collect();
+        stats.unsampled += line_windows - queries.len();
+        stats.windows += queries.len();
+        // Once a line has max_matches_per_doc matches in an SA it stops collecting there, and gets a SATURATED
+        // sentinel instead of the rest
+        let cap = max_matches_per_doc.unwrap_or(usize::MAX);
+        let mut line_matches: Vec<usize> = vec![0; sas.len()];
+        for (query_idx, query) in queries {
+            if line_matches.iter().all(|&found| found >= cap) {
+                break;
+            }
+            for (sa_idx, (((sa, sa_output), sa_offsets), found)) in sas.iter().zip(output.iter_mut()).zip(offset_output.iter_mut())
+                                                                         .zip(line_matches.iter_mut()).enumerate() {
+                if *found >= cap {
+                    continue;
+                }
+                // Only search the shards whose Bloom filter doesn't rule the window out
+                let candidates: Vec<&SaShard> = sa.shards.iter()
+                    .filter(|(_, bloom)| bloom.as_ref().is_none_or(|bloom| bloom.contains(query)))
+                    .map(|(shard, _)| shard)
+                    .collect();
+                if candidates.is_empty() {
+                    stats.bloom_skipped += 1;
+                    if occurrence_histogram {
+                        stats.tally_occurrences(sa_idx, 0);
+                    }
+                    continue;
+                }
+                if occurrence_histogram {
+                    stats.tally_occurrences(sa_idx, count_occurrences_sharded(candidates.iter().copied(), query));
+                }
+                let occurrences = match get_occurrences_sharded(candidates, query, max_occurrences) {
+                    Some(occurrences) => occurrences,
+                    None => {
+                        stats.common_windows += 1;
+                        continue;
+                    }
+                };
+                // Positions are read lazily, so a common window only costs as many as the line still has room for.
+                // Ones straddling a token boundary in the val text aren't real token-level matches
+                let room = cap - *found;
+                for text_idx in occurrences.filter(|text_idx| text_idx % (unit_width as u64) == 0).take(room) {
+                    sa_output.push((path_idx, line_num, text_idx));
+                    if train_offsets {
+                        sa_offsets.push((path_idx, line_num, text_idx, (query_idx * stride * unit_width) as u32, line_bytes.len() as u32));
+                    }
+                    *found += 1;
+                }
+            }
+        }
+        if line_matches.iter().any(|&found| found >= cap) {
+            stats.saturated += 1;
+            for (sa_output, found) in output.iter_mut().zip(&line_matches) {
+                if *found >= cap {
+                    sa_output.push((path_idx, line_num, SATURATED));
+                }
+            }
+        }
+    }
+    if stats.missing_text() > 0 {
+        warn!("Skipped {} lines in {:?} with no string at any of {:?}", stats.missing_text(), path, text_fields);
+    }
+    if stats.truncated_long + stats.skipped_long > 0 {
+        warn!("{} {} lines in {:?} over --max-line-bytes {}", if long_lines == LongLineMode::Skip { "Skipped" } else { "Truncated" },
+              stats.truncated_long + stats.skipped_long, path, max_line_bytes.unwrap_or(0));
+    }
+    Ok((output, offset_output, stats))
+}
+
+
+
+/*=================================================================
+=                      MARK CONTAMINATES HELPERS                  =
+=================================================================*/
+
+// (val_doc_id, trainset_path_id, line_num, coverage), where coverage is the fraction --metric measured
+pub type Contaminate = (usize, usize, usize, f32);
+
+// Bincode contaminates start with this magic and version. Files without it are from before coverage was
+// recorded, and hold bare (val_doc_id, trainset_path_id, line_num) tuples. Version 3 follows the version with
+// the matches' run_id (u32 LE length, then the id, empty if they had none)
+const CONTAMINATES_MAGIC: &[u8; 4] = b"SACT";
+const CONTAMINATES_VERSION: u32 = 3;
+
+// One val doc's matches: every train line that hit it, with that line's sorted match starts
+pub type DocMatches<'a> = [((usize, usize), &'a [u64])];
+
+// {(val_doc_id, val_doc_size) -> {(train_path_id, line_num) -> [in_doc_pos]}}, filled concurrently
+pub type GroupMap = DashMap<(usize, usize), DashMap<(usize, usize), Vec<u64>>>;
+
+pub enum MatchGroups {
+    Map(GroupMap),
+    // (val_doc_id, train_path_id, line_num, in_doc_pos), sorted, and the index each val doc's run starts at
+    Sorted(Vec<(usize, usize, usize, u64)>, Vec<usize>)
+}
+
+impl MatchGroups {
+    pub fn sorted(matches: Vec<(usize, usize, usize, u64)>) -> Self {
+        // Groups from already sorted (val_doc_id, train_path_id, line_num, in_doc_pos) tuples
+        let doc_starts: Vec<usize> = (0..matches.len()).into_par_iter()
+            .filter(|&i| i == 0 || matches[i].0 != matches[i - 1].0)
+            .collect();
+        MatchGroups::Sorted(matches, doc_starts)
+    }
+
+    pub fn num_docs(&self) -> usize {
+        match self {
+            MatchGroups::Map(groups) => groups.len(),
+            MatchGroups::Sorted(_, doc_starts) => doc_starts.len()
+        }
+    }
+
+    pub fn starts(&self, val_doc_id: usize, val_doc_size: usize, train_line: (usize, usize)) -> Option<Vec<u64>> {
+        // One train line's match starts in one val doc
+        match self {
+            MatchGroups::Map(groups) => groups.get(&(val_doc_id, val_doc_size))?.get(&train_line).map(|starts| starts.clone()),
+            MatchGroups::Sorted(matches, _) => {
+                let key = (val_doc_id, train_line.0, train_line.1);
+                let start = matches.partition_point(|&(doc, path, line, _)| (doc, path, line) < key);
+                let end = matches.partition_point(|&(doc, path, line, _)| (doc, path, line) <= key);
+                (start < end).then(|| matches[start..end].iter().map(|&(_, _, _, pos)| pos).collect())
+            }
+        }
+    }
+
+    pub fn par_map_docs<T: Send>(&self, size_object: &[u64],
+                                 f: impl Fn(usize, usize, &DocMatches) -> Result<T, Error> + Sync + Send) -> Result<Vec<T>, Error> {
+        // Runs f(val_doc_id, val_doc_size, matches) over the val docs in parallel, handing back results in no
+        // particular order. Sorted groups only ever build one doc's worth of start vecs per task. Map groups are
+        // listed (as read guards, so they're still there for --spotcheck) before scoring, since rayon splits an
+        // indexed Vec evenly and sizes the result up front, where par_bridge hands out the map's entries one by one
+        match self {
+            MatchGroups::Map(groups) => groups.iter().collect::<Vec<_>>().into_par_iter().map(|entry| {
+                let (val_doc_id, val_doc_size) = *entry.key();
+                let lines: Vec<_> = entry.value().iter().collect();
+                let doc_matches: Vec<((usize, usize), &[u64])> = lines.iter()
+                    .map(|line| (*line.key(), line.value().as_slice()))
+                    .collect();
+                f(val_doc_id, val_doc_size, &doc_matches)
+            }).collect(),
+            MatchGroups::Sorted(matches, doc_starts) => (0..doc_starts.len()).into_par_iter().map(|i| {
+                let doc = &matches[doc_starts[i]..doc_starts.get(i + 1).copied().unwrap_or(matches.len())];
+                let val_doc_id = doc[0].0;
+                let lines: Vec<((usize, usize), Vec<u64>)> = doc.chunk_by(|a, b| (a.1, a.2) == (b.1, b.2))
+                    .map(|run| ((run[0].1, run[0].2), run.iter().map(|&(_, _, _, pos)| pos).collect()))
+                    .collect();
+                let doc_matches: Vec<((usize, usize), &[u64])> = lines.iter()
+                    .map(|(train_line, starts)| (*train_line, starts.as_slice()))
+                    .collect();
+                f(val_doc_id, (size_object[val_doc_id + 1] - size_object[val_doc_id]) as usize, &doc_matches)
+            }).collect()
+        }
+    }
+}
+
+
+// Groups --group-mode external sorts in memory before spilling them as one run, and roughly how
+// many it merges back to score at once
+const SPILL_RUN_ENTRIES: usize = 1 << 22;
+const EXTERNAL_BATCH_ENTRIES: usize = 1 << 20;
+
+static SPILL_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct SpillDir(pub PathBuf);
+
+impl SpillDir {
+    pub fn new(parent: PathBuf) -> Result<Self, Error> {
+        // Unique to this process and call, so concurrent runs (or calls) sharing --spill-dir never share runs
+        let dir = parent.join(format!("sa_decontamination_spill_{}_{}", std::process::id(),
+                                      SPILL_DIRS.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir(&dir).with_context(|| format!("Failed to create spill dir {:?}", dir))?;
+        Ok(SpillDir(dir))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        // Removed however scoring ends, errors included
+        if let Err(err) = std::fs::remove_dir_all(&self.0) {
+            warn!("Failed to clean up spill dir {:?}: {}", self.0, err);
+        }
+    }
+}
+
+// A spilled match group: (val_doc_id, train_path_id, line_num, in_doc_pos)
+pub type Spilled = (usize, usize, usize, u64);
+
+pub struct SpillRuns {
+    // Match groups sorted a buffer at a time into run files of little-endian u64 quadruples
+    pub dir: SpillDir,
+    buffer: Vec<Spilled>,
+    runs: Vec<PathBuf>
+}
+
+impl SpillRuns {
+    pub fn new(parent: PathBuf) -> Result<Self, Error> {
+        Ok(SpillRuns {dir: SpillDir::new(parent)?, buffer: Vec::new(), runs: Vec::new()})
+    }
+
+    pub fn extend(&mut self, groups: impl ParallelIterator<Item=Spilled>) -> Result<(), Error> {
+        self.buffer.par_extend(groups);
+        if self.buffer.len() >= SPILL_RUN_ENTRIES {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.buffer.par_sort_unstable();
+        let run_path = self.dir.0.join(format!("run_{:05}.bin", self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&run_path).with_context(|| format!("Failed to create {:?}", run_path))?);
+        for &(val_doc_id, path_id, line_num, in_doc_pos) in &self.buffer {
+            for value in [val_doc_id as u64, path_id as u64, line_num as u64, in_doc_pos] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        self.runs.push(run_path);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    pub fn merge(mut self) -> Result<RunMerger, Error> {
+        // Spills whatever is left, then k-way merges every run
+        self.spill()?;
+        info!("Merging {:?} sorted runs from {:?}", self.runs.len(), self.dir.0);
+        let mut readers: Vec<BufReader<File>> = self.runs.iter()
+            .map(|run| Ok(BufReader::new(File::open(run)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut heap = BinaryHeap::new();
+        for (run, reader) in readers.iter_mut().enumerate() {
+            if let Some(group) = read_spilled(reader)? {
+                heap.push(Reverse((group, run)));
+            }
+        }
+        Ok(RunMerger {readers, heap, _dir: self.dir})
+    }
+}
+
+fn read_spilled(reader: &mut BufReader<File>) -> Result<Option<Spilled>, Error> {
+    let mut buf = [0u8; 32];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => {},
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into())
+    }
+    let field = |i: usize| u64::from_le_bytes(buf[i * 8..(i + 1) * 8].try_into().unwrap());
+    Ok(Some((field(0) as usize, field(1) as usize, field(2) as usize, field(3))))
+}
+
+pub struct RunMerger {
+    readers: Vec<BufReader<File>>,
+    heap: BinaryHeap<Reverse<(Spilled, usize)>>,
+    // Dropped after the readers, taking the runs with it
+    _dir: SpillDir
+}
+
+impl Iterator for RunMerger {
+    // Groups in sorted order over all runs
+    type Item = Result<Spilled, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((group, run)) = self.heap.pop()?;
+        match read_spilled(&mut self.readers[run]) {
+            Ok(Some(next)) => self.heap.push(Reverse((next, run))),
+            Ok(None) => {},
+            Err(err) => return Some(Err(err))
+        }
+        Some(Ok(group))
+    }
+}
+
+
+pub struct DocScore {
+    // One val doc's contaminates, how many of its train lines --max-group-starts cut short, how many distinct body
+    // intervals --ignore-phrases left out (however many train lines' merges they turned up in), and the highest
+    // fraction any train line reached (of those that got past the fast reject, which is all of them with score_all)
+    pub contaminates: Vec<Contaminate>,
+    pub capped_groups: usize,
+    pub ignored_intervals: usize,
+    pub max_fraction: f64
+}
+
+
+pub fn merge_matches(val_doc_id: usize, doc_matches: &DocMatches, window: &WindowSpec,
+                 doc_text: &[u8], val_doc_size: usize, header_len: usize, threshold: &ContaminationThreshold
+                 ) -> Result<DocScore, Error> {
+    // Groups into a vec of (val_doc_id, trainset_path_id, line_num, coverage)
+    // For any trainset docs that surpass the threshold
+    let mut output = DocScore {contaminates: Vec::new(), capped_groups: 0, ignored_intervals: 0, max_fraction: 0.0};
+    let mut ignored: HashSet<(usize, usize)> = HashSet::new();
+    // Only ngram-fraction needs the doc's distinct n-gram count, and it's the same for every trainset doc
+    let body_ngrams = match threshold.metric {
+        MatchMetric::Coverage => 0,
+        MatchMetric::NgramFraction => {
+            let body_text = doc_text.get(header_len..).unwrap_or(&[]);
+            _distinct_ngrams(window.window_starts(body_text).into_iter(), window, body_text).len()
+        }
+    };
+
+    doc_matches.iter()
+        .for_each(|&((train_path_id, line_num), starts)| {
+            let score = _score_group(starts, window, doc_text, val_doc_size, header_len, body_ngrams, threshold);
+            output.capped_groups += score.capped as usize;
+            ignored.extend(score.ignored);
+            output.max_fraction = score.fraction.map_or(output.max_fraction, |fraction| output.max_fraction.max(fraction));
+            if let Some(coverage) = score.fraction.filter(|_| score.passes) {
+                output.contaminates.push((val_doc_id, train_path_id, line_num, coverage as f32))
+            }
+        });
+    output.ignored_intervals = ignored.len();
+
+    Ok(output)
+}
+
+
+#[derive(Clone, Debug)]
+pub struct ContaminationThreshold {
+    pub fraction: Option<f64>,
+    pub min_overlap_bytes: Option<usize>,
+    pub min_distinct_windows: Option<usize>,
+    pub mode: ThresholdMode,
+    pub metric: MatchMetric,
+    pub gap_tolerance: usize,
+    pub min_span: Option<usize>,
+    pub adjacency: MergeAdjacency,
+    // (min_len, fraction) buckets sorted by min_len, see --threshold-by-length
+    pub by_length: Vec<(usize, f64)>,
+    pub ignore_phrases: Option<Arc<IgnorePhrases>>,
+    // --max-group-starts
+    pub max_starts: Option<usize>,
+    // --full-verdict wants every train line's fraction, passing or not, so nothing is fast rejected
+    pub score_all: bool
+}
+
+impl ContaminationThreshold {
+    pub fn fraction_for(&self, body_size: usize) -> Option<f64> {
+        // The last bucket starting at or below body_size wins, so buckets are half-open on the right
+        match self.by_length.partition_point(|(min_len, _)| *min_len <= body_size) {
+            0 => self.fraction,
+            idx => Some(self.by_length[idx - 1].1)
+        }
+    }
+
+    pub fn passes(&self, hits: usize, total: usize, covered: usize, windows: usize, body_size: usize) -> bool {
+        // Each check that's set up is run: the fraction (hits out of total, per the metric), covered bytes, and
+        // distinct windows. At least one always is, since --threshold can only be left out for --min-distinct-windows
+        let mut checks = [
+            self.fraction_for(body_size).map(|fraction| hits >= ((total as f64) * fraction).ceil() as usize),
+            self.min_overlap_bytes.map(|min_bytes| covered >= min_bytes),
+            self.min_distinct_windows.map(|min_windows| windows >= min_windows)
+        ].into_iter().flatten();
+        match self.mode {
+            ThresholdMode::And => checks.all(|ok| ok),
+            ThresholdMode::Or => checks.any(|ok| ok)
+        }
+    }
+}
+
+
+fn warn_on_loose_threshold(threshold: &ContaminationThreshold, window: &WindowSpec, size_object: &[u64], header_lens: &[u64]) {
+    // Preflight from doc sizes alone: how many val docs a single window's match would already flag, and how many
+    // no train line could flag even by covering all of them. No text is read, so char windows count as one byte a
+    // char, and a body's distinct n-grams as every window it holds
+    let num_docs = size_object.len().saturating_sub(1);
+    let (mut trivial, mut unreachable, mut nonempty) = (0, 0, 0);
+    for doc_id in 0..num_docs {
+        let body_size = (size_object[doc_id + 1] - size_object[doc_id]).saturating_sub(header_lens[doc_id]) as usize;
+        if body_size == 0 {
+            continue;
+        }
+        nonempty += 1;
+        let body_windows = match window {
+            WindowSpec::Chars(num_chars) => (body_size + 1).saturating_sub(*num_chars),
+            _ => window.num_windows(body_size, &[])
+        };
+        let one_window = cmp::min(window.min_width(), body_size);
+        let (one_hit, full_hits, total) = match threshold.metric {
+            MatchMetric::Coverage => (one_window, body_size, body_size),
+            MatchMetric::NgramFraction => (1, body_windows, body_windows)
+        };
+        trivial += threshold.passes(one_hit, total, one_window, 1, body_size) as usize;
+        unreachable += !threshold.passes(full_hits, total, body_size, body_windows, body_size) as usize;
+    }
+    let share = |count: usize| count as f64 / cmp::max(nonempty, 1) as f64;
+    if share(trivial) > 0.5 {
+        warn!("A single {}-byte match would flag {:.0}% of val docs, so nearly any shared window counts as contamination. \
+               Is the threshold too loose for this --match-size?", window.min_width(), 100.0 * share(trivial));
+    }
+    if share(unreachable) > 0.5 {
+        warn!("{:.0}% of val docs can't be flagged even by a train line matching all of them. Is the threshold too strict?",
+              100.0 * share(unreachable));
+    }
+}
+
+
+fn load_length_thresholds(path: &PathBuf) -> Result<Vec<(usize, f64)>, Error> {
+    let buckets: Value = serde_json::from_reader(read_pathbuf_to_mem(path)?)?;
+    let mut by_length: Vec<(usize, f64)> = buckets.as_array()
+        .ok_or_else(|| anyhow!("{:?} should be a list of {{min_len, threshold}} buckets", path))?
+        .iter()
+        .map(|bucket| match (bucket["min_len"].as_u64(), bucket["threshold"].as_f64()) {
+            (Some(min_len), Some(threshold)) => Ok((min_len as usize, threshold)),
+            _ => Err(anyhow!("Bad bucket {} in {:?}, expected {{min_len, threshold}}", bucket, path))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    by_length.sort_by_key(|(min_len, _)| *min_len);
+    if by_length.windows(2).any(|w| w[0].0 == w[1].0) {
+        return Err(anyhow!("Two buckets in {:?} share a min_len", path));
+    }
+    Ok(by_length)
+}
+
+
+#[derive(Debug)]
+pub struct IgnorePhrases {
+    // --ignore-phrases as SA text bytes, so intervals of doc text can be looked for in them directly
+    phrases: Vec<Vec<u8>>,
+    unit_width: usize
+}
+
+impl IgnorePhrases {
+    fn load(path: &PathBuf, data_file: &PathBuf) -> Result<Self, Error> {
+        let sa_encoder = SaTextEncoder::load(data_file)?;
+        let mut phrases: Vec<Vec<u8>> = Vec::new();
+        for line in read_pathbuf_to_mem(path)?.lines() {
+            let line = line.with_context(|| format!("Failed to read {:?}", path))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            phrases.push(sa_encoder.encode(&line)?);
+        }
+        phrases.sort_unstable();
+        phrases.dedup();
+        Ok(IgnorePhrases {phrases, unit_width: sa_encoder.unit_width()})
+    }
+
+    pub fn covers(&self, text: &[u8]) -> bool {
+        // Whether text is some phrase or lies inside one at a unit boundary. A match running on over the space
+        // after a phrase still counts as the phrase, so byte text is trimmed first
+        let text = match self.unit_width {
+            1 => text.trim_ascii(),
+            _ => text
+        };
+        !text.is_empty() && self.phrases.iter()
+            .filter(|phrase| phrase.len() >= text.len())
+            .any(|phrase| phrase.windows(text.len()).step_by(self.unit_width).any(|w| w == text))
+    }
+}
+
+
+pub fn _distinct_ngrams<'a>(starts: impl Iterator<Item=usize>, window: &WindowSpec, text: &'a [u8]) -> HashSet<&'a [u8]> {
+    starts.map(|start| window.interval(start, text))
+        .map(|(s, e)| &text[s..cmp::min(e, text.len())])
+        .collect()
+}
+
+
+pub fn _body_coverage<'a>(interval_starts: impl Iterator<Item=&'a u64>, starts_sorted: bool, window: &WindowSpec,
+                          doc_text: &[u8], header_len: usize, gap_tolerance: usize, adjacency: MergeAdjacency) -> usize {
+    // Bytes of the body (everything past the first header_len bytes) covered by window-sized matches at interval_starts,
+    // counting gaps of up to gap_tolerance bytes between them as covered
+    _body_intervals(interval_starts, starts_sorted, window, doc_text, header_len, gap_tolerance, adjacency).iter()
+        .map(|(s, e)| e - s)
+        .sum()
+}
+
+
+pub fn _body_intervals<'a>(interval_starts: impl Iterator<Item=&'a u64>, starts_sorted: bool, window: &WindowSpec,
+                           doc_text: &[u8], header_len: usize, gap_tolerance: usize, adjacency: MergeAdjacency
+                           ) -> Vec<(usize, usize)> {
+    // The merged intervals _body_coverage sums, as body offsets. Every window's interval starts where
+    // the window does, so sorted starts give sorted intervals
+    let intervals: Vec<(usize, usize)> = interval_starts
+        .map(|start| window.interval(*start as usize, doc_text))
+        .map(|(s, e)| (cmp::max(s, header_len), e))
+        .filter(|(s, e)| s < e)
+        .map(|(s, e)| (s - header_len, e - header_len))
+        .collect();
+    _merge_intervals(intervals, starts_sorted, gap_tolerance, adjacency)
+}
+
+
+pub struct GroupScore {
+    // What _score_group made of one train line's matches: the fraction the metric measured (None if the fast reject
+    // ruled it out before merging), whether that passes, whether --max-group-starts cut its starts short, and the
+    // merged body intervals left out as --ignore-phrases
+    pub fraction: Option<f64>,
+    pub passes: bool,
+    pub capped: bool,
+    pub ignored: Vec<(usize, usize)>
+}
+
+
+pub fn _check_threshold(interval_starts: &[u64], window: &WindowSpec, doc_text: &[u8], doc_size: usize,
+                        header_len: usize, body_ngrams: usize, threshold: &ContaminationThreshold) -> Option<f64> {
+    // Checks if the window-sized matches starting at interval_starts cover enough of the body to pass threshold,
+    // where the first header_len bytes of the doc are a header and don't count towards coverage. If so, returns
+    // the fraction the metric measured (covered bytes, or matched n-grams, over the body's).
+    // body_ngrams is the body's distinct n-gram count, only read for ngram-fraction
+    let score = _score_group(interval_starts, window, doc_text, doc_size, header_len, body_ngrams, threshold);
+    score.fraction.filter(|_| score.passes)
+}
+
+
+pub fn _score_group(interval_starts: &[u64], window: &WindowSpec, doc_text: &[u8], doc_size: usize,
+                    header_len: usize, body_ngrams: usize, threshold: &ContaminationThreshold) -> GroupScore {
+    // _check_threshold's scoring, handing back whether the starts were capped too. With threshold.score_all
+    // the fraction is worked out even for groups that can't pass
+    let body_size = doc_size.saturating_sub(header_len);
+    if body_size == 0 {
+        // Any threshold fraction of nothing is 0 bytes, which every match would trivially clear
+        return GroupScore {fraction: None, passes: false, capped: false, ignored: Vec::new()};
+    }
+    // A repeated start maps to the same interval as the first, so repeats only cost time. The group phase
+    // sorts starts, but they're only copied when there's a repeat (or they aren't sorted) to drop
+    let mut starts: Cow<[u64]> = Cow::Borrowed(interval_starts);
+    if !interval_starts.windows(2).all(|w| w[0] < w[1]) {
+        let mut deduped = interval_starts.to_vec();
+        deduped.sort_unstable();
+        deduped.dedup();
+        starts = Cow::Owned(deduped);
+    }
+    // A pathological group keeps only its first max_starts starts, making its coverage a lower bound
+    let capped = threshold.max_starts.is_some_and(|max_starts| starts.len() > max_starts);
+    if let Some(max_starts) = threshold.max_starts.filter(|_| capped) {
+        starts = Cow::Owned(starts[..max_starts].to_vec());
+    }
+    let interval_starts: &[u64] = &starts;
+    // Fast reject: n windows cover at most n widths plus the gaps between them that gap_tolerance fills (not
+    // capped at body_size, since a window near the end can run on into the next doc), and can't hit more than
+    // n distinct n-grams. If even that misses the threshold, skip the merge
+    let num_starts = interval_starts.len();
+    let covered_bound = num_starts * window.max_width() + num_starts.saturating_sub(1) * threshold.gap_tolerance;
+    let (hits_bound, total) = match threshold.metric {
+        MatchMetric::Coverage => (covered_bound, body_size),
+        MatchMetric::NgramFraction => (interval_starts.iter().filter(|s| **s as usize >= header_len).count(), body_ngrams)
+    };
+    // Starts are distinct by now. Ones inside the header don't count
+    let distinct_windows = interval_starts.iter().filter(|s| **s as usize >= header_len).count();
+    if !threshold.score_all && !threshold.passes(hits_bound, total, covered_bound, distinct_windows, body_size) {
+        return GroupScore {fraction: None, passes: false, capped, ignored: Vec::new()};
+    }
+    // The group phase sorts each train line's starts. Short spans and ignored phrases drop out of the sum here,
+    // after merging, so neither goes uncounted when what's around it matched too
+    let mut ignored: Vec<(usize, usize)> = Vec::new();
+    let total_width = match (&threshold.ignore_phrases, threshold.min_span) {
+        (None, None) => _body_coverage(interval_starts.iter(), true, window, doc_text, header_len, threshold.gap_tolerance,
+                                       threshold.adjacency),
+        (ignore_phrases, min_span) => {
+            let intervals = _body_intervals(interval_starts.iter(), true, window, doc_text, header_len,
+                                            threshold.gap_tolerance, threshold.adjacency);
+            let (left_out, kept): (Vec<_>, Vec<_>) = intervals.into_iter()
+                .filter(|(s, e)| min_span.is_none_or(|min_span| e - s >= min_span))
+                .partition(|(s, e)| ignore_phrases.as_ref().is_some_and(|ignore_phrases|
+                    ignore_phrases.covers(&doc_text[header_len + s..cmp::min(header_len + e, doc_text.len())])));
+            ignored = left_out;
+            kept.iter().map(|(s, e)| e - s).sum()
+        }
+    };
+    let (hits, total) = match threshold.metric {
+        MatchMetric::Coverage => (total_width, body_size),
+        MatchMetric::NgramFraction => {
+            // Windows starting inside the header don't count, same as their bytes don't for coverage
+            let body_text = doc_text.get(header_len..).unwrap_or(&[]);
+            let body_starts = interval_starts.iter()
+                .filter(|s| **s as usize >= header_len)
+                .map(|s| *s as usize - header_len);
+            (_distinct_ngrams(body_starts, window, body_text).len(), body_ngrams)
+        }
+    };
+    GroupScore {fraction: Some(hits as f64 / cmp::max(total, 1) as f64),
+                passes: threshold.passes(hits, total, total_width, distinct_windows, body_size), capped, ignored}
+}
+
+
+pub fn _merge_intervals(mut v: Vec<(usize, usize)>, already_sorted: bool, gap_tolerance: usize,
+                        adjacency: MergeAdjacency) -> Vec<(usize, usize)>{    
+    // Overlapping intervals become one, and so do ones that touch (or, with a gap_tolerance, are at most that far
+    // apart) when adjacency is Touching. Overlapping only allows gaps under gap_tolerance
+    if !already_sorted {
+        v.sort_by_key(|(key, _)| key.clone());
+    }
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (s, e) in v {
+        if merged.len() == 0 {
+            merged.push((s, e));
+        } else if match adjacency {
+            MergeAdjacency::Touching => merged.last().unwrap().1 + gap_tolerance >= s,
+            MergeAdjacency::Overlapping => merged.last().unwrap().1 + gap_tolerance > s
+        } {
+            let (old_s, old_e) = merged.pop().unwrap();
+            merged.push((old_s, cmp::max(e, old_e)));
+        } else {
+            merged.push((s, e));
+        }
+    }
+    merged
+}
+
+fn f32_json(value: f32) -> Value {
+    // Widening to f64 as json! does would print digits the f32 never had (0.95 as 0.949999988079071)
+    json!(value.to_string().parse::<f64>().unwrap_or(f64::NAN))
+}
+
+
+fn contaminates_to_bincode(contaminates: &[Contaminate], run_id: Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut output_bytes: Vec<u8> = CONTAMINATES_MAGIC.to_vec();
+    output_bytes.extend(CONTAMINATES_VERSION.to_le_bytes());
+    let run_id = run_id.unwrap_or_default().as_bytes();
+    output_bytes.extend((run_id.len() as u32).to_le_bytes());
+    output_bytes.extend(run_id);
+    output_bytes.extend(bincode::serialize(contaminates)?);
+    Ok(output_bytes)
+}
+
+
+fn contaminates_to_jsonl(contaminates: &[Contaminate], paths_file: &PathBuf, doc_names: &Option<Vec<String>>
+                         ) -> Result<Vec<u8>, Error> {
+    let path_lookup = load_path_lookup(paths_file)?;
+    let mut output_bytes: Vec<u8> = Vec::new();
+    for (val_doc_id, train_path_id, line_num, coverage) in contaminates {
+        let mut record = json!({"val_doc_id": val_doc_id,
+                                "train_path_id": train_path_id,
+                                "line_num": line_num,
+                                "train_path": path_lookup[*train_path_id],
+                                "coverage": f32_json(*coverage)});
+        add_doc_name(&mut record, doc_names, *val_doc_id);
+        output_bytes.extend(serde_json::to_vec(&record)?);
+        output_bytes.push(b'\n');
+    }
+    Ok(output_bytes)
+}
+
+
+fn load_contaminates(contaminates_file: &PathBuf) -> Result<Vec<Contaminate>, Error> {
+    Ok(load_contaminates_with_run(contaminates_file)?.0)
+}
+
+
+fn load_contaminates_with_run(contaminates_file: &PathBuf) -> Result<(Vec<Contaminate>, Option<String>), Error> {
+    // Reads contaminates in either output format, keyed off the filename, along with the run_id of the matches
+    // they came from. JSONL ones and ones from before version 3 have none. Ones written before coverage
+    // was recorded come back with a NaN coverage
+    if contaminates_file.to_string_lossy().contains(".jsonl") {
+        let mut contaminates: Vec<Contaminate> = Vec::new();
+        for line in read_pathbuf_streaming(contaminates_file)?.lines() {
+            let record: Value = serde_json::from_str(&line?)?;
+            let field = |name: &str| record[name].as_u64().map(|v| v as usize)
+                .ok_or_else(|| anyhow!("Contaminate record without a {} in {:?}", name, contaminates_file));
+            let coverage = record["coverage"].as_f64().map_or(f32::NAN, |c| c as f32);
+            contaminates.push((field("val_doc_id")?, field("train_path_id")?, field("line_num")?, coverage));
+        }
+        Ok((contaminates, None))
+    } else {
+        let bytes = read_file_bytes(contaminates_file)?;
+        if bytes.len() < 8 || &bytes[..4] != CONTAMINATES_MAGIC {
+            let legacy: Vec<(usize, usize, usize)> = bincode::deserialize(&bytes)?;
+            return Ok((legacy.into_iter().map(|(v, p, l)| (v, p, l, f32::NAN)).collect(), None));
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        match version {
+            2 => Ok((bincode::deserialize(&bytes[8..])?, None)),
+            CONTAMINATES_VERSION => {
+                let id_len = bytes.get(8..12)
+                    .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                    .ok_or_else(|| anyhow!("{:?} ends inside its header", contaminates_file))?;
+                let run_id = bytes.get(12..12 + id_len)
+                    .ok_or_else(|| anyhow!("{:?} ends inside its header", contaminates_file))?;
+                let run_id = String::from_utf8(run_id.to_vec())?;
+                Ok((bincode::deserialize(&bytes[12 + id_len..])?, Some(run_id).filter(|id| !id.is_empty())))
+            },
+            _ => Err(anyhow!("{:?} is contaminates version {}, but this build reads versions 2 to {}",
+                             contaminates_file, version, CONTAMINATES_VERSION))
+        }
+    }
+}
+
+
+fn load_contaminated_ids(contaminates_file: &PathBuf) -> Result<HashSet<usize>, Error> {
+    Ok(load_contaminates(contaminates_file)?.into_iter().map(|(val_doc_id, _, _, _)| val_doc_id).collect())
+}
+
+
+fn load_benchmark_ranges(benchmark_map: &PathBuf, data_file_id: usize) -> Result<Vec<(String, usize, usize)>, Error> {
+    // Map is {name -> [start, end)} over val_doc_ids, or {name -> {"data_file_id", "val_docs": [start, end)}}
+    // for one of several val sets. Only the benchmarks in data_file_id's set are kept; plain ranges are set 0's
+    let map: serde_json::Map<String, Value> = serde_json::from_reader(read_pathbuf_to_mem(benchmark_map)?)?;
+    let mut ranges = Vec::new();
+    for (name, entry) in map {
+        let (set, range) = match entry.get("val_docs") {
+            Some(range) => (entry["data_file_id"].as_u64()
+                                .ok_or_else(|| anyhow!("{:?}'s {:?} has val_docs but no data_file_id", benchmark_map, name))?,
+                            range.clone()),
+            None => (0, entry)
+        };
+        let (start, end): (usize, usize) = serde_json::from_value(range)
+            .with_context(|| format!("{:?}'s {:?} isn't a [first_val_doc_id, end_val_doc_id) range", benchmark_map, name))?;
+        if set as usize == data_file_id {
+            ranges.push((name, start, end));
+        }
+    }
+    Ok(ranges)
+}
+
+
+fn build_benchmark_report(contaminates: &[Contaminate], ranges: Vec<(String, usize, usize)>) -> Result<Value, Error> {
+    // For each benchmark we report how many of its items are compromised and how many distinct train lines
+    // contaminate it
+    let mut report = serde_json::Map::new();
+    for (name, start, end) in ranges {
+        let mut items: HashSet<usize> = HashSet::new();
+        let mut train_lines: HashSet<(usize, usize)> = HashSet::new();
+        for (val_doc_id, train_path_id, line_num, _) in contaminates {
+            if *val_doc_id >= start && *val_doc_id < end {
+                items.insert(*val_doc_id);
+                train_lines.insert((*train_path_id, *line_num));
+            }
+        }
+        report.insert(name, json!({"total_items": end - start,
+                                   "compromised_items": items.len(),
+                                   "contaminating_train_lines": train_lines.len()}));
+    }
+    Ok(Value::Object(report))
+}
+
+fn source_contaminates(contaminates: &[Contaminate], sources: &[(String, usize, usize)], tags: &[String]) -> Vec<Vec<Contaminate>> {
+    // The contaminates of val docs in each tag's ranges, in the order they came. Contaminates are sorted by
+    // val_doc_id, so one pass over them alongside the (disjoint) ranges sorted by first doc hands each to its tag
+    debug_assert!(contaminates.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    let mut ranges: Vec<(usize, usize, usize)> = sources.iter()
+        .filter_map(|(t, first_doc, end_doc)| tags.iter().position(|tag| tag == t).map(|tag| (*first_doc, *end_doc, tag)))
+        .collect();
+    ranges.sort_unstable();
+    let mut by_tag: Vec<Vec<Contaminate>> = vec![Vec::new(); tags.len()];
+    let mut ranges = ranges.into_iter().peekable();
+    for contaminate in contaminates {
+        while ranges.next_if(|(_, end_doc, _)| *end_doc <= contaminate.0).is_some() {}
+        if let Some((_, _, tag)) = ranges.peek().filter(|(first_doc, ..)| *first_doc <= contaminate.0) {
+            by_tag[*tag].push(*contaminate);
+        }
+    }
+    by_tag
+}
+
+
+fn build_source_report(by_tag: &[Vec<Contaminate>], sources: &[(String, usize, usize)], tags: &[String]) -> Value {
+    // Per BuildSa --source-tag, how many of its docs are contaminated, like --stats-json does for the whole val set.
+    // by_tag is source_contaminates' split
+    let mut report = serde_json::Map::new();
+    for (tag, tag_contaminates) in tags.iter().zip(by_tag) {
+        let num_docs: usize = sources.iter().filter(|(t, ..)| t == tag).map(|(_, first_doc, end_doc)| end_doc - first_doc).sum();
+        let contaminated_docs = tag_contaminates.iter().map(|(val_doc_id, ..)| val_doc_id).collect::<HashSet<_>>().len();
+        report.insert(tag.clone(), json!({"val_docs": num_docs,
+                                          "contaminated_docs": contaminated_docs,
+                                          "contaminates": tag_contaminates.len(),
+                                          "contamination_rate": contaminated_docs as f64 / cmp::max(num_docs, 1) as f64}));
+    }
+    Value::Object(report)
+}
+
+
+fn build_train_report(contaminates: &[Contaminate], paths_file: &PathBuf) -> Result<Vec<u8>, Error> {
+    // Groups contaminates by train line, worst offenders first (ties broken by path/line for stable output)
+    let path_lookup = load_path_lookup(paths_file)?;
+    let mut by_train_line: HashMap<(usize, usize), HashSet<usize>> = HashMap::new();
+    for (val_doc_id, train_path_id, line_num, _) in contaminates {
+        by_train_line.entry((*train_path_id, *line_num)).or_default().insert(*val_doc_id);
+    }
+    let mut ranked: Vec<((usize, usize), usize)> = by_train_line.into_iter()
+        .map(|(train_line, val_docs)| (train_line, val_docs.len()))
+        .collect();
+    ranked.sort_unstable_by(|(a_line, a_count), (b_line, b_count)| b_count.cmp(a_count).then(a_line.cmp(b_line)));
+
+    let mut output_bytes: Vec<u8> = Vec::new();
+    for ((train_path_id, line_num), num_val_docs) in ranked {
+        let record = json!({"train_path_id": train_path_id,
+                            "line_num": line_num,
+                            "train_path": path_lookup[train_path_id],
+                            "num_val_docs": num_val_docs});
+        output_bytes.extend(serde_json::to_vec(&record)?);
+        output_bytes.push(b'\n');
+    }
+    Ok(output_bytes)
+}
+
+
+fn build_overlap_report(contaminates: &[Contaminate], doc_names: &Option<Vec<String>>) -> Value {
+    // Per contaminated val doc, the distinct train files (not lines) among its contaminates, plus how many
+    // docs each file count has, as {train_files, val_docs} in increasing train_files (so the counts stay numbers).
+    // Contaminates are sorted by val doc, so each doc's come together
+    let mut docs: Vec<Value> = Vec::new();
+    let mut histogram: BTreeMap<usize, usize> = BTreeMap::new();
+    for doc_contaminates in contaminates.chunk_by(|a, b| a.0 == b.0) {
+        let num_files = doc_contaminates.iter().map(|(_, train_path_id, ..)| train_path_id).collect::<HashSet<_>>().len();
+        *histogram.entry(num_files).or_default() += 1;
+        let mut record = json!({"val_doc_id": doc_contaminates[0].0, "train_files": num_files});
+        add_doc_name(&mut record, doc_names, doc_contaminates[0].0);
+        docs.push(record);
+    }
+    let histogram: Vec<Value> = histogram.into_iter()
+        .map(|(num_files, num_docs)| json!({"train_files": num_files, "val_docs": num_docs}))
+        .collect();
+    json!({"contaminated_docs": docs.len(), "histogram": histogram, "docs": docs})
+}
+
+
+fn build_full_verdict(contaminates: &[Contaminate], doc_verdicts: &HashMap<usize, (f64, usize)>, num_docs: usize,
+                      doc_names: &Option<Vec<String>>) -> Result<Vec<u8>, Error> {
+    // One line per val doc in id order, walking the whole size object rather than just the docs with matches.
+    // Contaminates are sorted by val doc, so whether the next doc is contaminated is a matter of stepping through them
+    let mut verdict_bytes: Vec<u8> = Vec::new();
+    let mut contaminates = contaminates.iter().peekable();
+    for val_doc_id in 0..num_docs {
+        let mut contaminated = false;
+        while contaminates.next_if(|(doc_id, ..)| *doc_id == val_doc_id).is_some() {
+            contaminated = true;
+        }
+        let (max_coverage, num_lines) = doc_verdicts.get(&val_doc_id).copied().unwrap_or((0.0, 0));
+        let mut record = json!({"val_doc_id": val_doc_id, "contaminated": contaminated, "max_coverage": max_coverage as f32,
+                                "n_matching_train_docs": num_lines});
+        add_doc_name(&mut record, doc_names, val_doc_id);
+        verdict_bytes.extend(serde_json::to_vec(&record)?);
+        verdict_bytes.push(b'\n');
+    }
+    Ok(verdict_bytes)
+}
+
+
+struct ValSpans<'a> {
+    // Where a mark_contaminates run's val docs and their grouped matches are, for showing matched spans after scoring
+    match_groups: &'a MatchGroups,
+    window: &'a WindowSpec,
+    sa: &'a SaFiles,
+    size_object: &'a [u64],
+    doc_names: &'a Option<Vec<String>>,
+    adjacency: MergeAdjacency
+}
+
+
+struct TrainLines<'a> {
+    // How to read a contaminating train line back and rebuild the text build_matches windowed
+    paths_file: &'a PathBuf,
+    text_fields: &'a [String],
+    field_separator: &'a str,
+    encoding: TextEncoding,
+    doc_per_file: bool,
+    normalize: &'a [NormalizeStep]
+}
+
+
+const SPOTCHECK_CONTEXT: usize = 50;
+
+fn write_spotcheck(contaminates: &[Contaminate], val_spans: &ValSpans, train_lines: &TrainLines, num_samples: usize,
+                   spotcheck_output: &PathBuf) -> Result<(), Error> {
+    // Samples contaminated pairs (with a fixed seed so reruns pick the same ones) and writes
+    // the longest matched val span alongside the train line region it came from. A --case-insensitive SA
+    // is searched folded but shown as written, which the equal lengths make a matter of reading other text
+    let &ValSpans {match_groups, window, sa, size_object, doc_names, adjacency} = val_spans;
+    let &TrainLines {paths_file, text_fields, field_separator, encoding: train_encoding, doc_per_file, normalize} = train_lines;
+    let path_lookup = load_path_lookup(paths_file)?;
+    let case_insensitive = sa_case_insensitive(&sa.data_file)?;
+    let display_file = display_text_file(sa)?;
+    let mut rng = StdRng::seed_from_u64(1234);
+    let samples: Vec<&Contaminate> = contaminates.choose_multiple(&mut rng, num_samples).collect();
+
+    let mut output_bytes: Vec<u8> = Vec::new();
+    for (val_doc_id, train_path_id, line_num, _) in samples {
+        let val_doc_size = (size_object[*val_doc_id + 1] - size_object[*val_doc_id]) as usize;
+        let starts = match_groups.starts(*val_doc_id, val_doc_size, (*train_path_id, *line_num)).unwrap();
+        let val_text = load_text_range(&sa.text, size_object[*val_doc_id], size_object[*val_doc_id + 1]);
+        let intervals: Vec<(usize, usize)> = starts.iter()
+            .map(|s| window.interval(*s as usize, &val_text))
+            .map(|(s, e)| (s, cmp::min(e, val_doc_size)))
+            .collect();
+        let (span_start, span_end) = _merge_intervals(intervals, false, 0, adjacency).into_iter()
+            .max_by_key(|(s, e)| e - s).unwrap();
+
+        let val_span = &val_text[span_start..span_end];
+        let display_text = match case_insensitive {
+            true => load_text_range(&display_file, size_object[*val_doc_id], size_object[*val_doc_id + 1]),
+            false => val_text.clone()
+        };
+        let val_context = &display_text[span_start.saturating_sub(SPOTCHECK_CONTEXT)..cmp::min(span_end + SPOTCHECK_CONTEXT, val_text.len())];
+
+        let train_path = &path_lookup[*train_path_id];
+        let train_data = read_decoded(train_path, train_encoding)?;
+        let train_line = match doc_per_file {
+            true => std::io::read_to_string(train_data)?,
+            false => train_data.lines().nth(*line_num)
+                .ok_or_else(|| anyhow!("Line {} missing from {:?}", line_num, train_path))??
+        };
+        // As in collect_matches, a BOM opening the file isn't part of line 0
+        let train_line = match *line_num {
+            0 => train_line.strip_prefix('\u{feff}').map(String::from).unwrap_or(train_line),
+            _ => train_line
+        };
+        // No text fields means raw lines, each line its own text
+        // A matched line that's a bare JSON string was read with --json-string-as-text, as its own text
+        let train_fields = match text_fields.is_empty() {
+            true => Cow::Borrowed(train_line.as_str()),
+            false => match serde_json::from_str(&train_line)? {
+                Value::String(text) => Cow::Owned(text),
+                json => extract_fields(&json, text_fields, field_separator)
+                    .map(|text| Cow::Owned(text.into_owned()))
+                    .unwrap_or_default()
+            }
+        };
+        let train_text = normalize_text(&train_fields, normalize);
+        let train_search = fold_case(train_text.clone(), case_insensitive);
+        let train_text = train_text.as_bytes();
+        let train_offset = train_search.as_bytes().windows(val_span.len()).position(|w| w == val_span);
+        let train_context = match train_offset {
+            Some(o) => &train_text[o.saturating_sub(SPOTCHECK_CONTEXT)..cmp::min(o + val_span.len() + SPOTCHECK_CONTEXT, train_text.len())],
+            None => &train_text[..cmp::min(train_text.len(), 2 * SPOTCHECK_CONTEXT + val_span.len())]
+        };
+
+        let mut record = json!({"val_doc_id": val_doc_id,
+                                "train_path": train_path,
+                                "line_num": line_num,
+                                "val_span": [span_start, span_end],
+                                "val_match": String::from_utf8_lossy(&display_text[span_start..span_end]),
+                                "val_context": String::from_utf8_lossy(val_context),
+                                "train_offset": train_offset,
+                                "train_context": String::from_utf8_lossy(train_context)});
+        add_doc_name(&mut record, doc_names, *val_doc_id);
+        output_bytes.extend(serde_json::to_vec(&record)?);
+        output_bytes.push(b'\n');
+    }
+    write_mem_to_pathbuf(&output_bytes, spotcheck_output)
+}
+
+
+fn write_spans(contaminates: &[Contaminate], val_spans: &ValSpans, max_span_chars: usize, spans_output: &PathBuf
+               ) -> Result<(), Error> {
+    // For each contaminated val doc, unions the matches of every train line that pushed it over
+    // the threshold and writes out the text under each merged interval
+    let &ValSpans {match_groups, window, sa, size_object, doc_names, adjacency} = val_spans;
+    let mut train_lines: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for (val_doc_id, train_path_id, line_num, _) in contaminates {
+        train_lines.entry(*val_doc_id).or_default().push((*train_path_id, *line_num));
+    }
+    let mut val_doc_ids: Vec<usize> = train_lines.keys().cloned().collect();
+    val_doc_ids.sort_unstable();
+
+    // Folding only touches ASCII, so the original text has the same char boundaries for window.interval
+    let display_file = display_text_file(sa)?;
+    let mut output_bytes: Vec<u8> = Vec::new();
+    for val_doc_id in val_doc_ids {
+        let val_doc_size = (size_object[val_doc_id + 1] - size_object[val_doc_id]) as usize;
+        let val_text = load_text_range(&display_file, size_object[val_doc_id], size_object[val_doc_id + 1]);
+        let intervals: Vec<(usize, usize)> = train_lines[&val_doc_id].iter()
+            .flat_map(|train_line| match_groups.starts(val_doc_id, val_doc_size, *train_line).unwrap())
+            .map(|s| window.interval(s as usize, &val_text))
+            .map(|(s, e)| (s, cmp::min(e, val_doc_size)))
+            .collect();
+
+        let spans: Vec<Value> = _merge_intervals(intervals, false, 0, adjacency).into_iter()
+            .map(|(start, end)| {
+                let text: String = String::from_utf8_lossy(&val_text[start..end]).chars().take(max_span_chars).collect();
+                json!({"start": start, "end": end, "text": text})
+            })
+            .collect();
+        let mut record = json!({"val_doc_id": val_doc_id, "spans": spans});
+        add_doc_name(&mut record, doc_names, val_doc_id);
+        output_bytes.extend(serde_json::to_vec(&record)?);
+        output_bytes.push(b'\n');
+    }
+    write_mem_to_pathbuf(&output_bytes, spans_output)
+}
+
+/*=================================================================
+=                             Subcommands                         =
+=================================================================*/
+
+
+
+pub fn build_sa(args: &BuildSaArgs) -> Result<(), Error> {
+    let BuildSaArgs {input, output, text_field, field_separator, tokenizer, input_ids_field, raw_lines, encoding,
+                     token_width, prefix_field, prefix_separator, id_field, normalize, case_insensitive, source_tag, shards, match_size} = args;
+    let _span = info_span!("build_sa").entered();
+    info!("Starting SA build...");
+
+    // Phase 1: Gather doc texts. Lines without text become empty docs so doc ids stay aligned with input lines
+    let mut input_files = expand_dirs(input.clone(), raw_lines.then_some(RAW_LINE_EXTS))?;
+    input_files.sort();
+    // Sorting mixes the inputs' files together, so each file keeps the tag of the input it was found under
+    let mut file_tags: HashMap<PathBuf, &str> = HashMap::new();
+    if !source_tag.is_empty() {
+        if source_tag.len() != input.len() {
+            return Err(anyhow!("Got {} --source-tag for {} --input, there should be one per input", source_tag.len(), input.len()));
+        }
+        if let Some(tag) = source_tag.iter().find(|tag| tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))) {
+            return Err(anyhow!("--source-tag {:?} can only hold letters, digits, '-', '_' and '.'", tag));
+        }
+        for (input_path, tag) in input.iter().zip(source_tag) {
+            for file in expand_dirs(vec![input_path.clone()], raw_lines.then_some(RAW_LINE_EXTS))? {
+                if let Some(other) = file_tags.insert(file.clone(), tag).filter(|other| other != tag) {
+                    return Err(anyhow!("{:?} is under inputs tagged both {:?} and {:?}", file, other, tag));
+                }
+            }
+        }
+    }
+    let encoder = match input_ids_field {
+        Some(field) => Some(TokenEncoder::input_ids(field, *token_width)?),
+        None => tokenizer.as_ref().map(TokenEncoder::load).transpose()?
+    };
+    let pbar = build_pbar(input_files.len(), "Files");
+    // Docs come with the length of their --prefix-field header, 0 without one, and their --id-field names.
+    // Each file also counts its lines without text
+    type FileDocs = (Vec<(Vec<u8>, u64)>, Vec<String>, usize);
+    let file_docs: Vec<FileDocs> = input_files.par_iter().map(|path| {
+        let mut docs: Vec<(Vec<u8>, u64)> = Vec::new();
+        let mut names: Vec<String> = Vec::new();
+        let mut missing_text = 0;
+        for (line_num, line) in read_decoded(path, *encoding)?.lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read line {} of {:?}", line_num, path))?;
+            let json: Value = match raw_lines {
+                true => Value::Null,
+                false => serde_json::from_str(&line)
+                    .with_context(|| format!("Malformed JSON on line {} of {:?}", line_num, path))?
+            };
+            let doc = match &encoder {
+                Some(encoder) if encoder.reads_input_ids() => encoder.encode_ids(&json)
+                    .with_context(|| format!("Line {} of {:?}", line_num, path))?,
+                _ => match raw_lines {
+                    true => Some(Cow::Borrowed(line.as_str())),
+                    false => extract_fields(&json, text_field, field_separator)
+                }
+                    .map(|text| match (normalize_text(&text, normalize), &encoder) {
+                        (text, Some(encoder)) => encoder.encode(&text),
+                        (text, None) => Ok(text.into_owned().into_bytes())
+                    })
+                    .transpose()?
+            };
+            let doc = doc.unwrap_or_else(|| {
+                missing_text += 1;
+                Vec::new()
+            });
+            // The header is normalized and encoded by itself, so its length is exactly where the body starts
+            let header = match prefix_field.as_ref().and_then(|field| extract_text(&json, field, field_separator)) {
+                Some(prefix) => {
+                    let header_text = normalize_text(&format!("{}{}", prefix, prefix_separator), normalize).into_owned();
+                    match &encoder {
+                        Some(encoder) => encoder.encode(&header_text)?,
+                        None => header_text.into_bytes()
+                    }
+                },
+                None => Vec::new()
+            };
+            let header_len = header.len() as u64;
+            docs.push(([header, doc].concat(), header_len));
+            if let Some(id_field) = id_field {
+                names.push(doc_name(&json, id_field).unwrap_or_else(|| format!("{}:{}", path.display(), line_num)));
+            }
+        }
+        pbar.inc(1);
+        Ok((docs, names, missing_text))
+    }).collect::<Result<Vec<_>, Error>>()?;
+
+    // Phase 2: Concatenate, recording the cumsum of doc sizes (n + 1 entries, starting at 0)
+    let mut text: Vec<u8> = Vec::new();
+    let mut sizes: Vec<u64> = vec![0];
+    let mut header_lens: Vec<u64> = Vec::new();
+    let mut doc_names: Vec<String> = Vec::new();
+    // (tag, first_doc, end_doc) runs of consecutive docs from one --source-tag
+    let mut sources: Vec<(&str, usize, usize)> = Vec::new();
+    let mut missing_text = 0;
+    for (path, (docs, names, file_missing)) in input_files.iter().zip(file_docs) {
+        let first_doc = sizes.len() - 1;
+        for (doc, header_len) in docs {
+            text.extend(doc);
+            sizes.push(text.len() as u64);
+            header_lens.push(header_len);
+        }
+        doc_names.extend(names);
+        missing_text += file_missing;
+        if let Some(tag) = file_tags.get(path) {
+            match sources.last_mut() {
+                Some(last) if last.0 == *tag && last.2 == first_doc => last.2 = sizes.len() - 1,
+                _ => sources.push((tag, first_doc, sizes.len() - 1))
+            }
+        }
+    }
+    // Pre-tokenized docs come from one field, not the text fields, and raw lines from none
+    let source_fields = match (input_ids_field, raw_lines) {
+        (Some(field), _) => vec![field.clone()],
+        (None, true) => Vec::new(),
+        (None, false) => text_field.clone()
+    };
+    if text.is_empty() {
+        return Err(anyhow!("No text found in {:?} under fields {:?}", input, source_fields));
+    }
+    info!("Collected {:?} docs ({:?} bytes)", sizes.len() - 1, text.len());
+    if *case_insensitive {
+        // The table is over the folded text. The original, at the same offsets, is kept for display
+        write_mem_to_pathbuf(&text, &original_text_path(output))?;
+        info!("Wrote original text to {:?}", original_text_path(output));
+        text.make_ascii_lowercase();
+    }
+
+    // Phase 3: Build and write the table, or one per shard. A pre-tokenized SA records no text fields,
+    // since train lines aren't assembled from any
+    let mut sa_meta = match (input_ids_field, raw_lines) {
+        (Some(_), _) => json!({}),
+        (None, true) => json!({"raw_lines": true}),
+        (None, false) => fields_meta(text_field, field_separator)
+    };
+    sa_meta["version"] = json!(env!("CARGO_PKG_VERSION"));
+    sa_meta["input"] = json!(input);
+    if let Some(match_size) = match_size {
+        sa_meta["match_size"] = json!(match_size);
+    }
+    sa_meta["normalize"] = normalization_meta(normalize);
+    sa_meta["tokenization"] = tokenization_meta(&encoder, false);
+    if *case_insensitive {
+        sa_meta["case_insensitive"] = json!(true);
+    }
+    if !sources.is_empty() {
+        sa_meta["sources"] = json!(sources.iter()
+            .map(|(tag, first_doc, end_doc)| json!({"tag": tag, "first_doc": first_doc, "end_doc": end_doc}))
+            .collect::<Vec<_>>());
+    }
+    if *encoding != TextEncoding::Utf8 {
+        sa_meta["encoding"] = json!(encoding.name());
+    }
+    // Windows longer than this can only match some docs by running across a doc boundary
+    sa_meta["min_doc_bytes"] = json!(sizes.windows(2).map(|w| w[1] - w[0]).filter(|len| *len > 0).min());
+    if let Some(prefix_field) = prefix_field {
+        sa_meta["prefix_field"] = json!(prefix_field);
+        sa_meta["prefix_separator"] = json!(prefix_separator);
+        let offset_bytes: Vec<u8> = header_lens.iter().flat_map(|len| len.to_ne_bytes()).collect();
+        std::fs::write(body_offsets_path(output), offset_bytes)
+            .with_context(|| format!("Failed to write {:?}", body_offsets_path(output)))?;
+        info!("Wrote {:?} prefix lengths to {:?}", header_lens.len(), body_offsets_path(output));
+    }
+    if let Some(id_field) = id_field {
+        sa_meta["id_field"] = json!(id_field);
+        write_mem_to_pathbuf(&serde_json::to_vec(&doc_names)?, &doc_names_path(output))?;
+        info!("Wrote {:?} doc names to {:?}", doc_names.len(), doc_names_path(output));
+    }
+    if *shards <= 1 {
+        let size_width = write_sa(output, &text, &sizes, &sa_meta)?;
+        info!("Wrote {:?} docs with size_width {:?} to {:?}", sizes.len() - 1, size_width, output);
+    } else {
+        write_sa_text(output, &text, &sizes, &sa_meta)?;
+        // Each shard ends at the first doc boundary at or past its even share of the text. The last one
+        // always takes every remaining doc, so trailing empty docs still belong to a shard
+        let mut bounds: Vec<usize> = (0..*shards)
+            .map(|shard| sizes.partition_point(|&size| size < (text.len() * shard / shards) as u64))
+            .collect();
+        bounds.push(sizes.len() - 1);
+        bounds.dedup();
+        let mut shard_entries: Vec<Value> = Vec::new();
+        for (shard, doc_range) in bounds.windows(2).enumerate() {
+            let (start, end) = (sizes[doc_range[0]], sizes[doc_range[1]]);
+            let shard_file = PathBuf::from(format!("{}.shard_{:02}", output.display(), shard));
+            let shard_sizes: Vec<u64> = sizes[doc_range[0]..=doc_range[1]].iter().map(|size| size - start).collect();
+            let size_width = write_sa(&shard_file, &text[start as usize..end as usize], &shard_sizes, &sa_meta)?;
+            info!("Wrote shard {:?}: docs {:?}..{:?} with size_width {:?}", shard_file, doc_range[0], doc_range[1], size_width);
+            shard_entries.push(json!({"data_file": shard_file.file_name().unwrap().to_string_lossy(),
+                                      "start": start, "end": end,
+                                      "first_doc": doc_range[0], "num_docs": doc_range[1] - doc_range[0]}));
+        }
+        let manifest = json!({"version": 1, "size_text": text.len(), "shards": shard_entries});
+        std::fs::write(shards_path(output), serde_json::to_vec(&manifest)?)
+            .with_context(|| format!("Failed to write {:?}", shards_path(output)))?;
+        info!("Wrote {:?} docs in {:?} shards to {:?}", sizes.len() - 1, shard_entries.len(), output);
+    }
+    info!("Found {:?} lines with nothing at any of {:?}", missing_text, source_fields);
+    Ok(())
+}
+
+
+// How many trainset files a --dry-run collects matches from
+const DRY_RUN_SAMPLES: usize = 4;
+
+fn estimate_build_matches(input_files: &[PathBuf], sas: &[LoadedSa], params: &MatchParams, match_file: &Path,
+                          legacy_format: bool, compression: &CompressionArgs) -> Result<(), Error> {
+    // Scales what a few evenly spaced sample files produce up to the whole trainset. Local files are
+    // sized from their metadata, s3 ones aren't sized at all and are projected per file instead
+    let sizes: Vec<Option<u64>> = input_files.iter().map(|p| std::fs::metadata(p).ok().map(|m| m.len())).collect();
+    let total_bytes: u64 = sizes.iter().flatten().sum();
+    let num_unsized = sizes.iter().filter(|size| size.is_none()).count();
+    info!("Dry run over {:?} trainset files, {:?} bytes on disk", input_files.len(), total_bytes);
+    if num_unsized > 0 {
+        warn!("{:?} trainset files (s3) have no local size, so they're projected from the average sample file", num_unsized);
+    }
+    if input_files.is_empty() {
+        return Ok(());
+    }
+    let num_samples = cmp::min(DRY_RUN_SAMPLES, input_files.len());
+    let samples: Vec<usize> = (0..num_samples).map(|i| i * input_files.len() / num_samples).collect();
+    // Each sample's bytes on disk, bytes once decompressed, and deduped matches
+    type SampleResult = (u64, u64, Vec<(usize, usize, u64)>);
+    let sample_results: Vec<SampleResult> = samples.par_iter()
+        .map(|&idx| {
+            // Read (and decompressed) once, both to measure it and to collect its matches
+            let bytes = read_file_bytes(&input_files[idx])?;
+            let read_bytes = bytes.len() as u64;
+            let (sa_matches, _, _) = collect_matches_from(Box::new(Cursor::new(bytes)), &input_files[idx], idx, sas, params)?;
+            let mut path_matches: Vec<(usize, usize, u64)> = Vec::new();
+            for mut set_matches in sa_matches {
+                set_matches.sort_unstable();
+                set_matches.dedup();
+                path_matches.extend(set_matches);
+            }
+            Ok((sizes[idx].unwrap_or(read_bytes), read_bytes, path_matches))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let sample_bytes: u64 = sample_results.iter().map(|(disk, _, _)| disk).sum();
+    let sample_read_bytes: u64 = sample_results.iter().map(|(_, read, _)| read).sum();
+    let sample_matches: Vec<(usize, usize, u64)> = sample_results.into_iter().flat_map(|(_, _, m)| m).collect();
+    let per_byte = sample_matches.len() as f64 / cmp::max(sample_bytes, 1) as f64;
+    let per_file = sample_matches.len() as f64 / num_samples as f64;
+    let projected_matches = per_byte * total_bytes as f64 + per_file * num_unsized as f64;
+    // Output size goes by how well the sample's own matches compress
+    let sample_output = compress_data(serialize_matches(&sample_matches, legacy_format)?, match_file, compression.level)?;
+    let bytes_per_match = sample_output.len() as f64 / cmp::max(sample_matches.len(), 1) as f64;
+    info!("Sampled {:?} files: {:?} bytes on disk, {:?} decompressed, {:?} matches ({:.1} per MB on disk)",
+          num_samples, sample_bytes, sample_read_bytes, sample_matches.len(), per_byte * 1e6);
+    info!("Projected trainset: about {:.0} bytes decompressed",
+          total_bytes as f64 * sample_read_bytes as f64 / cmp::max(sample_bytes, 1) as f64);
+    info!("Projected output: about {:.0} matches in about {:.0} bytes of {:?}", projected_matches,
+          projected_matches * bytes_per_match, match_file.file_name().unwrap_or_default());
+    Ok(())
+}
+
+
+pub fn build_matches(args: &BuildMatchesArgs, cancel: &AtomicBool) -> Result<(), Error> {
+    // Once cancel is set (the binary sets it on Ctrl-C), trainset files already underway finish but no new ones
+    // start, and whatever was collected is written out as a partial run
+    let &BuildMatchesArgs {ref data_file, ref trainset, ref exclude, ref ext, ref output, ref prefix, ref match_size, ref text_field, ref field_separator,
+                           ref tokenizer, ref input_ids_field, raw_lines, doc_per_file, json_string_as_text, encoding, token_width,
+                           char_windows, stride, sample_rate,
+                           skip_errors, mmap, skip_if_done, stream, resume, file_chunk, max_occurrences, bloom,
+                           bloom_bits, bloom_hashes, min_doc_len, max_matches_per_doc, max_line_bytes, long_lines,
+                           self_match, train_offsets, ref normalize, case_insensitive, dry_run, progress_bytes,
+                           ref occurrence_histogram, s3_concurrency, legacy_match_format, ref profile_json, ref sa_paths, compression} = args;
+    let match_size = resolve_sa_match_size(data_file, *match_size)?;
+    // A single data_file is recorded as before, so existing manifests and progress files still line up
+    let data_file_param = match data_file.len() {
+        1 => json!(data_file[0]),
+        _ => json!(data_file)
+    };
+    let manifest_params = json!({"data_file": data_file_param, "trainset": trainset, "exclude": exclude, "ext": ext, "match_size": match_size,
+                                 "text_field": text_field, "field_separator": field_separator, "raw_lines": raw_lines, "doc_per_file": doc_per_file,
+                                 "json_string_as_text": json_string_as_text,
+                                 "encoding": encoding.name(),
+                                 "tokenizer": tokenizer, "char_windows": char_windows,
+                                 "input_ids_field": input_ids_field, "token_width": input_ids_field.as_ref().map(|_| token_width),
+                                 "stride": stride, "sample_rate": sample_rate,
+                                 "stream": stream, "max_occurrences": max_occurrences, "min_doc_len": min_doc_len,
+                                 "max_line_bytes": max_line_bytes, "long_lines": max_line_bytes.map(|_| format!("{:?}", long_lines)),
+                                 "max_matches_per_doc": max_matches_per_doc,
+                                 "self_match": self_match, "train_offsets": train_offsets, "normalize": normalization_meta(normalize),
+                                 "case_insensitive": case_insensitive, "legacy_match_format": legacy_match_format,
+                                 "sa_paths": sa_paths.manifest(),
+                                 "compression": format!("{:?}", compression.compression)});
+    if stride == 0 {
+        return Err(anyhow!("--stride must be at least 1"));
+    }
+    if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+        return Err(anyhow!("--sample-rate must be in (0, 1], got {}", sample_rate));
+    }
+    if file_chunk == 0 {
+        return Err(anyhow!("--file-chunk must be at least 1"));
+    }
+    check_prefix(prefix)?;
+    let exclude_patterns: Vec<glob::Pattern> = exclude.iter()
+        .map(|pattern| glob::Pattern::new(pattern).with_context(|| format!("Bad --exclude pattern {:?}", pattern)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    // With or without its leading dot, an extension is matched as a suffix of the whole file name
+    let exts: Vec<String> = ext.iter().map(|ext| format!(".{}", ext.trim_start_matches('.'))).collect();
+    if exts.iter().any(|ext| ext == ".") {
+        return Err(anyhow!("--ext can't be empty"));
+    }
+    if max_line_bytes == Some(0) {
+        return Err(anyhow!("--max-line-bytes must be at least 1"));
+    }
+    if max_matches_per_doc == Some(0) {
+        return Err(anyhow!("--max-matches-per-doc must be at least 1"));
+    }
+    if s3_concurrency == Some(0) {
+        return Err(anyhow!("--s3-concurrency must be at least 1"));
+    }
+    if trainset.iter().filter(|p| is_stdin(p)).count() > 1 {
+        return Err(anyhow!("--trainset - can only be given once, stdin can't be read twice"));
+    }
+    if data_file.iter().collect::<HashSet<_>>().len() != data_file.len() {
+        return Err(anyhow!("--data-file was given the same SA more than once"));
+    }
+    if sa_paths.is_set() && data_file.len() > 1 {
+        return Err(anyhow!("--text/--table/--size can only be used with a single --data-file"));
+    }
+    // Overrides only ever name the one SA's files
+    let sa_files: Vec<SaFiles> = data_file.iter().enumerate()
+        .map(|(set, sa_file)| match set {
+            0 => sa_paths.resolve(sa_file),
+            _ => Ok(SaFiles::new(sa_file))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    if stream && is_s3(output) {
+        return Err(anyhow!("--stream appends to shard files as it goes, so --output must be local"));
+    }
+    if dry_run && trainset.iter().any(|path| is_stdin(path)) {
+        return Err(anyhow!("--dry-run can't size stdin without consuming it, so it doesn't work with --trainset -"));
+    }
+    if resume && trainset.iter().any(|path| is_stdin(path)) {
+        return Err(anyhow!("--resume can't tell how much of stdin was already read, so it doesn't work with --trainset -"));
+    }
+    if self_match && (data_file.len() > 1 || trainset.iter().any(|path| is_stdin(path))) {
+        return Err(anyhow!("--self-match needs the one SA built over the trainset, and a trainset it can read twice (not -)"));
+    }
+    // A resumed run appends to the shards the interrupted one started, whatever the thread count is now
+    let progress_path = output.clone().join(prefixed_name(prefix, "progress.json"));
+    let prior_progress: Option<Value> = match resume && path_exists(&progress_path) {
+        true => {
+            let prior: Value = serde_json::from_reader(read_pathbuf_to_mem(&progress_path)?)?;
+            if prior["params"] != manifest_params {
+                return Err(anyhow!("{:?} is from a run with params {}, not {}. Rerun without --resume to start over",
+                                   progress_path, prior["params"], manifest_params));
+            }
+            Some(prior)
+        },
+        false => None
+    };
+    // One data_file writes its match set straight into output. With several, each gets its own complete
+    // match set (matches, paths and meta) in output/sa_NN, so later steps handle them one at a time as usual.
+    // Set NN's meta tags its matches with data_file_id NN, and match_sets.json lists every set
+    let set_prefixes: Vec<String> = match data_file.len() {
+        1 => vec![String::new()],
+        num_sets => (0..num_sets).map(|set| format!("sa_{:02}/", set)).collect()
+    };
+    // Match files are named relative to output and grouped by set, num_set_shards to a set
+    let match_names: Vec<String> = match (stream, &prior_progress) {
+        (true, Some(prior)) => prior["shards"].as_object()
+            .ok_or_else(|| anyhow!("{:?} doesn't record its shards", progress_path))?
+            .keys().cloned().collect(),
+        (true, None) => set_prefixes.iter()
+            .flat_map(|set_prefix| (0..rayon::current_num_threads())
+                .map(move |shard| format!("{}{}", set_prefix, prefixed_name(prefix, &match_shard_name(shard, &compression)))))
+            .collect(),
+        (false, _) => set_prefixes.iter()
+            .map(|set_prefix| format!("{}{}", set_prefix, prefixed_name(prefix, &compression.name("matches.bin"))))
+            .collect()
+    };
+    let num_set_shards = match_names.len() / set_prefixes.len();
+    // The meta records sidecar names relative to its set's dir, so they carry --prefix too
+    let paths_name = prefixed_name(prefix, &compression.name("paths.json"));
+    let meta_name = prefixed_name(prefix, "matches.meta.json");
+    let set_outputs: Vec<(String, String)> = set_prefixes.iter()
+        .map(|set_prefix| (format!("{}{}", set_prefix, paths_name), format!("{}{}", set_prefix, meta_name)))
+        .collect();
+    let sets_name = prefixed_name(prefix, "match_sets.json");
+    let offsets_name = prefixed_name(prefix, &compression.name("train_offsets.bin"));
+    let offset_names: Vec<String> = match train_offsets {
+        true => set_prefixes.iter().map(|set_prefix| format!("{}{}", set_prefix, offsets_name)).collect(),
+        false => Vec::new()
+    };
+    let mut expected_outputs: Vec<&str> = set_outputs.iter().map(|(paths, _)| paths.as_str()).collect();
+    expected_outputs.extend(match_names.iter().map(|n| n.as_str()));
+    expected_outputs.extend(offset_names.iter().map(|n| n.as_str()));
+    expected_outputs.extend(set_outputs.iter().map(|(_, meta)| meta.as_str()));
+    if data_file.len() > 1 {
+        expected_outputs.push(sets_name.as_str());
+    }
+    let manifest_name = prefixed_name(prefix, "build_matches.manifest.json");
+    if skip_if_done && run_already_done(output, &manifest_name, &manifest_params, &expected_outputs) {
+        info!("Outputs and manifest already present in {:?}, skipping", output);
+        return Ok(());
+    }
+    let _span = info_span!("build_matches").entered();
+    info!("Starting Match Building run...");
+    // Phase timings are kept for --profile-json. Loading the SAs (and their Bloom filters) is timed apart from
+    // the rest of setup
+    let mut profile = PhaseProfile::new();
+    let phase_start = Instant::now();
+    // Phase 0: Setup, collect filenames, build path lookup, build band seeds
+    let ext_refs: Vec<&str> = exts.iter().map(|ext| ext.as_str()).collect();
+    let list_exts = match (ext_refs.is_empty(), raw_lines, doc_per_file) {
+        (false, _, _) => Some(&ext_refs[..]),
+        (true, true, _) => Some(RAW_LINE_EXTS),
+        (true, false, true) => Some(DOC_FILE_EXTS),
+        (true, false, false) => None
+    };
+    let mut input_files = expand_dirs(trainset.clone(), list_exts)?;
+    if !exclude_patterns.is_empty() {
+        let num_found = input_files.len();
+        input_files.retain(|path| !exclude_patterns.iter().any(|pattern| pattern.matches_path(path)));
+        info!("Excluded {:?} of {:?} input files matching --exclude", num_found - input_files.len(), num_found);
+    }
+    input_files.sort(); // sort before building the path lookup
+    let path_map : BTreeMap<PathBuf, usize> = input_files.iter()
+        .enumerate()
+        .map(|(index, path)| (path.clone(), index))
+        .collect();
+
+    info!("Collected {:?} input files", input_files.len());
+    let encoder: Option<TokenEncoder> = match input_ids_field {
+        Some(field) => Some(TokenEncoder::input_ids(field, token_width)?),
+        None => tokenizer.as_ref().map(TokenEncoder::load).transpose()?
+    };
+    profile.record("setup", phase_start);
+    let phase_start = Instant::now();
+    let mut sas: Vec<LoadedSa> = Vec::new();
+    for (set, sa_file) in data_file.iter().enumerate() {
+        check_sa_version(sa_file)?;
+        let sa_normalization = load_sa_normalization(sa_file)?;
+        if sa_normalization != normalization_meta(normalize) {
+            return Err(anyhow!("{:?} was built with normalization {} but --normalize is {}",
+                               sa_file, sa_normalization, normalization_meta(normalize)));
+        }
+        if sa_case_insensitive(sa_file)? != case_insensitive {
+            return Err(anyhow!("{:?} was built {}with --case-insensitive, so build_matches needs it {}", sa_file,
+                               if case_insensitive { "without " } else { "" }, if case_insensitive { "off" } else { "on" }));
+        }
+        if input_ids_field.is_none() {
+            check_sa_fields(sa_file, text_field, field_separator, raw_lines)?;
+        }
+        check_sa_tokenization(sa_file, &encoder)?;
+        let window_bytes = match_size * encoder.as_ref().map_or(1, |e| e.token_width);
+        if let Some(min_doc_bytes) = load_sa_meta(sa_file)?["min_doc_bytes"].as_u64().filter(|min| (window_bytes as u64) > *min) {
+            warn!("{:?} has docs as short as {} bytes, under the {}-byte windows of --match-size {}. Matches on those \
+                   span doc boundaries, and mark_contaminates drops them", sa_file, min_doc_bytes, window_bytes, match_size);
+        }
+        let shards = load_sa_shards(&sa_files[set], mmap).with_context(|| format!("Failed to load SA at {:?}", sa_file))?;
+        if let Some(encoder) = &encoder {
+            if let Some(shard) = shards.iter().find(|shard| shard.size_text % (encoder.token_width as u64) != 0) {
+                return Err(anyhow!("{:?} is {} bytes, which isn't a whole number of {}-byte tokens. Was it built with this tokenizer?",
+                                   shard.data_file, shard.size_text, encoder.token_width));
+            }
+        }
+        sas.push(LoadedSa {shards: shards.into_iter().map(|shard| (shard, None)).collect()});
+    }
+    let match_params = MatchParams {match_size, text_fields: text_field, field_separator, raw_lines, doc_per_file, json_string_as_text, encoding,
+                                    encoder: &encoder, char_windows, normalize, case_insensitive, stride, sample_rate,
+                                    skip_errors, max_occurrences, min_doc_len, max_matches_per_doc, max_line_bytes, long_lines,
+                                    train_offsets, occurrence_histogram: occurrence_histogram.is_some()};
+    if dry_run {
+        // No Bloom filter here, since building one writes it out. It wouldn't change the matches anyway
+        return estimate_build_matches(&input_files, &sas, &match_params, &output.clone().join(&match_names[0]),
+                                      legacy_match_format, &compression);
+    }
+    // BuildSa made one doc per line of its sorted inputs, so train line l of path p is doc first_doc[p] + l.
+    // That only holds if the line counts add up to the SA's doc count
+    let self_match_meta = match self_match {
+        true => {
+            let line_counts: Vec<usize> = input_files.par_iter()
+                .map(|path| Ok(read_decoded(path, encoding)?.lines().count()))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let first_doc: Vec<usize> = line_counts.iter()
+                .scan(0, |total, count| { *total += count; Some(*total - count) })
+                .collect();
+            let num_docs = load_size_object(&sa_files[0].size)?.len() - 1;
+            if line_counts.iter().sum::<usize>() != num_docs {
+                return Err(anyhow!("The trainset has {} lines but {:?} has {} docs, so it wasn't built over this trainset",
+                                   line_counts.iter().sum::<usize>(), data_file[0], num_docs));
+            }
+            Some(json!({"first_doc": first_doc, "num_docs": num_docs}))
+        },
+        false => None
+    };
+
+    profile.record("sa_load", phase_start);
+    let phase_start = Instant::now();
+
+    // Phase 1: Collect all matches. When streaming, each worker appends every finished path's matches
+    // to its own shard as a standalone gzip member, so only one path's matches are ever held per worker
+    let collect_span = info_span!("collect_matches").entered();
+    info!("Starting match collection...");
+    let mut done: serde_json::Map<String, Value> = serde_json::Map::new();
+    let mut changed: HashSet<usize> = HashSet::new();
+    if let Some(prior) = &prior_progress {
+        if prior["paths"] != json!(input_files) {
+            return Err(anyhow!("The trainset files changed since {:?} was written, so path ids would shift. \
+                                Rerun without --resume to start over", progress_path));
+        }
+        done = prior["done"].as_object().cloned().unwrap_or_default();
+        changed = done.iter()
+            .filter(|(path, entry)| entry["fingerprint"] != file_fingerprint(&PathBuf::from(path)))
+            .map(|(path, _)| path_map[&PathBuf::from(path)])
+            .collect();
+        if !changed.is_empty() {
+            info!("Redoing {:?} trainset files that changed since the interrupted run", changed.len());
+            done.retain(|path, _| !changed.contains(&path_map[&PathBuf::from(path)]));
+        }
+        info!("Resuming with {:?} of {:?} trainset files already done", done.len(), input_files.len());
+    }
+    let shards: Vec<(PathBuf, Mutex<File>)> = match (stream, &prior_progress) {
+        (true, Some(prior)) => {
+            // Anything past a shard's recorded length is from a path that never finished
+            for name in &match_names {
+                let shard_path = output.clone().join(name);
+                File::options().write(true).open(&shard_path)
+                    .with_context(|| format!("Failed to reopen {:?}", shard_path))?
+                    .set_len(prior["shards"][name].as_u64().unwrap_or(0))?;
+                if !changed.is_empty() {
+                    drop_paths_from_shard(&shard_path, &changed, legacy_match_format, compression.level)?;
+                }
+            }
+            match_names.iter()
+                .map(|n| {
+                    let shard_path = output.clone().join(n);
+                    let shard = File::options().append(true).open(&shard_path)?;
+                    Ok((shard_path, Mutex::new(shard)))
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        },
+        (true, None) => {
+            for prefix in &set_prefixes {
+                std::fs::create_dir_all(output.clone().join(prefix))?;
+            }
+            match_names.iter()
+                .map(|n| {
+                    let shard_path = output.clone().join(n);
+                    let mut shard = File::create(&shard_path).with_context(|| format!("Failed to create {:?}", shard_path))?;
+                    // Start with an empty gzip member so shards whose thread never got a path still decode
+                    shard.write_all(&compress_data(Vec::new(), &shard_path, compression.level)?)?;
+                    Ok((shard_path, Mutex::new(shard)))
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        },
+        (false, _) => Vec::new()
+    };
+    // A non-streamed run stopped by Ctrl-C left what it had collected in the usual files
+    let mut matches: Vec<Vec<(usize, usize, u64)>> = sas.iter().map(|_| Vec::new()).collect();
+    let mut offsets: Vec<Vec<TrainOffset>> = sas.iter().map(|_| Vec::new()).collect();
+    if let (false, Some(_)) = (stream, &prior_progress) {
+        for set in 0..sas.len() {
+            let match_file = output.clone().join(&match_names[set]);
+            let match_bytes = read_file_bytes(&match_file)
+                .with_context(|| format!("Failed to read the interrupted run's matches {:?}", match_file))?;
+            matches[set] = deserialize_matches(&match_bytes, true)?;
+            matches[set].retain(|(path_id, _, _)| !changed.contains(path_id));
+            if train_offsets {
+                let offsets_file = output.clone().join(&offset_names[set]);
+                let offsets_bytes = read_file_bytes(&offsets_file)
+                    .with_context(|| format!("Failed to read the interrupted run's train offsets {:?}", offsets_file))?;
+                offsets[set] = deserialize_train_offsets(&offsets_bytes, true)?;
+                offsets[set].retain(|(path_id, ..)| !changed.contains(path_id));
+            }
+        }
+    }
+    // progress.json only ever records shard lengths that end on a finished path. Each done path
+    // records its deduped match count per set
+    let mut set_matches = done_set_matches(&done, sas.len(), &progress_path)?;
+    let progress: Mutex<Value> = Mutex::new(json!({"params": manifest_params, "paths": input_files, "done": done,
+                                                   "shards": match_names.iter().zip(&shards)
+                                                       .map(|(name, (_, shard))| {
+                                                           let len = shard.lock().unwrap().metadata()?.len();
+                                                           Ok((name.clone(), json!(len)))
+                                                       })
+                                                       .collect::<Result<serde_json::Map<String, Value>, Error>>()?}));
+    if stream {
+        write_progress(&progress.lock().unwrap(), &progress_path)?;
+    }
+    let progress_written: Mutex<Instant> = Mutex::new(Instant::now());
+    let todo_files: Vec<(usize, &PathBuf)> = input_files.iter()
+        .enumerate()
+        .filter(|(_, p)| !done.contains_key(&p.display().to_string()))
+        .collect();
+    if bloom {
+        // Token windows only ever count at token-aligned val positions, so only those go in
+        let unit_width = encoder.as_ref().map_or(1, |e| e.token_width);
+        for (shard, shard_bloom) in sas.iter_mut().flat_map(|sa| sa.shards.iter_mut()) {
+            let (bloom_filter, rebuilt) = load_or_build_bloom(&shard.data_file, &shard.text, match_size * unit_width,
+                                                              unit_width, bloom_bits, bloom_hashes)?;
+            info!("{} Bloom filter for {:?}", if rebuilt { "Built" } else { "Loaded" }, shard.data_file);
+            *shard_bloom = Some(bloom_filter);
+        }
+    }
+    // Each file moves the bar on by its weight: one, or its size with --progress-bytes
+    let file_weights: Vec<u64> = match progress_bytes {
+        true => todo_files.par_iter()
+            .map(|(_, p)| match is_stdin(p) {
+                true => 0,
+                false => path_size(p).unwrap_or_else(|err| {
+                    warn!("Couldn't size {:?} for the progress bar, so it won't move it: {:?}", p, err);
+                    0
+                })
+            })
+            .collect(),
+        false => vec![1; todo_files.len()]
+    };
+    let pbar = match progress_bytes {
+        true => build_bytes_pbar(file_weights.iter().sum(), "Bytes"),
+        false => build_pbar(todo_files.len(), "Paths")
+    };
+    type PathResult = (Vec<Vec<(usize, usize, u64)>>, Vec<Vec<TrainOffset>>, usize, Vec<usize>, CollectStats);
+    let process_file = |todo_idx: usize, data: Box<dyn BufRead>, read_secs: f64| -> Result<PathResult, Error> {
+        let (idx, p) = todo_files[todo_idx];
+        let compute_start = Instant::now();
+        let (mut sa_matches, mut sa_offsets, mut path_stats) = collect_matches_from(data, p, idx, &sas, &match_params)?;
+        // Overlapping windows can hit the same (path, line, sa_pos) more than once, only keep one copy.
+        // Every tuple carries its path id, so deduping per path is the same as deduping globally
+        let num_raw: usize = sa_matches.iter().map(|m| m.len()).sum();
+        for path_matches in sa_matches.iter_mut() {
+            path_matches.sort_unstable();
+            path_matches.dedup();
+        }
+        for path_offsets in sa_offsets.iter_mut() {
+            path_offsets.sort_unstable();
+            path_offsets.dedup();
+        }
+        let num_deduped: Vec<usize> = sa_matches.iter().map(|m| m.len()).collect();
+        if !shards.is_empty() {
+            // Same shard slot in every set, so a thread's paths all land in matching shards
+            let slot = rayon::current_thread_index().unwrap_or(0) % num_set_shards;
+            // Hold the shard locks until progress is saved, so the recorded lengths cover exactly the done paths
+            let mut locked = Vec::new();
+            for (set, path_matches) in sa_matches.iter().enumerate() {
+                let shard_idx = set * num_set_shards + slot;
+                let (shard_path, shard) = &shards[shard_idx];
+                let shard_bytes = compress_data(serialize_matches(path_matches, legacy_match_format)?, shard_path,
+                                                compression.level)?;
+                let mut shard = shard.lock().unwrap();
+                shard.write_all(&shard_bytes)
+                    .with_context(|| format!("Failed to append to {:?}", shard_path))?;
+                locked.push((shard_idx, shard));
+            }
+            let mut progress = progress.lock().unwrap();
+            for (shard_idx, shard) in &locked {
+                progress["shards"][&match_names[*shard_idx]] = json!(shard.metadata()?.len());
+            }
+            progress["done"][p.display().to_string()] = json!({"fingerprint": file_fingerprint(p),
+                                                               "num_matches": num_deduped});
+            let mut written = progress_written.lock().unwrap();
+            if written.elapsed().as_secs_f64() >= PROGRESS_WRITE_SECS {
+                write_progress(&progress, &progress_path)?;
+                *written = Instant::now();
+            }
+            drop(locked);
+            sa_matches = Vec::new();
+        }
+        path_stats.read_secs = read_secs;
+        path_stats.compute_secs = compute_start.elapsed().as_secs_f64();
+        pbar.inc(file_weights[todo_idx]);
+        Ok((sa_matches, sa_offsets, num_raw, num_deduped, path_stats))
+    };
+    // Workers take files in trainset order, or with --s3-concurrency whichever the prefetcher has read next.
+    // Once Ctrl-C is hit they stop taking new ones
+    let prefetcher = s3_concurrency.map(|concurrency| {
+        let (receiver, handle) = prefetch_files(todo_files.iter().map(|(_, p)| (*p).clone()).collect(), concurrency);
+        (Mutex::new(receiver), handle)
+    });
+    let path_results: Result<Vec<(usize, PathResult)>, Error> = match &prefetcher {
+        None => todo_files.par_iter()
+            .enumerate()
+            .with_min_len(file_chunk)
+            .filter(|_| !cancel.load(Ordering::SeqCst))
+            .map(|(todo_idx, (_, p))| {
+                // Local files are decompressed as they're windowed, so only opening them (or fetching an s3
+                // file's compressed bytes) counts as reading
+                let read_start = Instant::now();
+                let data = read_pathbuf_streaming(p).with_context(|| format!("Failed to read {:?}", p))?;
+                Ok((todo_idx, process_file(todo_idx, data, read_start.elapsed().as_secs_f64())?))
+            })
+            .collect::<Result<Vec<_>, Error>>(),
+        Some((receiver, _)) => (0..todo_files.len()).into_par_iter()
+            .with_min_len(file_chunk)
+            .filter(|_| !cancel.load(Ordering::SeqCst))
+            .map(|_| {
+                // Time spent waiting here is time the reads kept a worker idle
+                let wait_start = Instant::now();
+                let (todo_idx, data) = receiver.lock().unwrap().blocking_recv()
+                    .ok_or_else(|| anyhow!("Prefetching stopped before every trainset file was read"))?;
+                Ok((todo_idx, process_file(todo_idx, Box::new(data?), wait_start.elapsed().as_secs_f64())?))
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    };
+    if stream {
+        // Catches up on every file finished since the last write, even if another file failed
+        write_progress(&progress.lock().unwrap(), &progress_path)?;
+    }
+    let path_results = path_results?;
+    if let (Some(concurrency), Some((receiver, handle))) = (s3_concurrency, prefetcher) {
+        drop(receiver);
+        let read_time = handle.join().map_err(|_| anyhow!("The prefetch thread panicked"))?;
+        info!("Prefetched trainset files {:?} at a time, {:.1}s of reads in all", concurrency, read_time.as_secs_f64());
+    }
+    // A file Ctrl-C kept from starting stays None
+    let mut file_results: Vec<Option<PathResult>> = todo_files.iter().map(|_| None).collect();
+    for (todo_idx, path_result) in path_results {
+        file_results[todo_idx] = Some(path_result);
+    }
+    let mut progress = progress.into_inner().unwrap();
+    let num_skipped = file_results.iter().filter(|result| result.is_none()).count();
+    let partial = num_skipped > 0;
+    let mut stats = CollectStats::default();
+    let mut num_raw_matches = set_matches.iter().sum::<usize>();
+    // Every file's matches are in hand by now, so each set grows once to its final size rather than file by file.
+    // Stream workers already wrote theirs out and hand back none
+    for set in 0..sas.len() {
+        matches[set].reserve_exact(file_results.iter().flatten()
+            .map(|(sa_matches, ..)| sa_matches.get(set).map_or(0, Vec::len))
+            .sum());
+        offsets[set].reserve_exact(file_results.iter().flatten()
+            .map(|(_, sa_offsets, ..)| sa_offsets.get(set).map_or(0, Vec::len))
+            .sum());
+    }
+    for ((_, p), path_result) in todo_files.iter().zip(file_results) {
+        let Some((sa_matches, sa_offsets, num_raw, num_deduped, path_stats)) = path_result else {
+            continue;
+        };
+        if !stream {
+            // Stream workers record their own files as they finish
+            progress["done"][p.display().to_string()] = json!({"fingerprint": file_fingerprint(p),
+                                                               "num_matches": num_deduped});
+        }
+        for (set, path_matches) in sa_matches.into_iter().enumerate() {
+            matches[set].extend(path_matches);
+        }
+        for (set, path_offsets) in sa_offsets.into_iter().enumerate() {
+            offsets[set].extend(path_offsets);
+        }
+        for (set, set_num_deduped) in num_deduped.into_iter().enumerate() {
+            set_matches[set] += set_num_deduped;
+        }
+        num_raw_matches += num_raw;
+        stats.add(&path_stats);
+    }
+    let num_matches: usize = set_matches.iter().sum();
+    info!("Collected {:?} matches, deduplicated down to {:?}", num_raw_matches, num_matches);
+    if partial && !stream {
+        // Stream runs keep progress.json current as they go, other runs only need it to be resumed.
+        // Their output may be on s3, and needn't exist yet
+        write_mem_to_pathbuf(&serde_json::to_vec(&progress)?, &progress_path)?;
+    }
+    drop(collect_span);
+    profile.record("collect", phase_start);
+    let phase_start = Instant::now();
+
+    // Phase 2: Save everything, one match set per data_file
+    // Paths are sorted, so each one's index in the list is its path_idx. Every set's paths and meta share a run_id
+    let run_id = new_run_id();
+    let path_map_json_bytes: Vec<u8> = serde_json::to_vec(&json!({"run_id": run_id, "match_size": match_size,
+                                                                  "tokenization": tokenization_meta(&encoder, char_windows),
+                                                                  "paths": input_files}))?;
+    for (set, (set_paths_name, set_meta_name)) in set_outputs.iter().enumerate() {
+        compression.write(&path_map_json_bytes, &output.clone().join(set_paths_name))?;
+        if !stream {
+            matches[set].par_sort_unstable();
+            compression.write_matches(&matches[set], legacy_match_format, &output.clone().join(&match_names[set]))?;
+        }
+        if train_offsets {
+            // Sorted like the matches, so each train line's offsets are contiguous
+            offsets[set].par_sort_unstable();
+            write_to_pathbuf(&output.clone().join(&offset_names[set]), compression.level,
+                             |writer| write_train_offsets(&offsets[set], writer))?;
+        }
+        let mut match_meta = json!({"version": MATCH_META_VERSION,
+                                    "run_id": run_id,
+                                    "match_size": match_size,
+                                    "data_file": data_file[set],
+                                    "data_file_id": set,
+                                    "data_files": data_file,
+                                    "tokenization": tokenization_meta(&encoder, char_windows),
+                                    "stride": stride,
+                                    "max_occurrences": max_occurrences,
+                                    "normalize": normalization_meta(normalize),
+                                    "paths_file": paths_name});
+        if let Some(self_match_meta) = &self_match_meta {
+            match_meta["self_match"] = self_match_meta.clone();
+        }
+        if train_offsets {
+            match_meta["train_offsets_file"] = json!(offsets_name);
+        }
+        match_meta["text_fields"] = json!(text_field);
+        match_meta["field_separator"] = json!(field_separator);
+        if raw_lines {
+            match_meta["raw_lines"] = json!(true);
+        }
+        if doc_per_file {
+            match_meta["doc_per_file"] = json!(true);
+        }
+        if case_insensitive {
+            match_meta["case_insensitive"] = json!(true);
+        }
+        if json_string_as_text {
+            match_meta["json_string_as_text"] = json!(true);
+        }
+        if sample_rate < 1.0 {
+            // A triage run, so anything later computed from these matches is an estimate
+            match_meta["sample_rate"] = json!(sample_rate);
+        }
+        if encoding != TextEncoding::Utf8 {
+            match_meta["encoding"] = json!(encoding.name());
+        }
+        if stream {
+            match_meta["num_shards"] = json!(num_set_shards);
+        }
+        if partial {
+            match_meta["partial"] = json!(true);
+        }
+        write_mem_to_pathbuf(&serde_json::to_vec(&match_meta)?, &output.clone().join(set_meta_name))?;
+    }
+    if data_file.len() > 1 {
+        let sets: Vec<Value> = data_file.iter().zip(&set_prefixes).zip(&set_matches).enumerate()
+            .map(|(set, ((sa_file, set_prefix), set_num_matches))| json!({"data_file_id": set, "data_file": sa_file,
+                                                                          "dir": set_prefix, "num_matches": set_num_matches}))
+            .collect();
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&json!({"run_id": run_id, "sets": sets}))?,
+                             &output.clone().join(&sets_name))?;
+    }
+    // Without a manifest, --skip-if-done won't take a partial run for a finished one
+    if !partial {
+        write_manifest(output, &manifest_name, &manifest_params, &expected_outputs)?;
+    }
+    profile.record("serialize", phase_start);
+
+    // Phase 3, finish up
+    info!("Found {:?} matches ({:?} before dedup) from {:?} paths", num_matches, num_raw_matches, input_files.len());
+    if data_file.len() > 1 {
+        for (set, ((sa_file, prefix), set_num_matches)) in data_file.iter().zip(&set_prefixes).zip(&set_matches).enumerate() {
+            info!("  data_file_id {:?}, {:?}: {:?} matches, in {:?}", set, sa_file, set_num_matches, output.clone().join(prefix));
+        }
+    }
+    info!("Skipped {:?} lines with no string at any of {:?}: {:?} without the field, {:?} with it null, {:?} with it not a string",
+          stats.missing_text(), text_field, stats.text_absent, stats.text_null, stats.text_not_string);
+    info!("Skipped {:?} lines whose text is empty", stats.text_empty);
+    if stats.blank > 0 {
+        info!("Passed over {:?} blank lines", stats.blank);
+    }
+    if stats.windows == 0 && stats.text_absent > 0 {
+        warn!("No train line had text at {:?}, was --text-field right?", text_field);
+    }
+    info!("Skipped {:?} malformed lines", stats.malformed);
+    info!("Skipped {:?} lines shorter than match_size", stats.skipped_short);
+    if min_doc_len > 0 {
+        info!("Skipped {:?} lines under --min-doc-len {:?} bytes", stats.skipped_min_len, min_doc_len);
+    }
+    if let Some(max_occurrences) = max_occurrences {
+        info!("Skipped {:?} windows occurring more than {:?} times", stats.common_windows, max_occurrences);
+    }
+    if sample_rate < 1.0 {
+        warn!("Sampled {:?} of {:?} windows at --sample-rate {:?}, so these matches are for triage, not a full decontamination",
+              stats.windows, stats.windows + stats.unsampled, sample_rate);
+    }
+    if let Some(occurrence_histogram) = occurrence_histogram {
+        let histograms: Vec<Value> = data_file.iter().enumerate()
+            .map(|(set, sa_file)| {
+                let buckets: &[usize] = stats.occurrence_buckets.get(set).map_or(&[], |b| b.as_slice());
+                let bucket_counts: Vec<Value> = buckets.iter().enumerate()
+                    .map(|(bucket, num_windows)| {
+                        let (min, max) = match bucket {
+                            0 => (0, 0),
+                            _ => (1u64 << (bucket - 1), (1u64 << (bucket - 1)).saturating_mul(2) - 1)
+                        };
+                        json!({"min": min, "max": max, "windows": num_windows})
+                    })
+                    .collect();
+                json!({"data_file": sa_file, "windows": buckets.iter().sum::<usize>(), "buckets": bucket_counts})
+            })
+            .collect();
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&histograms)?, occurrence_histogram)?;
+        info!("Wrote the occurrence histogram to {:?}", occurrence_histogram);
+    }
+    if stats.truncated_long + stats.skipped_long > 0 {
+        info!("{} {:?} lines over --max-line-bytes {:?}", if long_lines == LongLineMode::Skip { "Skipped" } else { "Truncated" },
+              stats.truncated_long + stats.skipped_long, max_line_bytes.unwrap_or(0));
+    }
+    if let Some(max_matches_per_doc) = max_matches_per_doc {
+        info!("Saturated {:?} lines at --max-matches-per-doc {:?}", stats.saturated, max_matches_per_doc);
+    }
+    info!("Workers spent {:.1}s {} trainset files and {:.1}s windowing and searching them", stats.read_secs,
+          if s3_concurrency.is_some() { "waiting on prefetched" } else { "opening" }, stats.compute_secs);
+    if bloom {
+        let searches = stats.windows * sas.len();
+        info!("Bloom filter short-circuited {:?} of {:?} SA searches ({:.2}%)", stats.bloom_skipped, searches,
+              100.0 * stats.bloom_skipped as f64 / cmp::max(searches, 1) as f64);
+    }
+    // An interrupted run is still profiled, its collect phase cut short
+    if let Some(profile_json) = profile_json {
+        profile.write("build_matches", profile_json)?;
+    }
+    if partial {
+        return Err(anyhow!("Interrupted with {} of {} trainset files still to do, so the outputs in {:?} are partial. \
+                            Rerun with --resume to finish", num_skipped, input_files.len(), output));
+    }
+    Ok(())
+}
+
+
+pub fn merge_match_sets(args: &MergeMatchesArgs) -> Result<(), Error> {
+    let &MergeMatchesArgs {match_dir: ref match_dirs, ref output, legacy_match_format, compression} = args;
+    let _span = info_span!("merge_matches").entered();
+    info!("Starting match merging...");
+    let matches_name = compression.name("matches.bin");
+    let paths_name = compression.name("paths.json");
+    let offsets_name = compression.name("train_offsets.bin");
+
+    // Phase 0: Check the runs are compatible. Matches only mean the same thing if they index the same SA
+    // with the same windows
+    // Trailing slashes so s3 prefixes are read as dirs too. A --prefix run is named by its meta file instead
+    let dirs: Vec<PathBuf> = match_dirs.iter()
+        .map(|dir| match dir.to_string_lossy().ends_with("matches.meta.json") {
+            true => dir.clone(),
+            false => PathBuf::from(format!("{}/", dir.display().to_string().trim_end_matches('/')))
+        })
+        .collect();
+    let mut metas: Vec<Value> = Vec::new();
+    for dir in &dirs {
+        let meta = load_match_meta(dir)?.ok_or_else(|| anyhow!("{:?} has no matches.meta.json, so it can't be merged", dir))?;
+        if meta["partial"].as_bool().unwrap_or(false) {
+            warn!("{:?} is from an interrupted build_matches run, so it only holds some of its trainset's matches", dir);
+        }
+        if !meta["self_match"].is_null() {
+            return Err(anyhow!("{:?} is a --self-match run, whose path ids are tied to its SA's doc ids, so it can't be merged", dir));
+        }
+        for key in ["data_file", "match_size", "tokenization", "normalize", "stride", "max_occurrences", "sample_rate"] {
+            if !metas.is_empty() && meta[key] != metas[0][key] {
+                return Err(anyhow!("{:?} has {} {} but {:?} has {}", dir, key, meta[key], dirs[0], metas[0][key]));
+            }
+        }
+        metas.push(meta);
+    }
+    // Train offsets only carry over if every run has them
+    let with_offsets = metas.iter().all(|meta| meta["train_offsets_file"].is_string());
+    if !with_offsets && metas.iter().any(|meta| meta["train_offsets_file"].is_string()) {
+        warn!("Only some of the runs were built with --train-offsets, so the merged matches won't have train offsets");
+    }
+
+    // Phase 1: Concatenate, shifting each run's path ids past the runs before it
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut seen_paths: HashSet<PathBuf> = HashSet::new();
+    let mut matches: Vec<(usize, usize, u64)> = Vec::new();
+    let mut offsets: Vec<TrainOffset> = Vec::new();
+    for (dir, meta) in dirs.iter().zip(&metas) {
+        let offset = paths.len();
+        let run_paths_file = match_dir(dir).join(meta["paths_file"].as_str().unwrap_or("paths.json.gz"));
+        check_paths_run(&run_paths_file, meta["run_id"].as_str(), &format!("{:?}", dir))?;
+        let run_paths = load_path_lookup(&run_paths_file)?;
+        for path in run_paths {
+            paths.push(path.clone());
+            if !seen_paths.insert(path.clone()) {
+                return Err(anyhow!("{:?} shows up in more than one run, so its matches would be counted twice", path));
+            }
+        }
+        let mut num_run_matches = 0;
+        for match_file in match_files(dir, &Some(meta.clone()))? {
+            let match_data_bytes = read_file_bytes(&match_file)
+                .with_context(|| format!("Failed to read matches from {:?}", match_file))?;
+            let run_matches = deserialize_matches(&match_data_bytes, true)?;
+            num_run_matches += run_matches.len();
+            matches.extend(run_matches.into_iter().map(|(path_id, line_num, sa_pos)| (path_id + offset, line_num, sa_pos)));
+        }
+        if with_offsets {
+            let offsets_file = match_dir(dir).join(meta["train_offsets_file"].as_str().unwrap_or_default());
+            let offsets_bytes = read_file_bytes(&offsets_file)
+                .with_context(|| format!("Failed to read train offsets from {:?}", offsets_file))?;
+            offsets.extend(deserialize_train_offsets(&offsets_bytes, true)?.into_iter()
+                .map(|(path_id, line_num, sa_pos, train_pos, line_len)| (path_id + offset, line_num, sa_pos, train_pos, line_len)));
+        }
+        info!("Read {:?} matches from {:?}", num_run_matches, dir);
+    }
+
+    // Phase 2: Save everything, as a run of its own
+    let run_id = new_run_id();
+    matches.par_sort_unstable();
+    let paths_json = json!({"run_id": run_id, "match_size": metas[0]["match_size"],
+                            "tokenization": metas[0]["tokenization"], "paths": paths});
+    compression.write(&serde_json::to_vec(&paths_json)?, &output.clone().join(&paths_name))?;
+    compression.write_matches(&matches, legacy_match_format, &output.clone().join(&matches_name))?;
+    if with_offsets {
+        offsets.par_sort_unstable();
+        write_to_pathbuf(&output.clone().join(&offsets_name), compression.level, |writer| write_train_offsets(&offsets, writer))?;
+    }
+    let mut match_meta = metas[0].clone();
+    let meta_fields = match_meta.as_object_mut().unwrap();
+    meta_fields.remove("num_shards");
+    match with_offsets {
+        true => meta_fields.insert("train_offsets_file".to_string(), json!(offsets_name)),
+        false => meta_fields.remove("train_offsets_file")
+    };
+    meta_fields.insert("version".to_string(), json!(MATCH_META_VERSION));
+    meta_fields.insert("run_id".to_string(), json!(run_id));
+    meta_fields.insert("paths_file".to_string(), json!(paths_name));
+    meta_fields.insert("merged_from".to_string(), json!(match_dirs));
+    write_mem_to_pathbuf(&serde_json::to_vec(&match_meta)?, &output.clone().join("matches.meta.json"))?;
+    let mut expected_outputs = vec![paths_name.as_str(), matches_name.as_str(), "matches.meta.json"];
+    if with_offsets {
+        expected_outputs.push(offsets_name.as_str());
+    }
+    write_manifest(output, "merge_matches.manifest.json",
+                   &json!({"match_dir": match_dirs, "compression": format!("{:?}", compression.compression)}),
+                   &expected_outputs)?;
+
+    info!("Merged {:?} matches over {:?} paths from {:?} runs", matches.len(), paths.len(), match_dirs.len());
+    Ok(())
+}
+
+
+fn train_coverages(offsets: &[TrainOffset], window: &WindowSpec, size_object: &Vec<u64>, header_lens: &[u64],
+                   self_first_doc: &Option<Vec<usize>>) -> HashMap<(usize, usize, usize), f32> {
+    // The fraction of each train line its matches into each val doc cover, keyed (val_doc_id, path_id, line_num).
+    // Offsets are sorted, so a train line's are contiguous. Matches grouping drops are skipped here too
+    let width = window.max_width() as u64;
+    let train_lines: Vec<&[TrainOffset]> = offsets.chunk_by(|a, b| (a.0, a.1) == (b.0, b.1)).collect();
+    train_lines.into_par_iter()
+        .flat_map_iter(|line_offsets| {
+            let (path_id, line_num, _, _, line_len) = line_offsets[0];
+            let mut by_doc: Vec<(usize, u64)> = line_offsets.iter()
+                .filter_map(|&(_, _, sa_pos, train_pos, _)| {
+                    let val_doc_id = doc_lookup(sa_pos, size_object);
+                    let in_doc_pos = sa_pos - size_object[val_doc_id];
+                    let self_match = self_first_doc.as_ref().is_some_and(|first_doc| first_doc[path_id] + line_num == val_doc_id);
+                    let crossing = in_doc_pos + width > size_object[val_doc_id + 1] - size_object[val_doc_id];
+                    let in_header = in_doc_pos + width <= header_lens[val_doc_id];
+                    (!self_match && !crossing && !in_header).then_some((val_doc_id, train_pos as u64))
+                })
+                .collect();
+            by_doc.sort_unstable();
+            by_doc.dedup();
+            by_doc.chunk_by(|a, b| a.0 == b.0)
+                .map(|doc_offsets| {
+                    // Union of [train_pos, train_pos + width) over the line's matches into this doc
+                    let (mut covered, mut covered_end) = (0u64, 0u64);
+                    for &(_, train_pos) in doc_offsets {
+                        covered += (train_pos + width).saturating_sub(cmp::max(train_pos, covered_end));
+                        covered_end = cmp::max(covered_end, train_pos + width);
+                    }
+                    ((doc_offsets[0].0, path_id, line_num), (covered as f64 / cmp::max(line_len, 1) as f64) as f32)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+
+pub fn mark_contaminates(args: &MarkContaminatesArgs) -> Result<(), Error> {
+    let &MarkContaminatesArgs {ref data_file, ref match_location, ref output, ref prefix, threshold, min_overlap_bytes,
+                               min_distinct_windows, threshold_mode, train_coverage_threshold, ref threshold_by_length, ref ignore_phrases, metric, gap_tolerance, min_span, merge_adjacency, group_mode, ref spill_dir, match_size, max_group_starts, ref text_field, ref field_separator, ref body_offsets, ref benchmark_map,
+                               spotcheck, ref spotcheck_output, ref dump_spans, max_span_chars, ref paths,
+                               ref train_report, ref overlap_report, ref stats_json, ref profile_json, ref contaminated_ids, ref window_stats, ref full_verdict, format, strict, skip_if_done,
+                               ref sa_paths, compression} = args;
+    let manifest_params = json!({"data_file": data_file, "sa_paths": sa_paths.manifest(), "match_location": match_location,
+                                 "threshold": threshold, "min_overlap_bytes": min_overlap_bytes,
+                                 "min_distinct_windows": min_distinct_windows,
+                                 "threshold_mode": format!("{:?}", threshold_mode),
+                                 "train_coverage_threshold": train_coverage_threshold,
+                                 "threshold_by_length": threshold_by_length, "ignore_phrases": ignore_phrases,
+                                 "metric": format!("{:?}", metric), "gap_tolerance": gap_tolerance, "min_span": min_span,
+                                 "merge_adjacency": format!("{:?}", merge_adjacency),
+                                 "match_size": match_size, "max_group_starts": max_group_starts, "body_offsets": body_offsets,
+                                 "benchmark_map": benchmark_map, "format": format!("{:?}", format),
+                                 "compression": format!("{:?}", compression.compression)});
+    check_prefix(prefix)?;
+    let sa = sa_paths.resolve(data_file)?;
+    let contaminates_name = prefixed_name(prefix, &match format {
+        ContaminateFormat::Bincode => compression.name("contaminates.bin"),
+        ContaminateFormat::Jsonl => compression.name("contaminates.jsonl")
+    });
+    let report_name = prefixed_name(prefix, "benchmark_report.json");
+    // An SA built with --source-tag also gets each tag's contaminates on their own, named for the tag
+    let sources = load_sa_sources(data_file)?;
+    let mut source_tags: Vec<String> = sources.iter().map(|(tag, ..)| tag.clone()).collect();
+    source_tags.sort();
+    source_tags.dedup();
+    let source_names: Vec<String> = source_tags.iter()
+        .map(|tag| prefixed_name(prefix, &match format {
+            ContaminateFormat::Bincode => compression.name(&format!("contaminates.{}.bin", tag)),
+            ContaminateFormat::Jsonl => compression.name(&format!("contaminates.{}.jsonl", tag))
+        }))
+        .collect();
+    let source_report_name = prefixed_name(prefix, "source_report.json");
+    let manifest_name = prefixed_name(prefix, "mark_contaminates.manifest.json");
+    // paths.json.gz normally sits right next to matches.bin.gz, under whatever name the meta recorded
+    let match_meta = load_match_meta(match_location)?;
+    if match_meta.as_ref().is_some_and(|m| m["partial"].as_bool().unwrap_or(false)) {
+        warn!("{:?} is from an interrupted build_matches run, so contaminates only reflect the trainset files it got through",
+              match_location);
+    }
+    // A run over several val sets tagged each set's matches with the data_file it was built against
+    let data_file_id = match_meta.as_ref().and_then(|m| m["data_file_id"].as_u64());
+    if let Some(built_against) = match_meta.as_ref().and_then(|m| m["data_file"].as_str()).filter(|f| PathBuf::from(f) != *data_file) {
+        warn!("{:?} holds matches against data_file_id {:?} ({:?}), not {:?}", match_location, data_file_id.unwrap_or(0),
+              built_against, data_file);
+    }
+    let sample_rate = match_meta.as_ref().and_then(|m| m["sample_rate"].as_f64());
+    if let Some(sample_rate) = sample_rate {
+        warn!("{:?} was built with --sample-rate {:?}, so coverages and contamination rates are estimates from a sample of \
+               windows, not a full decontamination", match_location, sample_rate);
+    }
+    let paths_name = match match_meta.as_ref().and_then(|m| m["paths_file"].as_str()) {
+        Some(paths_name) => paths_name.to_string(),
+        None => format!("{}paths.json.gz", match_prefix(match_location))
+    };
+    let paths_file = paths.clone().unwrap_or_else(|| match_dir(match_location).join(paths_name));
+    let run_id = match_meta.as_ref().and_then(|m| m["run_id"].as_str());
+    if format == ContaminateFormat::Jsonl || spotcheck_output.is_some() || train_report.is_some() {
+        check_paths_run(&paths_file, run_id, &format!("{:?}", match_location))?;
+    }
+    let mut expected_outputs = vec![contaminates_name.as_str()];
+    if benchmark_map.is_some() {
+        expected_outputs.push(report_name.as_str());
+    }
+    if !sources.is_empty() {
+        expected_outputs.extend(source_names.iter().map(|name| name.as_str()));
+        expected_outputs.push(source_report_name.as_str());
+    }
+    if skip_if_done && run_already_done(output, &manifest_name, &manifest_params, &expected_outputs) {
+        info!("Outputs and manifest already present in {:?}, skipping", output);
+        return Ok(());
+    }
+
+    if max_group_starts == Some(0) {
+        return Err(anyhow!("--max-group-starts must be at least 1"));
+    }
+    if group_mode == GroupMode::External && (spotcheck.is_some() || dump_spans.is_some()) {
+        return Err(anyhow!("--spotcheck and --dump-spans look groups up after scoring, which --group-mode external doesn't keep"));
+    }
+    let _span = info_span!("mark_contaminates").entered();
+    info!("Starting contaminate marking...");
+    // Phase timings are kept for --stats-json and --profile-json; the spans log the same durations
+    let mut profile = PhaseProfile::new();
+    let phase_start = Instant::now();
+    // Phase 0: Load everything into mem
+    let match_size = resolve_match_size(&match_meta, match_size)?;
+    let window = meta_window_spec(&match_meta, match_size)?;
+    check_sa_version(data_file)?;
+    check_match_tokenization(data_file, &match_meta)?;
+    if let Some(sa_match_size) = load_sa_meta(data_file)?["match_size"].as_u64().filter(|m| *m as usize != match_size) {
+        warn!("{:?} was built for --match-size {}, but these matches are at {}", data_file, sa_match_size, match_size);
+    }
+    // Offsets from differently normalized text can't be compared. Matches without meta predate normalization
+    let match_normalization = match_meta.as_ref().map_or(json!([]), |m| m.get("normalize").cloned().unwrap_or(json!([])));
+    let sa_normalization = load_sa_normalization(data_file)?;
+    if match_normalization != sa_normalization {
+        return Err(anyhow!("Matches were built with normalization {} but data_file has {}",
+                           match_normalization, sa_normalization));
+    }
+    let match_case_insensitive = match_meta.as_ref().is_some_and(|m| m["case_insensitive"].as_bool().unwrap_or(false));
+    if match_case_insensitive != sa_case_insensitive(data_file)? {
+        return Err(anyhow!("Matches and data_file disagree on --case-insensitive (matches: {}, data_file: {})",
+                           match_case_insensitive, !match_case_insensitive));
+    }
+    let match_files = match_files(match_location, &match_meta)?;
+    let size_object = load_size_object(&sa.size)?;
+    check_size_object(&size_object, &sa)?;
+    let num_docs = size_object.len().saturating_sub(1);
+    let doc_names = load_doc_names(data_file, num_docs)?;
+    let header_lens = load_header_lens(data_file, body_offsets.as_ref(), num_docs)?;
+    // In a --self-match run train line l of path p is val doc first_doc[p] + l, and those matches say nothing
+    let self_first_doc: Option<Vec<usize>> = match match_meta.as_ref().map(|m| &m["self_match"]) {
+        Some(self_meta) if !self_meta.is_null() => {
+            if self_meta["num_docs"].as_u64() != Some(num_docs as u64) {
+                return Err(anyhow!("The --self-match matches were built over {} docs but {:?} has {}",
+                                   self_meta["num_docs"], data_file, num_docs));
+            }
+            Some(serde_json::from_value(self_meta["first_doc"].clone())?)
+        },
+        _ => None
+    };
+    let self_matches = AtomicUsize::new(0);
+    let crossing_matches = AtomicUsize::new(0);
+    // Train lines that hit --max-matches-per-doc, known by their SATURATED sentinel match
+    let saturated: DashSet<(usize, usize)> = DashSet::new();
+    let by_length = match threshold_by_length {
+        Some(path) => load_length_thresholds(path)?,
+        None => Vec::new()
+    };
+    let ignore_phrases = match ignore_phrases {
+        Some(path) => Some(Arc::new(IgnorePhrases::load(path, data_file)?)),
+        None => None
+    };
+    let contamination_threshold = ContaminationThreshold {fraction: threshold, min_overlap_bytes, min_distinct_windows,
+                                                          mode: threshold_mode, metric, gap_tolerance, min_span, adjacency: merge_adjacency, by_length,
+                                                          ignore_phrases: ignore_phrases.clone(), max_starts: max_group_starts,
+                                                          score_all: full_verdict.is_some()};
+    warn_on_loose_threshold(&contamination_threshold, &window, &size_object, &header_lens);
+
+    // Phase 1: group all matches by their val set id (and do path lookups)
+    profile.record("load", phase_start);
+    let phase_start = Instant::now();
+    let group_span = info_span!("group").entered();
+    info!("Starting grouping of matches...");
+    let map_groups: GroupMap = DashMap::new();
+    // Match groups maps:
+    // {(Val_set_doc_id, Val_set_doc_len) -> 
+    //            {train_set_doc_id -> [in_doc_pos]}
+    // }
+    // --group-mode sort collects the same (val_doc_id, path_id, line_num, in_doc_pos) as one flat vec instead,
+    // and --group-mode external spills that vec to disk in sorted runs
+    let mut sorted_groups: Vec<(usize, usize, usize, u64)> = Vec::new();
+    let mut spill_runs: Option<SpillRuns> = match group_mode {
+        GroupMode::External => Some(SpillRuns::new(spill_dir.clone().unwrap_or_else(std::env::temp_dir))?),
+        _ => None
+    };
+    // A position past the end of the text means data_file isn't the SA these matches came from, and
+    // doc_lookup would quietly pin it on the last doc
+    let text_len = size_object.last().copied().unwrap_or(0);
+    let check_positions = |matches: &[(usize, usize, u64)], match_file: &PathBuf| -> Result<(), Error> {
+        if let Some(max_pos) = matches.iter().map(|(_, _, sa_pos)| *sa_pos).filter(|pos| *pos != SATURATED).max()
+                                      .filter(|pos| *pos >= text_len) {
+            return Err(anyhow!("{:?} has a match at SA position {}, but {:?} is only {} bytes. Do the matches come from \
+                                a different data_file?", match_file, max_pos, data_file, text_len));
+        }
+        Ok(())
+    };
+    // (val_doc_id, path_id, line_num, in_doc_pos) for matches that count towards some val doc
+    let to_group = |(path_id, line_num, sa_pos): (usize, usize, u64)| {
+        if sa_pos == SATURATED {
+            saturated.insert((path_id, line_num));
+            return None;
+        }
+        let val_doc_id = doc_lookup(sa_pos, &size_object);
+        if self_first_doc.as_ref().is_some_and(|first_doc| first_doc[path_id] + line_num == val_doc_id) {
+            self_matches.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let in_doc_pos = sa_pos - size_object[val_doc_id];
+        if in_doc_pos + (window.min_width() as u64) > size_object[val_doc_id + 1] - size_object[val_doc_id] {
+            // The window runs on into the next doc, so it's a match on the concatenated text, not on this doc.
+            // Char windows can also cross with fewer bytes left; theirs get cut at the doc's end instead
+            crossing_matches.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if in_doc_pos + (window.max_width() as u64) <= header_lens[val_doc_id] {
+            // Match lives entirely in the doc header, so it says nothing about the body
+            return None;
+        }
+        Some((val_doc_id, path_id, line_num, in_doc_pos))
+    };
+    for match_file in &match_files {
+        if group_mode != GroupMode::Map {
+            // Read a record at a time, so the raw matches are never all in memory next to the groups. External
+            // groups then hold no more than a run's worth of matches
+            let mut reader = open_pathbuf_reader(match_file)
+                .with_context(|| format!("Failed to read matches from {:?}", match_file))?;
+            for_each_record(&mut reader, strict, |record| {
+                let chunk = decode_match_record(record)?;
+                check_positions(&chunk, match_file)?;
+                match spill_runs.as_mut() {
+                    Some(spill_runs) => spill_runs.extend(chunk.into_par_iter().filter_map(to_group)),
+                    None => {
+                        sorted_groups.par_extend(chunk.into_par_iter().filter_map(to_group));
+                        Ok(())
+                    }
+                }
+            })?;
+            continue;
+        }
+        // Shards are read one at a time, so only one shard's raw matches are in memory next to the map
+        let match_data_bytes = read_file_bytes(match_file)
+            .with_context(|| format!("Failed to read matches from {:?}", match_file))?;
+        let matches: Vec<(usize, usize, u64)> = deserialize_matches(&match_data_bytes, strict)?;
+        check_positions(&matches, match_file)?;
+        let pbar = build_pbar(matches.len(), "Matches");
+        matches.into_par_iter()
+            .filter_map(|m| {
+                pbar.inc(1);
+                to_group(m)
+            })
+            .for_each(|(val_doc_id, path_id, line_num, in_doc_pos)| {
+                let val_doc_size = size_object[val_doc_id+1] - size_object[val_doc_id];
+                map_groups.entry((val_doc_id, val_doc_size.try_into().unwrap())).or_default()
+                    .entry((path_id, line_num)).or_default()
+                    .push(in_doc_pos);
+            });
+    }
+    if self_first_doc.is_some() {
+        info!("Dropped {:?} matches of docs against themselves", self_matches.into_inner());
+    }
+    let crossing_matches = crossing_matches.into_inner();
+    if crossing_matches > 0 {
+        warn!("Dropped {:?} matches running past the end of their val doc into the next one, which match the \
+               concatenated SA text rather than either doc", crossing_matches);
+    }
+    if !saturated.is_empty() {
+        info!("Found {:?} train lines saturated at --max-matches-per-doc, flagged wherever they match", saturated.len());
+    }
+    // Sorted once here, so merging a train line's intervals needn't sort them again. External groups are
+    // only sorted as they're merged back, in Phase 2. Map groups are listed first, like par_map_docs does,
+    // so rayon splits the docs evenly rather than taking them off the map one by one
+    let match_groups: Option<MatchGroups> = match group_mode {
+        GroupMode::Map => {
+            map_groups.iter().collect::<Vec<_>>().into_par_iter().for_each(|doc_matches| {
+                doc_matches.value().iter_mut().for_each(|mut starts| starts.sort_unstable());
+            });
+            Some(MatchGroups::Map(map_groups))
+        },
+        GroupMode::Sort => {
+            sorted_groups.par_sort_unstable();
+            Some(MatchGroups::sorted(sorted_groups))
+        },
+        GroupMode::External => None
+    };
+    drop(group_span);
+    profile.record("group", phase_start);
+
+    // Phase 1b: With --train-coverage-threshold, how much of each train line its matches into each val doc cover
+    let train_coverage: Option<HashMap<(usize, usize, usize), f32>> = match train_coverage_threshold {
+        Some(_) => {
+            let offsets_name = match_meta.as_ref().and_then(|m| m["train_offsets_file"].as_str())
+                .ok_or_else(|| anyhow!("--train-coverage-threshold needs train offsets, but {:?} wasn't built with \
+                                        build_matches --train-offsets", match_location))?;
+            let offsets_file = match_dir(match_location).join(offsets_name);
+            let offsets_bytes = read_file_bytes(&offsets_file)
+                .with_context(|| format!("Failed to read train offsets from {:?}", offsets_file))?;
+            let offsets = deserialize_train_offsets(&offsets_bytes, strict)?;
+            Some(train_coverages(&offsets, &window, &size_object, &header_lens, &self_first_doc))
+        },
+        None => None
+    };
+    let train_dropped = AtomicUsize::new(0);
+    // (val doc, train line) pairs --max-group-starts scored only some starts of
+    let capped_groups = AtomicUsize::new(0);
+    // Distinct (val doc, merged interval) pairs --ignore-phrases left out of coverage
+    let ignored_intervals = AtomicUsize::new(0);
+
+    // Phase 2: For each group merge intervals and compute thresholds
+    let phase_start = Instant::now();
+    let merge_span = info_span!("merge").entered();
+    info!("Starting contaminate aggregation...");
+    // External groups don't know how many val docs they hold until they're merged, so count against all of them
+    let pbar = build_pbar(match_groups.as_ref().map_or(num_docs, |groups| groups.num_docs()), "Groups");
+
+    let score_doc = |val_doc_id: usize, val_doc_size: usize, doc_matches: &DocMatches| {
+        let header_len = header_lens[val_doc_id] as usize;
+        // Char windows need the doc text to find where each match ends, ngram-fraction to tell n-grams apart,
+        // and ignored phrases to check intervals against
+        let doc_text = match (window, metric) {
+            (WindowSpec::Chars(_), _) | (_, MatchMetric::NgramFraction) =>
+                load_text_range(&sa.text, size_object[val_doc_id], size_object[val_doc_id + 1]),
+            _ if ignore_phrases.is_some() => load_text_range(&sa.text, size_object[val_doc_id], size_object[val_doc_id + 1]),
+            _ => Vec::new()
+        };
+        let doc_score = merge_matches(val_doc_id, doc_matches, &window, &doc_text, val_doc_size, header_len,
+                                      &contamination_threshold)?;
+        capped_groups.fetch_add(doc_score.capped_groups, Ordering::Relaxed);
+        ignored_intervals.fetch_add(doc_score.ignored_intervals, Ordering::Relaxed);
+        let max_fraction = doc_score.max_fraction;
+        let mut merged_matches = doc_score.contaminates;
+        // A saturated train line is flagged whatever its (partial, so lower bound) coverage came to
+        for &((train_path_id, line_num), starts) in doc_matches.iter().filter(|(train_line, _)| saturated.contains(train_line)) {
+            if !merged_matches.iter().any(|&(_, path_id, line, _)| (path_id, line) == (train_path_id, line_num)) {
+                let covered = _body_coverage(starts.iter(), true, &window, &doc_text, header_len, gap_tolerance,
+                                             merge_adjacency);
+                let coverage = covered as f64 / cmp::max(val_doc_size.saturating_sub(header_len), 1) as f64;
+                merged_matches.push((val_doc_id, train_path_id, line_num, coverage as f32));
+            }
+        }
+        if let (Some(min_train_coverage), Some(train_coverage)) = (train_coverage_threshold, &train_coverage) {
+            let num_flagged = merged_matches.len();
+            merged_matches.retain(|&(_, path_id, line_num, _)| saturated.contains(&(path_id, line_num))
+                || train_coverage.get(&(val_doc_id, path_id, line_num)).is_some_and(|c| *c as f64 >= min_train_coverage));
+            train_dropped.fetch_add(num_flagged - merged_matches.len(), Ordering::Relaxed);
+        }
+        // Coverage of a flagged doc counts every train doc that flagged it, together
+        let coverage = match stats_json.is_some() && !merged_matches.is_empty() {
+            true => {
+                let flagged: HashSet<(usize, usize)> = merged_matches.iter()
+                    .map(|&(_, path_id, line_num, _)| (path_id, line_num))
+                    .collect();
+                let flagged_starts: Vec<u64> = doc_matches.iter()
+                    .filter(|(train_line, _)| flagged.contains(train_line))
+                    .flat_map(|(_, starts)| starts.iter().copied())
+                    .collect();
+                let body_size = val_doc_size.saturating_sub(header_len);
+                let covered = _body_coverage(flagged_starts.iter(), false, &window, &doc_text, header_len, gap_tolerance,
+                                             merge_adjacency);
+                Some(covered as f64 / cmp::max(body_size, 1) as f64)
+            },
+            false => None
+        };
+        // Window stats count every match into the doc, whichever train line it came from
+        let doc_window_stats = match window_stats.is_some() {
+            true => {
+                let mut starts: Vec<u64> = doc_matches.iter().flat_map(|(_, starts)| starts.iter().copied()).collect();
+                starts.sort_unstable();
+                starts.dedup();
+                let body_size = val_doc_size.saturating_sub(header_len);
+                let body_text = doc_text.get(header_len..).unwrap_or_default();
+                let covered = _body_coverage(starts.iter(), true, &window, &doc_text, header_len, gap_tolerance,
+                                             merge_adjacency);
+                let mut stats = json!({"val_doc_id": val_doc_id, "total_windows": window.num_windows(body_size, body_text),
+                                       "matched_windows": starts.len(), "covered_bytes": covered, "doc_bytes": body_size});
+                add_doc_name(&mut stats, &doc_names, val_doc_id);
+                Some(stats)
+            },
+            false => None
+        };
+        // The verdict's coverage is each matching train line's on its own, whether or not it was flagged, scored
+        // the way thresholding did (capped, and without ignored phrases or short spans)
+        let doc_verdict = full_verdict.is_some().then_some((val_doc_id, max_fraction, doc_matches.len()));
+        pbar.inc(1);
+        Ok((merged_matches, coverage, doc_window_stats, doc_verdict))
+    };
+    let doc_results: Vec<_> = match (&match_groups, spill_runs) {
+        (Some(match_groups), _) => match_groups.par_map_docs(&size_object, score_doc)?,
+        (None, Some(spill_runs)) => {
+            // Val docs come off the merged runs in order, and are scored a batch (of whole docs) at a time
+            let mut doc_results = Vec::new();
+            let mut batch: Vec<(usize, usize, usize, u64)> = Vec::new();
+            for group in spill_runs.merge()? {
+                let group = group?;
+                if batch.len() >= EXTERNAL_BATCH_ENTRIES && batch.last().is_some_and(|last| last.0 != group.0) {
+                    doc_results.extend(MatchGroups::sorted(std::mem::take(&mut batch)).par_map_docs(&size_object, score_doc)?);
+                }
+                batch.push(group);
+            }
+            doc_results.extend(MatchGroups::sorted(batch).par_map_docs(&size_object, score_doc)?);
+            doc_results
+        },
+        (None, None) => unreachable!("external grouping always has spill runs")
+    };
+    let mut coverages: Vec<f64> = doc_results.iter().filter_map(|(_, coverage, ..)| *coverage).collect();
+    let mut doc_window_stats: Vec<Value> = doc_results.iter().filter_map(|(_, _, stats, _)| stats.clone()).collect();
+    let doc_verdicts: HashMap<usize, (f64, usize)> = doc_results.iter()
+        .filter_map(|(.., verdict)| verdict.map(|(val_doc_id, max_coverage, num_lines)| (val_doc_id, (max_coverage, num_lines))))
+        .collect();
+    let mut contaminates: Vec<Contaminate> = doc_results.into_iter().flat_map(|(c, ..)| c).collect();
+    // Groups come back in whatever order they finished, so sort for reproducible outputs.
+    // (val_doc_id, path, line) is unique, so coverage never needs to break ties
+    contaminates.par_sort_unstable_by_key(|&(val_doc_id, train_path_id, line_num, _)| (val_doc_id, train_path_id, line_num));
+    if let Some(min_train_coverage) = train_coverage_threshold {
+        info!("Dropped {:?} contaminates covering under {:?} of their train line", train_dropped.into_inner(), min_train_coverage);
+    }
+    if ignore_phrases.is_some() {
+        info!("Left {:?} distinct merged match intervals out of coverage as --ignore-phrases", ignored_intervals.into_inner());
+    }
+    let capped_groups = capped_groups.into_inner();
+    if capped_groups > 0 {
+        warn!("Scored only the first {:?} starts of {:?} (val doc, train line) pairs with more, so their coverage is a lower bound",
+              max_group_starts.unwrap_or_default(), capped_groups);
+    }
+    drop(merge_span);
+    profile.record("merge", phase_start);
+    let phase_start = Instant::now();
+
+    // Phase 3: Save contaminates
+    let contaminate_bytes = match format {
+        ContaminateFormat::Bincode => contaminates_to_bincode(&contaminates, run_id)?,
+        ContaminateFormat::Jsonl => contaminates_to_jsonl(&contaminates, &paths_file, &doc_names)?
+    };
+    compression.write(&contaminate_bytes, &output.clone().join(&contaminates_name))?;
+    let by_tag = source_contaminates(&contaminates, &sources, &source_tags);
+    for (tag_contaminates, source_name) in by_tag.iter().zip(&source_names) {
+        let tag_bytes = match format {
+            ContaminateFormat::Bincode => contaminates_to_bincode(tag_contaminates, run_id)?,
+            ContaminateFormat::Jsonl => contaminates_to_jsonl(tag_contaminates, &paths_file, &doc_names)?
+        };
+        compression.write(&tag_bytes, &output.clone().join(source_name))?;
+    }
+    if !sources.is_empty() {
+        let report = build_source_report(&by_tag, &sources, &source_tags);
+        for (tag, tag_report) in report.as_object().unwrap() {
+            info!("  {}: {} of {} val docs contaminated", tag, tag_report["contaminated_docs"], tag_report["val_docs"]);
+        }
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&report)?, &output.clone().join(&source_report_name))?;
+    }
+    if let Some(benchmark_map) = benchmark_map {
+        let ranges = load_benchmark_ranges(benchmark_map, data_file_id.unwrap_or(0) as usize)?;
+        if ranges.is_empty() {
+            warn!("{:?} has no benchmarks in data_file_id {:?}", benchmark_map, data_file_id.unwrap_or(0));
+        }
+        let report = build_benchmark_report(&contaminates, ranges)?;
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&report)?, &output.clone().join(&report_name))?;
+    }
+    let val_spans = match_groups.as_ref().map(|match_groups| ValSpans {match_groups, window: &window, sa: &sa, size_object: &size_object,
+                                                                        doc_names: &doc_names, adjacency: merge_adjacency});
+    if let (Some(num_samples), Some(spotcheck_output), Some(val_spans)) = (spotcheck, spotcheck_output, &val_spans) {
+        // Train lines are reassembled the way build_matches did, unless told otherwise
+        let raw_lines = match_meta.as_ref().and_then(|m| m["raw_lines"].as_bool()).unwrap_or(false);
+        let text_fields: Vec<String> = match (text_field.is_empty(), match_meta.as_ref().map(|m| &m["text_fields"])) {
+            _ if raw_lines => Vec::new(),
+            (true, Some(Value::Array(fields))) => fields.iter().filter_map(|f| f.as_str().map(String::from)).collect(),
+            (true, _) => vec!["text".to_string()],
+            (false, _) => text_field.clone()
+        };
+        let field_separator = field_separator.clone()
+            .or_else(|| match_meta.as_ref().and_then(|m| m["field_separator"].as_str().map(String::from)))
+            .unwrap_or_else(|| "\n\n".to_string());
+        let train_encoding = match &match_meta {
+            Some(match_meta) => TextEncoding::from_meta(match_meta)?,
+            None => TextEncoding::Utf8
+        };
+        let doc_per_file = match_meta.as_ref().and_then(|m| m["doc_per_file"].as_bool()).unwrap_or(false);
+        let normalize = parse_normalization(&sa_normalization)?;
+        let train_lines = TrainLines {paths_file: &paths_file, text_fields: &text_fields, field_separator: &field_separator,
+                                      encoding: train_encoding, doc_per_file, normalize: &normalize};
+        write_spotcheck(&contaminates, val_spans, &train_lines, num_samples, spotcheck_output)?;
+    }
+    if let (Some(dump_spans), Some(val_spans)) = (dump_spans, &val_spans) {
+        write_spans(&contaminates, val_spans, max_span_chars, dump_spans)?;
+    }
+    if let Some(train_report) = train_report {
+        write_mem_to_pathbuf(&build_train_report(&contaminates, &paths_file)?, train_report)?;
+    }
+    if let Some(overlap_report) = overlap_report {
+        let report = build_overlap_report(&contaminates, &doc_names);
+        for bucket in report["histogram"].as_array().unwrap() {
+            info!("  {} val docs contaminated by exactly {} train files", bucket["val_docs"], bucket["train_files"]);
+        }
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&report)?, overlap_report)?;
+    }
+    if let Some(window_stats) = window_stats {
+        doc_window_stats.sort_by_key(|stats| stats["val_doc_id"].as_u64());
+        let mut stats_bytes: Vec<u8> = Vec::new();
+        for stats in &doc_window_stats {
+            stats_bytes.extend(serde_json::to_vec(stats)?);
+            stats_bytes.push(b'\n');
+        }
+        write_mem_to_pathbuf(&stats_bytes, window_stats)?;
+        info!("Wrote window stats for {:?} matched val docs to {:?}", doc_window_stats.len(), window_stats);
+    }
+    if let Some(full_verdict) = full_verdict {
+        write_mem_to_pathbuf(&build_full_verdict(&contaminates, &doc_verdicts, num_docs, &doc_names)?, full_verdict)?;
+        info!("Wrote verdicts for all {:?} val docs to {:?}", num_docs, full_verdict);
+    }
+    write_manifest(output, &manifest_name, &manifest_params, &expected_outputs)?;
+    profile.record("save", phase_start);
+
+    // Phase 4: Finalize
+    let total_contams: DashSet<usize> = DashSet::new();
+    contaminates.par_iter()
+        .for_each(|(val_doc_id, _, _, _)| {
+            total_contams.insert(*val_doc_id);
+    });
+    match data_file_id {
+        Some(data_file_id) => info!("Found {:?} contaminated val set docs in {:?} (data_file_id {:?})", total_contams.len(),
+                                    data_file, data_file_id),
+        None => info!("Found {:?} contaminated val set docs", total_contams.len())
+    }
+    info!("Found {:?} total contaminates", contaminates.len());
+    if let Some(contaminated_ids) = contaminated_ids {
+        let mut ids: Vec<usize> = total_contams.iter().map(|id| *id).collect();
+        ids.par_sort_unstable();
+        let ids_bytes: Vec<u8> = ids.iter().flat_map(|id| match &doc_names {
+            Some(doc_names) => format!("{}\t{}\n", id, doc_names[*id]),
+            None => format!("{}\n", id)
+        }.into_bytes()).collect();
+        write_mem_to_pathbuf(&ids_bytes, contaminated_ids)?;
+        info!("Wrote {:?} contaminated val doc ids to {:?}", ids.len(), contaminated_ids);
+    }
+    if let Some(stats_json) = stats_json {
+        coverages.sort_by(|a, b| a.total_cmp(b));
+        let median_coverage = match coverages.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(coverages[n / 2]),
+            n => Some((coverages[n / 2 - 1] + coverages[n / 2]) / 2.0)
+        };
+        let stats = json!({"data_file": data_file,
+                           "data_file_id": data_file_id,
+                           "val_docs": num_docs,
+                           "contaminated_docs": total_contams.len(),
+                           "contaminates": contaminates.len(),
+                           "contamination_rate": total_contams.len() as f64 / cmp::max(num_docs, 1) as f64,
+                           "mean_coverage": (!coverages.is_empty()).then(|| coverages.iter().sum::<f64>() / coverages.len() as f64),
+                           "median_coverage": median_coverage,
+                           "sampled": sample_rate.is_some(),
+                           "sample_rate": sample_rate,
+                           "phase_secs": &profile.phase_secs});
+        write_mem_to_pathbuf(&serde_json::to_vec_pretty(&stats)?, stats_json)?;
+    }
+    if let Some(profile_json) = profile_json {
+        profile.write("mark_contaminates", profile_json)?;
+    }
+    Ok(())
+}
+
+
+pub fn filter(args: &FilterArgs) -> Result<(), Error> {
+    let &FilterArgs {ref contaminates, ref data_file, ref sa_paths, ref input, ref output, invert, ref body_offsets} = args;
+    let sa = sa_paths.resolve(data_file)?;
+    let _span = info_span!("filter").entered();
+    info!("Starting filtering...");
+    let contaminated = load_contaminated_ids(contaminates).unwrap();
+    let size_object = load_size_object(&sa.size)?;
+    check_size_object(&size_object, &sa)?;
+    let num_docs = size_object.len().saturating_sub(1);
+    let should_keep = |val_doc_id: usize| contaminated.contains(&val_doc_id) == invert;
+    info!("Loaded {:?} contaminated ids out of {:?} docs", contaminated.len(), num_docs);
+
+    let mut kept_docs = 0;
+    let mut removed_docs = 0;
+    let mut removed_bytes: u64 = 0;
+    if input.is_empty() {
+        // No source files, so the SA text is the only copy of the docs we have. Headers were put there by
+        // BuildSa, so only the bodies go out
+        let header_lens = load_header_lens(data_file, body_offsets.as_ref(), num_docs)?;
+        let mut output_bytes: Vec<u8> = Vec::new();
+        for val_doc_id in 0..num_docs {
+            let body_start = cmp::min(size_object[val_doc_id] + header_lens[val_doc_id], size_object[val_doc_id + 1]);
+            let doc_text = load_text_range(&sa.text, body_start, size_object[val_doc_id + 1]);
+            if should_keep(val_doc_id) {
+                output_bytes.extend(serde_json::to_vec(&json!({"text": String::from_utf8_lossy(&doc_text)})).unwrap());
+                output_bytes.push(b'\n');
+                kept_docs += 1;
+            } else {
+                removed_docs += 1;
+                removed_bytes += doc_text.len() as u64;
+            }
+        }
+        write_mem_to_pathbuf(&output_bytes, &output.clone().join("filtered.jsonl.gz")).unwrap();
+    } else {
+        // Doc ids are assigned sequentially over lines of the input files, sorted as BuildSa sorted them
+        let mut input_files = expand_dirs(input.clone(), None).unwrap();
+        input_files.sort();
+        let mut val_doc_id = 0;
+        for input_file in &input_files {
+            let mut output_bytes: Vec<u8> = Vec::new();
+            for line in read_pathbuf_streaming(input_file).unwrap().lines() {
+                let line = line.unwrap();
+                if should_keep(val_doc_id) {
+                    output_bytes.extend(line.as_bytes());
+                    output_bytes.push(b'\n');
+                    kept_docs += 1;
+                } else {
+                    removed_docs += 1;
+                    removed_bytes += line.len() as u64;
+                }
+                val_doc_id += 1;
+            }
+            write_mem_to_pathbuf(&output_bytes, &get_output_filename(input, input_file, output)).unwrap();
+        }
+        if val_doc_id != num_docs {
+            return Err(anyhow!("Inputs have {} docs but the size object has {}. Are these the files the SA was built from?",
+                               val_doc_id, num_docs));
+        }
+    }
+
+    info!("Kept {:?} docs, removed {:?} docs ({:?} bytes)", kept_docs, removed_docs, removed_bytes);
+    Ok(())
+}
+
+
+pub fn query_matches(args: &QueryMatchesArgs) -> Result<(), Error> {
+    // Lists the val docs that only train files matching train_path contaminate, i.e. the ones that would
+    // come out clean if those files were dropped from the trainset
+    let QueryMatchesArgs {contaminates, paths, train_path, data_file, sa_paths, body_offsets, output} = args;
+    let _span = info_span!("query_matches").entered();
+    let pattern = glob::Pattern::new(train_path).with_context(|| format!("Bad --train-path pattern {:?}", train_path))?;
+    let path_lookup = load_path_lookup(paths)?;
+    let queried: HashSet<usize> = path_lookup.iter().enumerate()
+        .filter(|(_, path)| pattern.matches_path(path))
+        .map(|(path_id, _)| path_id)
+        .collect();
+    if queried.is_empty() {
+        return Err(anyhow!("No train path in {:?} matches {:?}", paths, train_path));
+    }
+    info!("{:?} of {:?} train paths match {:?}", queried.len(), path_lookup.len(), train_path);
+
+    let mut by_val_doc: BTreeMap<usize, Vec<(usize, usize, f32)>> = BTreeMap::new();
+    let (contaminates_list, run_id) = load_contaminates_with_run(contaminates)?;
+    check_paths_run(paths, run_id.as_deref(), &format!("{:?}", contaminates))?;
+    for (val_doc_id, train_path_id, line_num, coverage) in contaminates_list {
+        by_val_doc.entry(val_doc_id).or_default().push((train_path_id, line_num, coverage));
+    }
+    if data_file.is_none() && sa_paths.is_set() {
+        return Err(anyhow!("--text/--table/--size only apply with --data-file"));
+    }
+    let sa: Option<SaFiles> = data_file.as_ref().map(|data_file| sa_paths.resolve(data_file)).transpose()?;
+    let size_object = match &sa {
+        Some(sa) => {
+            let size_object = load_size_object(&sa.size)?;
+            check_size_object(&size_object, sa)?;
+            Some(size_object)
+        },
+        None => None
+    };
+    let doc_names = match (data_file, &size_object) {
+        (Some(data_file), Some(size_object)) => load_doc_names(data_file, size_object.len() - 1)?,
+        _ => None
+    };
+    let header_lens: Option<Vec<u64>> = match (data_file, &size_object) {
+        (Some(data_file), Some(size_object)) => Some(load_header_lens(data_file, body_offsets.as_ref(), size_object.len() - 1)?),
+        _ => None
+    };
+    // Resolved once here rather than per doc, since it reads the SA meta to see whether the SA is case-insensitive
+    let text_file: Option<PathBuf> = sa.as_ref().map(display_text_file).transpose()?;
+
+    let mut output_bytes: Vec<u8> = Vec::new();
+    let mut num_exclusive = 0;
+    for (val_doc_id, train_lines) in by_val_doc.iter_mut() {
+        if !train_lines.iter().all(|(train_path_id, _, _)| queried.contains(train_path_id)) {
+            continue;
+        }
+        train_lines.sort_unstable_by_key(|&(train_path_id, line_num, _)| (train_path_id, line_num));
+        let mut record = json!({"val_doc_id": val_doc_id,
+                                "train_lines": train_lines.iter()
+                                    .map(|(train_path_id, line_num, coverage)| json!({"train_path": path_lookup[*train_path_id],
+                                                                                      "line_num": line_num,
+                                                                                      "coverage": f32_json(*coverage)}))
+                                    .collect::<Vec<Value>>()});
+        if let (Some(text_file), Some(size_object), Some(header_lens)) = (&text_file, &size_object, &header_lens) {
+            let doc_end = size_object[*val_doc_id + 1];
+            let body_start = cmp::min(size_object[*val_doc_id] + header_lens[*val_doc_id], doc_end);
+            let doc_text = load_text_range(text_file, body_start, doc_end);
+            record["text"] = json!(String::from_utf8_lossy(&doc_text));
+        }
+        add_doc_name(&mut record, &doc_names, *val_doc_id);
+        output_bytes.extend(serde_json::to_vec(&record)?);
+        output_bytes.push(b'\n');
+        num_exclusive += 1;
+    }
+    match output {
+        Some(output) => write_mem_to_pathbuf(&output_bytes, output)?,
+        None => std::io::stdout().write_all(&output_bytes)?
+    }
+    info!("{:?} of {:?} contaminated val docs are contaminated only by paths matching {:?}",
+          num_exclusive, by_val_doc.len(), train_path);
+    Ok(())
+}
+
+
+pub fn lookup(args: &LookupArgs) -> Result<(), Error> {
+    // Where one string occurs in the val set, searched for as build_matches would search a train window
+    let LookupArgs {data_file, sa_paths, query, query_file, limit, body_offsets, mmap, output} = args;
+    let sa = sa_paths.resolve(data_file)?;
+    let _span = info_span!("lookup").entered();
+    let query_text: String = match (query, query_file) {
+        (Some(query), _) => query.clone(),
+        (None, Some(query_file)) => std::fs::read_to_string(query_file)
+            .with_context(|| format!("Failed to read {:?} as UTF-8", query_file))?,
+        (None, None) => return Err(anyhow!("Give the query with --query or --query-file"))
+    };
+    let sa_encoder = SaTextEncoder::load(data_file)?;
+    let (query_bytes, unit_width) = (sa_encoder.encode(&query_text)?, sa_encoder.unit_width());
+    if query_bytes.is_empty() {
+        return Err(anyhow!("The query is empty"));
+    }
+    let shards = load_sa_shards(&sa, *mmap).with_context(|| format!("Failed to load SA at {:?}", data_file))?;
+    let size_object = load_size_object(&sa.size)?;
+    check_size_object(&size_object, &sa)?;
+    let doc_names = load_doc_names(data_file, size_object.len() - 1)?;
+    let header_lens = load_header_lens(data_file, body_offsets.as_ref(), size_object.len() - 1)?;
+
+    // Hits that start mid-token aren't token matches, same as in collect_matches. Ones wholly inside a doc's
+    // header aren't in the val text proper, same as in mark_contaminates
+    let mut positions: Vec<u64> = get_occurrences_sharded(&shards, &query_bytes, None).into_iter().flatten()
+        .filter(|pos| pos % (unit_width as u64) == 0)
+        .collect();
+    positions.sort_unstable();
+    let (positions, doc_ids): (Vec<u64>, Vec<usize>) = positions.into_iter()
+        .map(|pos| (pos, doc_lookup(pos, &size_object)))
+        .filter(|(pos, doc_id)| pos - size_object[*doc_id] + query_bytes.len() as u64 > header_lens[*doc_id])
+        .unzip();
+    let mut output_bytes: Vec<u8> = Vec::new();
+    for (pos, doc_id) in positions.iter().zip(&doc_ids).take(*limit) {
+        let doc_offset = pos - size_object[*doc_id];
+        let mut record = json!({"pos": pos, "val_doc_id": doc_id, "doc_offset": doc_offset,
+                                "body_offset": doc_offset.saturating_sub(header_lens[*doc_id])});
+        add_doc_name(&mut record, &doc_names, *doc_id);
+        output_bytes.extend(serde_json::to_vec(&record)?);
+        output_bytes.push(b'\n');
+    }
+    match output {
+        Some(output) => write_mem_to_pathbuf(&output_bytes, output)?,
+        None => std::io::stdout().write_all(&output_bytes)?
+    }
+    let num_docs = doc_ids.iter().collect::<HashSet<_>>().len();
+    info!("{:?} occurrences of the {:?}-byte query in {:?} val docs", positions.len(), query_bytes.len(), num_docs);
+    if positions.len() > *limit {
+        info!("Listed the first {:?}, raise --limit to see the rest", limit);
+    }
+    Ok(())
+}
+
+
+fn sweep_covered(offsets: &[TrainOffset], unit_width: usize, min_size: usize, match_sizes: &[usize],
+                 size_object: &Vec<u64>, header_lens: &[u64]) -> Vec<HashMap<usize, usize>> {
+    // For each of match_sizes, the most body bytes of each val doc that any one train line covers, as
+    // mark_contaminates would measure it had the matches been built at that size.
+    // Matches at min_size along one diagonal (val pos - train pos) of a train line, one unit apart, chain into
+    // a common substring of the line and the val text. A window of any size k fits in there exactly when the
+    // substring is at least k long, and those windows cover all of it, so one pass at the smallest size gives
+    // every larger one
+    let mut diagonals: Vec<(usize, usize, i64, u64)> = offsets.par_iter()
+        .map(|&(path_id, line_num, sa_pos, train_pos, _)| (path_id, line_num, sa_pos as i64 - train_pos as i64, train_pos as u64))
+        .collect();
+    diagonals.par_sort_unstable();
+    diagonals.dedup();
+    let train_lines: Vec<&[(usize, usize, i64, u64)]> = diagonals.chunk_by(|a, b| (a.0, a.1) == (b.0, b.1)).collect();
+    let unit = unit_width as u64;
+    let line_covered: Vec<(usize, usize, usize)> = train_lines.into_par_iter()
+        .flat_map_iter(|line_diagonals| {
+            // Common substrings as (val_doc_id, start, end) in doc offsets, split where they cross into the next doc
+            let mut pieces: Vec<(usize, u64, u64)> = Vec::new();
+            for run in line_diagonals.chunk_by(|a, b| a.2 == b.2 && a.3 + unit == b.3) {
+                let start = (run[0].2 + run[0].3 as i64) as u64;
+                let end = start + (run.len() as u64 - 1) * unit + (min_size as u64) * unit;
+                let mut piece_start = start;
+                let mut val_doc_id = doc_lookup(start, size_object);
+                while piece_start < end && val_doc_id + 1 < size_object.len() {
+                    let doc_end = size_object[val_doc_id + 1];
+                    pieces.push((val_doc_id, piece_start - size_object[val_doc_id], cmp::min(end, doc_end) - size_object[val_doc_id]));
+                    piece_start = doc_end;
+                    val_doc_id += 1;
+                }
+            }
+            pieces.sort_unstable();
+            let mut covered: Vec<(usize, usize, usize)> = Vec::new();
+            for (size_idx, match_size) in match_sizes.iter().enumerate() {
+                let width = (*match_size * unit_width) as u64;
+                for doc_pieces in pieces.chunk_by(|a, b| a.0 == b.0) {
+                    let val_doc_id = doc_pieces[0].0;
+                    let header_len = header_lens[val_doc_id];
+                    let intervals: Vec<(usize, usize)> = doc_pieces.iter()
+                        .filter(|(_, start, end)| end - start >= width)
+                        .map(|(_, start, end)| (cmp::max(*start, header_len), *end))
+                        .filter(|(start, end)| start < end)
+                        .map(|(start, end)| ((start - header_len) as usize, (end - header_len) as usize))
+                        .collect();
+                    let doc_covered: usize = _merge_intervals(intervals, true, 0, MergeAdjacency::Touching).iter()
+                        .map(|(start, end)| end - start)
+                        .sum();
+                    if doc_covered > 0 {
+                        covered.push((size_idx, val_doc_id, doc_covered));
+                    }
+                }
+            }
+            covered
+        })
+        .collect();
+    let mut best: Vec<HashMap<usize, usize>> = match_sizes.iter().map(|_| HashMap::new()).collect();
+    for (size_idx, val_doc_id, doc_covered) in line_covered {
+        let doc_best = best[size_idx].entry(val_doc_id).or_insert(0);
+        *doc_best = cmp::max(*doc_best, doc_covered);
+    }
+    best
+}
+
+
+pub fn sweep(args: &SweepArgs) -> Result<(), Error> {
+    // Contamination rates over a match_size x threshold grid, from one build_matches-style pass over a
+    // sample of the trainset at the smallest match_size
+    let SweepArgs {data_file, sa_paths, trainset, sample_files, match_size, threshold, encoding, body_offsets, mmap, output} = args;
+    let sa = sa_paths.resolve(data_file)?;
+    let _span = info_span!("sweep").entered();
+    let mut match_sizes = match_size.clone();
+    match_sizes.sort_unstable();
+    match_sizes.dedup();
+    let min_size = match_sizes[0];
+    if min_size == 0 {
+        return Err(anyhow!("--match-size must be at least 1"));
+    }
+    if let Some(bad) = threshold.iter().find(|t| !(0.0..=1.0).contains(*t)) {
+        return Err(anyhow!("--threshold {} isn't a fraction between 0 and 1", bad));
+    }
+
+    // Phase 0: Windows are cut the way the SA's text was built
+    let sa_meta = load_sa_meta(data_file)?;
+    let encoder: Option<TokenEncoder> = match sa_meta["tokenization"]["mode"].as_str() {
+        Some("tokens") => {
+            let tokenizer = sa_meta["tokenization"]["tokenizer"].as_str()
+                .ok_or_else(|| anyhow!("{:?} was tokenized but doesn't record its tokenizer", data_file))?;
+            Some(TokenEncoder::load(&PathBuf::from(tokenizer))?)
+        },
+        Some("input_ids") => {
+            let field = sa_meta["tokenization"]["input_ids_field"].as_str()
+                .ok_or_else(|| anyhow!("{:?} was built from input ids but doesn't record their field", data_file))?;
+            let token_width = sa_meta["tokenization"]["token_width"].as_u64()
+                .ok_or_else(|| anyhow!("{:?} was built from input ids but doesn't record their width", data_file))?;
+            Some(TokenEncoder::input_ids(field, token_width as usize)?)
+        },
+        _ => None
+    };
+    let unit_width = encoder.as_ref().map_or(1, |e| e.token_width);
+    let text_fields: Vec<String> = match &sa_meta["text_fields"] {
+        Value::Array(fields) => fields.iter().filter_map(|f| f.as_str().map(String::from)).collect(),
+        _ => vec!["text".to_string()]
+    };
+    let field_separator = sa_meta["field_separator"].as_str().unwrap_or("\n\n").to_string();
+    let normalize = parse_normalization(&load_sa_normalization(data_file)?)?;
+    let raw_lines = sa_meta["raw_lines"].as_bool().unwrap_or(false);
+    let mut input_files = expand_dirs(trainset.clone(), raw_lines.then_some(RAW_LINE_EXTS))?;
+    input_files.sort();
+    if let Some(num_samples) = sample_files.filter(|n| *n < input_files.len()) {
+        if num_samples == 0 {
+            return Err(anyhow!("--sample-files must be at least 1"));
+        }
+        let num_files = input_files.len();
+        input_files = (0..num_samples).map(|i| input_files[i * num_files / num_samples].clone()).collect();
+    }
+    info!("Sweeping {:?} match sizes and {:?} thresholds over {:?} train files", match_sizes.len(), threshold.len(),
+          input_files.len());
+    let shards = load_sa_shards(&sa, *mmap).with_context(|| format!("Failed to load SA at {:?}", data_file))?;
+    let sas = vec![LoadedSa {shards: shards.into_iter().map(|shard| (shard, None)).collect()}];
+    let size_object = load_size_object(&sa.size)?;
+    check_size_object(&size_object, &sa)?;
+    let num_docs = size_object.len().saturating_sub(1);
+    // Coverage is of doc bodies, as in mark_contaminates
+    let header_lens = load_header_lens(data_file, body_offsets.as_ref(), num_docs)?;
+
+    // Phase 1: Every occurrence of every window at the smallest size, with where it sits in its train line
+    let match_params = MatchParams {match_size: min_size, text_fields: &text_fields, field_separator: &field_separator,
+                                    raw_lines, doc_per_file: false, json_string_as_text: false, encoding: *encoding, encoder: &encoder, char_windows: false,
+                                    normalize: &normalize, case_insensitive: sa_case_insensitive(data_file)?, stride: 1, sample_rate: 1.0,
+                                    skip_errors: true, max_occurrences: None, min_doc_len: 0, max_matches_per_doc: None,
+                                    max_line_bytes: None, long_lines: LongLineMode::Truncate, train_offsets: true,
+                                    occurrence_histogram: false};
+    let pbar = build_pbar(input_files.len(), "Paths");
+    let offsets: Vec<TrainOffset> = input_files.par_iter().enumerate()
+        .map(|(path_idx, path)| {
+            let (_, mut offsets, _) = collect_matches(path, path_idx, &sas, &match_params)?;
+            pbar.inc(1);
+            Ok(offsets.pop().unwrap_or_default())
+        })
+        .collect::<Result<Vec<_>, Error>>()?
+        .into_iter().flatten().collect();
+    info!("Collected {:?} matches at match_size {:?}", offsets.len(), min_size);
+
+    // Phase 2: Score the grid
+    let best = sweep_covered(&offsets, unit_width, min_size, &match_sizes, &size_object, &header_lens);
+    let mut output_bytes: Vec<u8> = b"match_size,threshold,contaminated_docs,num_docs,contamination_rate\n".to_vec();
+    for (size, doc_covered) in match_sizes.iter().zip(&best) {
+        for fraction in threshold {
+            // The same rounding as mark_contaminates' threshold check, over each doc's body
+            let num_contaminated = doc_covered.iter()
+                .filter(|(val_doc_id, covered)| {
+                    let body_size = (size_object[**val_doc_id + 1] - size_object[**val_doc_id]).saturating_sub(header_lens[**val_doc_id]);
+                    body_size > 0 && **covered >= ((body_size as f64) * fraction).ceil() as usize
+                })
+                .count();
+            output_bytes.extend(format!("{},{},{},{},{}\n", size, fraction, num_contaminated, num_docs,
+                                        num_contaminated as f64 / cmp::max(num_docs, 1) as f64).into_bytes());
+        }
+    }
+    match output {
+        Some(output) => write_mem_to_pathbuf(&output_bytes, output)?,
+        None => std::io::stdout().write_all(&output_bytes)?
+    }
+    Ok(())
+}
+
+
+pub fn bench(args: &BenchArgs) -> Result<(), Error> {
+    // Times lookups alone, one at a time so each latency is a single binary search (plus reading its positions)
+    let &BenchArgs {ref data_file, ref sa_paths, match_size, num_queries, mmap, seed} = args;
+    let sa = sa_paths.resolve(data_file)?;
+    let _span = info_span!("bench").entered();
+    let load_start = Instant::now();
+    let (text, size_text, table, size_table, size_width) = load_sa(&sa.text, &sa.table, mmap)
+        .with_context(|| format!("Failed to load SA at {:?}", data_file))?;
+    info!("Loaded {:?} ({:?} bytes, {:?}-byte table entries) {} in {:.3}s", data_file, size_text, size_width,
+          if mmap { "with mmap" } else { "into memory" }, load_start.elapsed().as_secs_f64());
+    if match_size == 0 || (size_text as usize) < match_size {
+        return Err(anyhow!("--match-size must be between 1 and the text size ({} bytes)", size_text));
+    }
+
+    // Random bytes almost never occur, so they time the full search for a miss. Sampled windows always
+    // occur at least once, so they also time reading positions back
+    let mut rng = StdRng::seed_from_u64(seed);
+    let random_queries: Vec<Vec<u8>> = (0..num_queries)
+        .map(|_| (0..match_size).map(|_| rng.gen::<u8>()).collect())
+        .collect();
+    let sampled_queries: Vec<Vec<u8>> = (0..num_queries)
+        .map(|_| {
+            let start = rng.gen_range(0..=size_text as usize - match_size);
+            text[start..start + match_size].to_vec()
+        })
+        .collect();
+
+    for (name, queries) in [("random", random_queries), ("sampled", sampled_queries)] {
+        let mut latencies: Vec<f64> = Vec::with_capacity(queries.len());
+        let mut total_occurrences: usize = 0;
+        let run_start = Instant::now();
+        for query in &queries {
+            let query_start = Instant::now();
+            total_occurrences += get_occurrences_memory(&text, size_text, &table, size_table, query, size_width).count();
+            latencies.push(query_start.elapsed().as_secs_f64() * 1e6);
+        }
+        let run_secs = run_start.elapsed().as_secs_f64();
+        latencies.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: usize| latencies.get(cmp::min(latencies.len() * p / 100, latencies.len().saturating_sub(1)))
+            .copied().unwrap_or(0.0);
+        info!("{}: {:.0} queries/sec, {:.2} mean occurrences per query, p50 {:.2}us, p99 {:.2}us",
+              name, queries.len() as f64 / run_secs.max(f64::EPSILON), total_occurrences as f64 / cmp::max(queries.len(), 1) as f64,
+              percentile(50), percentile(99));
+    }
+    Ok(())
+}
+
+
+pub fn verify_sa(args: &VerifySaArgs) -> Result<(), Error> {
+    // Stops at the first problem, cheapest checks first: the size object, then that the table is a
+    // permutation of the text offsets, then that suffixes are in order
+    let &VerifySaArgs {ref data_file, ref sa_paths, full, samples, mmap, seed} = args;
+    let sa = sa_paths.resolve(data_file)?;
+    let _span = info_span!("verify_sa").entered();
+    let (text, size_text, table, _, size_width) = load_sa(&sa.text, &sa.table, mmap)
+        .with_context(|| format!("Failed to load SA at {:?}", data_file))?;
+    let num_entries = size_text as usize;
+    info!("Loaded {:?} ({:?} bytes, {:?}-byte table entries)", data_file, size_text, size_width);
+
+    let size_path = sa.size.clone();
+    let size_object = load_size_object(&size_path)?;
+    if size_object.first() != Some(&0) {
+        return Err(anyhow!("Size object {:?} is empty or doesn't start at offset 0", size_path));
+    }
+    if let Some(bad) = size_object.windows(2).position(|w| w[1] < w[0]) {
+        return Err(anyhow!("Size object goes backwards at index {}: {} then {}", bad + 1, size_object[bad], size_object[bad + 1]));
+    }
+    let size_end = size_object[size_object.len() - 1];
+    if size_end != size_text {
+        return Err(anyhow!("Size object ends at offset {} but the text is {} bytes", size_end, size_text));
+    }
+    info!("Size object OK: {:?} docs", size_object.len() - 1);
+
+    let entry = |i: usize| {
+        let mut tmp = [0u8; 8];
+        tmp[..size_width].copy_from_slice(&table[i * size_width..(i + 1) * size_width]);
+        u64::from_le_bytes(tmp) as usize
+    };
+    let mut seen: Vec<u64> = vec![0; num_entries.div_ceil(64)];
+    for i in 0..num_entries {
+        let offset = entry(i);
+        if offset >= num_entries {
+            return Err(anyhow!("table[{}] is {}, past the end of the {}-byte text", i, offset, size_text));
+        }
+        if seen[offset / 64] & (1 << (offset % 64)) != 0 {
+            return Err(anyhow!("table[{}] is {}, which already appeared earlier in the table", i, offset));
+        }
+        seen[offset / 64] |= 1 << (offset % 64);
+    }
+    info!("Table OK: every offset in 0..{:?} appears exactly once", size_text);
+
+    let out_of_order = |i: &usize| text[entry(*i)..] > text[entry(*i + 1)..];
+    let num_pairs = num_entries.saturating_sub(1);
+    let first_bad = match full || samples >= num_pairs {
+        true => (0..num_pairs).into_par_iter().find_first(out_of_order),
+        false => {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut sampled: Vec<usize> = (0..samples).map(|_| rng.gen_range(0..num_pairs)).collect();
+            sampled.par_sort_unstable();
+            sampled.into_par_iter().find_first(out_of_order)
+        }
+    };
+    if let Some(bad) = first_bad {
+        return Err(anyhow!("Suffixes out of order at table[{}] (offset {}) and table[{}] (offset {})",
+                           bad, entry(bad), bad + 1, entry(bad + 1)));
+    }
+    info!("Order OK: {} adjacent suffix pairs in sorted order",
+          if full || samples >= num_pairs { format!("all {}", num_pairs) } else { format!("{} sampled", samples) });
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_test_sa(name: &str, docs: &[&str]) -> PathBuf {
+        // A fresh single-shard SA over docs, with no meta, in its own temp dir
+        let dir = std::env::temp_dir().join(format!("sa_decontamination_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_file = dir.join("val.bin");
+        let text: Vec<u8> = docs.concat().into_bytes();
+        let sizes: Vec<u64> = std::iter::once(0).chain(docs.iter().scan(0, |end, doc| {
+            *end += doc.len() as u64;
+            Some(*end)
+        })).collect();
+        write_sa(&data_file, &text, &sizes, &json!({})).unwrap();
+        data_file
+    }
+
+    fn coverage_threshold(fraction: f64) -> ContaminationThreshold {
+        ContaminationThreshold {fraction: Some(fraction), min_overlap_bytes: None, min_distinct_windows: None,
+                                mode: ThresholdMode::And, metric: MatchMetric::Coverage, gap_tolerance: 0, min_span: None,
+                                adjacency: MergeAdjacency::Touching, by_length: Vec::new(), ignore_phrases: None,
+                                max_starts: None, score_all: false}
+    }
+
+    fn test_match_params(match_size: usize, text_fields: &[String]) -> MatchParams<'_> {
+        // Plain utf-8 byte windows over text_fields with nothing filtered or sampled, as build_matches' defaults
+        MatchParams {match_size, text_fields, field_separator: "\n\n", raw_lines: false, doc_per_file: false,
+                     json_string_as_text: false, encoding: TextEncoding::Utf8, encoder: &None, char_windows: false, normalize: &[],
+                     case_insensitive: false, stride: 1, sample_rate: 1.0, skip_errors: false, max_occurrences: None,
+                     min_doc_len: 0, max_matches_per_doc: None, max_line_bytes: None, long_lines: LongLineMode::Truncate,
+                     train_offsets: false, occurrence_histogram: false}
+    }
+
+    fn parse_args<T: Args + clap::FromArgMatches>(argv: &[&str]) -> T {
+        // A subcommand's args as clap would parse them, defaults and all
+        let matches = T::augment_args(clap::Command::new("test")).get_matches_from(argv);
+        T::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn sa_path_overrides_apply_per_call() {
+        let data_file = PathBuf::from("val/sa.bin");
+        let overrides = SaPathArgs {text: Some(PathBuf::from("elsewhere/text.bin")), table: None, size: None};
+        let sa = overrides.resolve(&data_file).unwrap();
+        assert_eq!((sa.text, sa.table), (PathBuf::from("elsewhere/text.bin"), PathBuf::from("val/sa.bin.table.bin")));
+        // A later call without overrides gets the usual paths, not the earlier call's
+        let sa = SaPathArgs::default().resolve(&data_file).unwrap();
+        assert_eq!((sa.text, sa.size), (data_file.clone(), PathBuf::from("val/sa.bin.size")));
+    }
+
+    #[test]
+    fn repeated_starts_keep_coverage() {
+        let window = WindowSpec::Bytes(4);
+        let threshold = coverage_threshold(0.3);
+        let distinct = _check_threshold(&[0, 4, 8], &window, &[], 40, 0, 0, &threshold);
+        let repeated = _check_threshold(&[8, 0, 4, 0, 8, 4, 4], &window, &[], 40, 0, 0, &threshold);
+        assert_eq!(distinct, Some(0.3));
+        assert_eq!(repeated, distinct);
+        // Repeats can't lift a group over the threshold either
+        let threshold = coverage_threshold(0.5);
+        assert_eq!(_check_threshold(&[0, 0, 0, 0, 0, 0], &window, &[], 40, 0, 0, &threshold), None);
+    }
+
+    #[test]
+    fn max_group_starts_caps_and_counts() {
+        let window = WindowSpec::Bytes(4);
+        let mut threshold = coverage_threshold(0.1);
+        threshold.max_starts = Some(2);
+        let score = _score_group(&[0, 4, 8], &window, &[], 40, 0, 0, &threshold);
+        assert!(score.capped && score.passes);
+        assert_eq!(score.fraction, Some(0.2));
+        // Repeats are dropped before capping, so they don't count towards it
+        assert!(!_score_group(&[0, 0, 4, 4], &window, &[], 40, 0, 0, &threshold).capped);
+        let (over, under): (Vec<u64>, Vec<u64>) = (vec![0, 4, 8, 12], vec![20, 24]);
+        let doc_matches: Vec<((usize, usize), &[u64])> = vec![((0, 0), &over), ((0, 1), &under), ((1, 0), &over)];
+        let doc_score = merge_matches(3, &doc_matches, &window, &[], 40, 0, &threshold).unwrap();
+        assert_eq!(doc_score.capped_groups, 2);
+        assert_eq!(doc_score.contaminates, vec![(3, 0, 0, 0.2), (3, 0, 1, 0.2), (3, 1, 0, 0.2)]);
+    }
+
+    #[test]
+    fn verdict_fraction_is_capped_and_filtered() {
+        // A train line that can't pass still gets its fraction with score_all, worked out the way thresholding does
+        let window = WindowSpec::Bytes(4);
+        let mut threshold = coverage_threshold(0.9);
+        threshold.max_starts = Some(2);
+        threshold.min_span = Some(8);
+        let (capped, short): (Vec<u64>, Vec<u64>) = (vec![0, 4, 8, 12], vec![20, 30]);
+        let doc_matches: Vec<((usize, usize), &[u64])> = vec![((0, 0), &capped), ((0, 1), &short)];
+        assert_eq!(merge_matches(0, &doc_matches, &window, &[], 40, 0, &threshold).unwrap().max_fraction, 0.0);
+        threshold.score_all = true;
+        let doc_score = merge_matches(0, &doc_matches, &window, &[], 40, 0, &threshold).unwrap();
+        // Only the first two starts count, and the isolated windows are under --min-span
+        assert_eq!(doc_score.max_fraction, 0.2);
+        assert!(doc_score.contaminates.is_empty());
+    }
+
+    #[test]
+    fn ignored_intervals_count_once_per_doc() {
+        let window = WindowSpec::Bytes(4);
+        let doc_text = b"LICENSE ABCD and then the question itself";
+        let mut threshold = coverage_threshold(0.2);
+        threshold.ignore_phrases = Some(Arc::new(IgnorePhrases {phrases: vec![b"THE LICENSE ABCD".to_vec()], unit_width: 1}));
+        let (phrase, question): (Vec<u64>, Vec<u64>) = (vec![0, 4, 8], vec![0, 4, 8, 17, 21, 25]);
+        let doc_matches: Vec<((usize, usize), &[u64])> = vec![((0, 0), &phrase), ((0, 1), &phrase), ((1, 0), &question)];
+        let doc_score = merge_matches(0, &doc_matches, &window, doc_text, doc_text.len(), 0, &threshold).unwrap();
+        // Three train lines turn up [0, 12), but it's one interval of the doc
+        assert_eq!(doc_score.ignored_intervals, 1);
+        assert_eq!(doc_score.contaminates, vec![(0, 1, 0, (12.0 / doc_text.len() as f64) as f32)]);
+    }
+
+    #[test]
+    fn done_set_matches_sums_per_set() {
+        let progress_path = PathBuf::from("progress.json");
+        let done = json!({"a": {"num_matches": [3, 1]}, "b": {"num_matches": [2, 0]}});
+        assert_eq!(done_set_matches(done.as_object().unwrap(), 2, &progress_path).unwrap(), vec![5, 1]);
+        // A single count from before there were sets is the one set's, and can't be split between several
+        let old = json!({"a": {"num_matches": 4}, "b": {"num_matches": 2}});
+        assert_eq!(done_set_matches(old.as_object().unwrap(), 1, &progress_path).unwrap(), vec![6]);
+        assert!(done_set_matches(old.as_object().unwrap(), 2, &progress_path).is_err());
+        assert!(done_set_matches(done.as_object().unwrap(), 3, &progress_path).is_err());
+    }
+
+    #[test]
+    fn spill_runs_merge_in_order_and_clean_up() {
+        let parent = std::env::temp_dir();
+        let mut spill_runs = SpillRuns::new(parent.clone()).unwrap();
+        let other = SpillRuns::new(parent).unwrap();
+        assert_ne!(spill_runs.dir.0, other.dir.0);
+        let dir = spill_runs.dir.0.clone();
+        spill_runs.extend(vec![(2, 0, 0, 5), (0, 1, 0, 3)].into_par_iter()).unwrap();
+        spill_runs.spill().unwrap();
+        spill_runs.extend(vec![(1, 0, 0, 0), (0, 0, 0, 9)].into_par_iter()).unwrap();
+        let merged: Vec<_> = spill_runs.merge().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(merged, vec![(0, 0, 0, 9), (0, 1, 0, 3), (1, 0, 0, 0), (2, 0, 0, 5)]);
+        assert!(!dir.exists());
+        // Dropped without merging (as when scoring errors out) still removes it
+        let other_dir = other.dir.0.clone();
+        drop(other);
+        assert!(!other_dir.exists());
+    }
+
+    #[test]
+    fn source_contaminates_splits_by_tag_range() {
+        // Tag a owns two ranges, b one, and doc 9 is in no range
+        let sources = vec![("b".to_string(), 3, 6), ("a".to_string(), 0, 3), ("a".to_string(), 6, 9)];
+        let tags = vec!["a".to_string(), "b".to_string()];
+        let contaminates: Vec<Contaminate> = vec![(0, 0, 0, 1.0), (2, 1, 0, 0.5), (3, 0, 1, 0.9), (5, 0, 0, 1.0),
+                                                  (7, 2, 2, 0.6), (9, 0, 0, 1.0)];
+        let by_tag = source_contaminates(&contaminates, &sources, &tags);
+        assert_eq!(by_tag, vec![vec![(0, 0, 0, 1.0), (2, 1, 0, 0.5), (7, 2, 2, 0.6)], vec![(3, 0, 1, 0.9), (5, 0, 0, 1.0)]]);
+        let report = build_source_report(&by_tag, &sources, &tags);
+        assert_eq!(report["a"]["val_docs"], 6);
+        assert_eq!(report["a"]["contaminated_docs"], 3);
+        assert_eq!(report["b"]["contaminates"], 2);
+    }
+
+    #[test]
+    fn overlap_histogram_is_an_array_by_train_files() {
+        let contaminates: Vec<Contaminate> = vec![(0, 0, 0, 1.0), (0, 0, 1, 1.0), (0, 2, 0, 1.0), (1, 1, 0, 1.0),
+                                                  (2, 10, 0, 1.0), (2, 11, 0, 1.0)];
+        let report = build_overlap_report(&contaminates, &None);
+        assert_eq!(report["contaminated_docs"], 3);
+        assert_eq!(report["histogram"], json!([{"train_files": 1, "val_docs": 1}, {"train_files": 2, "val_docs": 2}]));
+        assert_eq!(report["docs"][0]["train_files"], 2);
+    }
+
+    #[test]
+    fn benchmark_ranges_follow_their_data_file_id() {
+        let benchmark_map = std::env::temp_dir().join(format!("sa_decontamination_benchmarks_{}.json", std::process::id()));
+        std::fs::write(&benchmark_map, br#"{"plain": [0, 2], "second": {"data_file_id": 1, "val_docs": [0, 3]}}"#).unwrap();
+        assert_eq!(load_benchmark_ranges(&benchmark_map, 0).unwrap(), vec![("plain".to_string(), 0, 2)]);
+        let ranges = load_benchmark_ranges(&benchmark_map, 1).unwrap();
+        assert_eq!(ranges, vec![("second".to_string(), 0, 3)]);
+        let contaminates: Vec<Contaminate> = vec![(0, 0, 0, 1.0), (2, 0, 0, 1.0), (2, 1, 4, 1.0), (5, 0, 0, 1.0)];
+        let report = build_benchmark_report(&contaminates, ranges).unwrap();
+        assert_eq!(report["second"], json!({"total_items": 3, "compromised_items": 2, "contaminating_train_lines": 2}));
+        std::fs::write(&benchmark_map, br#"{"broken": {"val_docs": [0, 3]}}"#).unwrap();
+        assert!(load_benchmark_ranges(&benchmark_map, 0).is_err());
+        std::fs::remove_file(&benchmark_map).unwrap();
+    }
+
+    #[test]
+    fn header_region_matches_only_count_their_body_part() {
+        // A 6-byte header then a 10-byte body. The window at 0 is all header, the one at 4 runs 2 bytes into the body
+        let window = WindowSpec::Bytes(4);
+        let doc_text = b"TITLE:0123456789";
+        let starts: Vec<u64> = vec![0, 4, 10];
+        assert_eq!(_body_intervals(starts.iter(), true, &window, doc_text, 6, 0, MergeAdjacency::Touching), vec![(0, 2), (4, 8)]);
+        let score = _score_group(&starts, &window, doc_text, doc_text.len(), 6, 0, &coverage_threshold(0.5));
+        assert_eq!(score.fraction, Some(0.6));
+        // Only starts in the body count as distinct windows
+        let mut threshold = coverage_threshold(0.0);
+        threshold.min_distinct_windows = Some(2);
+        assert!(!_score_group(&starts, &window, doc_text, doc_text.len(), 6, 0, &threshold).passes);
+    }
+
+    #[test]
+    fn short_body_offsets_are_an_error() {
+        let data_file = std::env::temp_dir().join(format!("sa_decontamination_headers_{}.bin", std::process::id()));
+        let body_offsets = body_offsets_path(&data_file);
+        std::fs::write(&body_offsets, [6u64, 0].iter().flat_map(|len| len.to_ne_bytes()).collect::<Vec<u8>>()).unwrap();
+        assert_eq!(load_header_lens(&data_file, Some(&body_offsets), 2).unwrap(), vec![6, 0]);
+        assert!(load_header_lens(&data_file, Some(&body_offsets), 3).is_err());
+        assert_eq!(load_header_lens(&data_file, None, 3).unwrap(), vec![0; 3]);
+        std::fs::remove_file(&body_offsets).unwrap();
+    }
+
+    #[test]
+    fn byte_matches_are_refused_for_a_token_sa() {
+        let data_file = std::env::temp_dir().join(format!("sa_decontamination_tokens_{}.bin", std::process::id()));
+        std::fs::write(sa_meta_path(&data_file), serde_json::to_vec(&json!({"tokenization": {"mode": "tokens", "token_width": 2}})).unwrap()).unwrap();
+        let token_meta = Some(json!({"tokenization": {"mode": "tokens", "tokenizer": "tok.json", "token_width": 2}}));
+        let id_meta = Some(json!({"tokenization": {"mode": "input_ids", "input_ids_field": "ids", "token_width": 2}}));
+        assert!(check_match_tokenization(&data_file, &token_meta).is_ok());
+        assert!(check_match_tokenization(&data_file, &id_meta).is_ok());
+        assert!(check_match_tokenization(&data_file, &Some(json!({"tokenization": {"mode": "bytes"}}))).is_err());
+        assert!(check_match_tokenization(&data_file, &Some(json!({"tokenization": {"mode": "tokens", "token_width": 4}}))).is_err());
+        // Matches from before tokenization was recorded are taken on trust
+        assert!(check_match_tokenization(&data_file, &None).is_ok());
+        std::fs::remove_file(sa_meta_path(&data_file)).unwrap();
+    }
+
+    #[test]
+    fn is_contaminated_over_a_loaded_sa() {
+        let data_file = write_test_sa("is_contaminated", &["the quick brown fox jumps", "over the lazy dog"]);
+        let sa = LoadedSa::load(&data_file, false).unwrap();
+        // Wholly in the SA
+        assert_eq!(is_contaminated(&sa, b"quick brown fox", 8, 0.8), (true, 1.0));
+        // Half of it: "quick brown " is in the SA and the rest isn't
+        let (contaminated, coverage) = is_contaminated(&sa, b"quick brown zzzzzzzzzzzz", 8, 0.8);
+        assert!(!contaminated);
+        assert_eq!(coverage, 0.5);
+        assert_eq!(is_contaminated(&sa, b"quick brown zzzzzzzzzzzz", 8, 0.5), (true, 0.5));
+        // Not in the SA at all
+        assert_eq!(is_contaminated(&sa, b"nothing like it here", 8, 0.1), (false, 0.0));
+        // Shorter than match_size, so no windows, even though it's in the SA
+        assert_eq!(is_contaminated(&sa, b"fox", 8, 0.1), (false, 0.0));
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn window_sampling_is_deterministic_per_path() {
+        let sampled = |path: &str| -> Vec<bool> {
+            let seed = path_sample_seed(&PathBuf::from(path));
+            (0..2000).map(|query_idx| sampled_window(seed, query_idx / 100, query_idx % 100, 0.25)).collect()
+        };
+        let kept = sampled("train/shard_00.jsonl.gz");
+        // The same path keeps the same windows every time, wherever it falls in the trainset
+        assert_eq!(kept, sampled("train/shard_00.jsonl.gz"));
+        assert_ne!(kept, sampled("train/shard_01.jsonl.gz"));
+        // And about sample_rate of them
+        let rate = kept.iter().filter(|k| **k).count() as f64 / kept.len() as f64;
+        assert!((rate - 0.25).abs() < 0.05, "kept {}", rate);
+    }
+
+    #[test]
+    fn line_nums_survive_a_bom_blank_lines_and_no_final_newline() {
+        let data_file = write_test_sa("line_nums", &["alpha beta gamma delta"]);
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["text".to_string()];
+        let params = test_match_params(8, &text_fields);
+        let train = "\u{feff}{\"text\": \"alpha beta\"}\n\n   \n{\"text\": \"nothing here\"}\n{\"text\": \"gamma delta\"}";
+        let path = PathBuf::from("train.jsonl");
+        let (matches, _, stats) = collect_matches_from(Box::new(Cursor::new(train.as_bytes().to_vec())), &path, 3, &sas, &params).unwrap();
+        // The BOM line is still line 0 and the blank ones still count, so the last record is line 4
+        let mut lines: Vec<(usize, usize)> = matches[0].iter().map(|&(path_idx, line_num, _)| (path_idx, line_num)).collect();
+        lines.dedup();
+        assert_eq!(lines, vec![(3, 0), (3, 4)]);
+        assert_eq!(stats.blank, 2);
+        assert_eq!(stats.malformed, 0);
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn strided_starts_merge_to_a_lower_bound() {
+        // Stride 2 under a 4-byte window still tiles [0, 10) with no gaps, the same as stride 1 would
+        let intervals = |starts: &[usize]| starts.iter().map(|s| (*s, s + 4)).collect::<Vec<_>>();
+        let stride_1 = _merge_intervals(intervals(&[0, 1, 2, 3, 4, 5, 6]), true, 0, MergeAdjacency::Touching);
+        assert_eq!(stride_1, vec![(0, 10)]);
+        assert_eq!(_merge_intervals(intervals(&[0, 2, 4, 6]), true, 0, MergeAdjacency::Touching), stride_1);
+        // A stride past the window leaves gaps, so the same match covers less than it really does
+        let stride_5 = _merge_intervals(intervals(&[0, 5]), true, 0, MergeAdjacency::Touching);
+        assert_eq!(stride_5, vec![(0, 4), (5, 9)]);
+        let window = WindowSpec::Bytes(4);
+        assert_eq!(_check_threshold(&[0, 5], &window, &[], 20, 0, 0, &coverage_threshold(0.4)), Some(0.4));
+        assert_eq!(_check_threshold(&[0, 5], &window, &[], 20, 0, 0, &coverage_threshold(0.5)), None);
+    }
+
+    #[test]
+    fn repeated_windows_serialize_once() {
+        let data_file = write_test_sa("dedup_matches", &["abababab"]);
+        let dir = data_file.parent().unwrap().to_path_buf();
+        std::fs::create_dir_all(dir.join("train")).unwrap();
+        std::fs::write(dir.join("train/train.jsonl"), "{\"text\": \"ababab\"}\n").unwrap();
+        let output = dir.join("matches");
+        let path = |p: &PathBuf| p.display().to_string();
+        let args: BuildMatchesArgs = parse_args(&["test", "--data-file", &path(&data_file), "--trainset", &path(&dir.join("train")),
+                                                  "--output", &path(&output), "--match-size", "4"]);
+        build_matches(&args, &AtomicBool::new(false)).unwrap();
+        // "abab" is found at 0, 2 and 4 by each of the train windows at 0 and 2, and "baba" at 1 and 3, so 8 raw matches
+        // but only 5 distinct positions
+        let mut compressed = Vec::new();
+        File::open(output.join("matches.bin.gz")).unwrap().read_to_end(&mut compressed).unwrap();
+        let mut data = Vec::new();
+        flate2::read::MultiGzDecoder::new(&compressed[..]).read_to_end(&mut data).unwrap();
+        let matches = deserialize_matches(&data, true).unwrap();
+        assert_eq!(matches, vec![(0, 0, 0), (0, 0, 1), (0, 0, 2), (0, 0, 3), (0, 0, 4)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_intervals_joins_overlaps_and_touches() {
+        let intervals = vec![(8, 12), (0, 4), (2, 6), (6, 7), (20, 24)];
+        assert_eq!(_merge_intervals(intervals.clone(), false, 0, MergeAdjacency::Touching), vec![(0, 7), (8, 12), (20, 24)]);
+        // (6, 7) only touches (2, 6), so it stays apart when intervals must overlap
+        assert_eq!(_merge_intervals(intervals.clone(), false, 0, MergeAdjacency::Overlapping), vec![(0, 6), (6, 7), (8, 12), (20, 24)]);
+        assert_eq!(_merge_intervals(intervals, false, 1, MergeAdjacency::Touching), vec![(0, 12), (20, 24)]);
+        assert_eq!(_merge_intervals(Vec::new(), true, 0, MergeAdjacency::Touching), vec![]);
+    }
+
+    #[test]
+    fn min_overlap_bytes_combines_with_the_fraction() {
+        // Two 5-byte windows cover 10 bytes of a 30-byte doc, a third of it
+        let window = WindowSpec::Bytes(5);
+        let check = |fraction: Option<f64>, min_overlap_bytes: usize, mode: ThresholdMode| {
+            let mut threshold = coverage_threshold(0.0);
+            (threshold.fraction, threshold.min_overlap_bytes, threshold.mode) = (fraction, Some(min_overlap_bytes), mode);
+            _check_threshold(&[0, 5], &window, &[], 30, 0, 0, &threshold)
+        };
+        let third = Some(10.0 / 30.0);
+        // Both pass
+        assert_eq!(check(Some(0.3), 10, ThresholdMode::And), third);
+        // Only the bytes pass
+        assert_eq!(check(Some(0.5), 8, ThresholdMode::And), None);
+        assert_eq!(check(Some(0.5), 8, ThresholdMode::Or), third);
+        // Only the fraction passes
+        assert_eq!(check(Some(0.3), 12, ThresholdMode::And), None);
+        assert_eq!(check(Some(0.3), 12, ThresholdMode::Or), third);
+        // Neither does
+        assert_eq!(check(Some(0.5), 12, ThresholdMode::Or), None);
+        // Bytes alone
+        assert_eq!(check(None, 10, ThresholdMode::And), third);
+        assert_eq!(check(None, 11, ThresholdMode::And), None);
+    }
+
+    #[test]
+    fn char_windows_start_and_end_on_char_boundaries() {
+        let doc = "价格是🎉很好 ok 👍🏽";
+        let data_file = write_test_sa("char_windows", &[doc]);
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["text".to_string()];
+        let collect = |char_windows: bool| -> Vec<u64> {
+            let params = MatchParams {char_windows, ..test_match_params(3, &text_fields)};
+            let train = json!({"text": "是🎉很好 ok 👍🏽价格"}).to_string();
+            let (matches, _, _) = collect_matches_from(Box::new(Cursor::new(train.into_bytes())), &PathBuf::from("train.jsonl"), 0,
+                                                       &sas, &params).unwrap();
+            matches[0].iter().map(|(_, _, sa_pos)| *sa_pos).collect()
+        };
+        // 3-byte windows land mid-codepoint, which is what --char-windows is for
+        assert!(collect(false).iter().any(|sa_pos| !doc.is_char_boundary(*sa_pos as usize)));
+        let starts = collect(true);
+        assert!(!starts.is_empty());
+        for sa_pos in starts {
+            let (start, end) = WindowSpec::Chars(3).interval(sa_pos as usize, doc.as_bytes());
+            let span = std::str::from_utf8(&doc.as_bytes()[start..end]).unwrap();
+            assert_eq!(span.chars().count(), 3);
+        }
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn offsets_past_the_table_width_or_text_are_refused() {
+        let doc = "abcdefghij".repeat(30);
+        let data_file = write_test_sa("offset_guard", &[&doc]);
+        let sa = SaFiles::new(&data_file);
+        assert!(check_size_object(&vec![0, 300], &sa).is_ok());
+        // A size object reaching past the text would have docs end in bytes that aren't there
+        assert!(check_size_object(&vec![0, 300, 70_000], &sa).is_err());
+        // 1-byte entries only reach offset 255 of the 300-byte text
+        let mut table_bytes = table_header(1);
+        table_bytes.extend(vec![0u8; doc.len()]);
+        std::fs::write(&sa.table, table_bytes).unwrap();
+        let err = LoadedSa::load(&data_file, false).err().unwrap();
+        assert!(format!("{:#}", err).contains("wider size_width"), "{:#}", err);
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn length_buckets_are_half_open() {
+        let buckets = std::env::temp_dir().join(format!("sa_decontamination_by_length_{}.json", std::process::id()));
+        std::fs::write(&buckets, br#"[{"min_len": 5000, "threshold": 0.4}, {"min_len": 200, "threshold": 0.6}]"#).unwrap();
+        let mut threshold = coverage_threshold(0.9);
+        threshold.by_length = load_length_thresholds(&buckets).unwrap();
+        assert_eq!(threshold.by_length, vec![(200, 0.6), (5000, 0.4)]);
+        // Under every min_len keeps --threshold, and each min_len starts its own bucket
+        assert_eq!(threshold.fraction_for(0), Some(0.9));
+        assert_eq!(threshold.fraction_for(199), Some(0.9));
+        assert_eq!(threshold.fraction_for(200), Some(0.6));
+        assert_eq!(threshold.fraction_for(4999), Some(0.6));
+        assert_eq!(threshold.fraction_for(5000), Some(0.4));
+        assert_eq!(threshold.fraction_for(usize::MAX), Some(0.4));
+        // So 120 covered bytes pass a 200-byte doc but not a 199-byte one
+        let window = WindowSpec::Bytes(120);
+        assert_eq!(_check_threshold(&[0], &window, &[], 200, 0, 0, &threshold), Some(0.6));
+        assert_eq!(_check_threshold(&[0], &window, &[], 199, 0, 0, &threshold), None);
+        std::fs::write(&buckets, br#"[{"min_len": 200, "threshold": 0.6}, {"min_len": 200, "threshold": 0.4}]"#).unwrap();
+        assert!(load_length_thresholds(&buckets).is_err());
+        std::fs::remove_file(&buckets).unwrap();
+    }
+
+    #[test]
+    fn ngram_fraction_sees_through_a_long_repeat() {
+        // The train line repeats "ab" over the first 20 bytes, which coverage counts in full but are only 2 of
+        // the doc's 12 distinct 4-grams
+        let window = WindowSpec::Bytes(4);
+        let doc_text = b"abababababababababab0123456789";
+        let starts: Vec<u64> = (0..17).collect();
+        let doc_matches: Vec<((usize, usize), &[u64])> = vec![((0, 0), &starts)];
+        let mut threshold = coverage_threshold(0.5);
+        let coverage = merge_matches(0, &doc_matches, &window, doc_text, doc_text.len(), 0, &threshold).unwrap();
+        assert_eq!(coverage.contaminates, vec![(0, 0, 0, (20.0 / 30.0) as f32)]);
+        threshold.metric = MatchMetric::NgramFraction;
+        let ngrams = merge_matches(0, &doc_matches, &window, doc_text, doc_text.len(), 0, &threshold).unwrap();
+        assert!(ngrams.contaminates.is_empty());
+        assert_eq!(ngrams.max_fraction, 2.0 / 12.0);
+    }
+
+    #[test]
+    fn empty_and_short_docs_are_never_contaminated() {
+        let window = WindowSpec::Bytes(8);
+        // A 0-byte val doc, where any fraction of it would be 0 bytes
+        assert_eq!(_check_threshold(&[0], &window, &[], 0, 0, 0, &coverage_threshold(0.5)), None);
+        assert_eq!(_check_threshold(&[0], &window, &[], 0, 0, 0, &coverage_threshold(0.0)), None);
+        // A train line one byte short of match_size has no windows and is tallied as short, and an empty one as empty
+        let data_file = write_test_sa("short_docs", &["abcdefgh"]);
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["text".to_string()];
+        let train = "{\"text\": \"abcdefg\"}\n{\"text\": \"\"}\n{\"text\": \"abcdefgh\"}\n";
+        let (matches, _, stats) = collect_matches_from(Box::new(Cursor::new(train.as_bytes().to_vec())), &PathBuf::from("train.jsonl"),
+                                                       0, &sas, &test_match_params(8, &text_fields)).unwrap();
+        assert_eq!(matches[0], vec![(0, 2, 0)]);
+        assert_eq!((stats.skipped_short, stats.text_empty, stats.windows), (1, 1, 1));
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn gap_tolerance_bridges_a_small_edit() {
+        // A 40-byte doc copied with bytes [16, 21) edited: 8-byte windows match up to byte 16 and again from 21
+        let window = WindowSpec::Bytes(8);
+        let starts: Vec<u64> = (0..=8).chain(21..=32).collect();
+        let mut threshold = coverage_threshold(0.9);
+        assert_eq!(_check_threshold(&starts, &window, &[], 40, 0, 0, &threshold), None);
+        threshold.gap_tolerance = 5;
+        assert_eq!(_check_threshold(&starts, &window, &[], 40, 0, 0, &threshold), Some(1.0));
+        // A gap wider than the tolerance stays a gap
+        threshold.gap_tolerance = 4;
+        assert_eq!(_check_threshold(&starts, &window, &[], 40, 0, 0, &threshold), None);
+    }
+
+    #[test]
+    fn fast_reject_agrees_with_naive_coverage() {
+        // Random groups scored by _check_threshold (fast reject, dedup, merge) and by marking covered bytes one by one
+        let mut rng = StdRng::seed_from_u64(301);
+        for _ in 0..2000 {
+            let (doc_size, width, gap_tolerance) = (rng.gen_range(1..200), rng.gen_range(1..20), rng.gen_range(0..4));
+            let starts: Vec<u64> = (0..rng.gen_range(0..30)).map(|_| rng.gen_range(0..doc_size as u64)).collect();
+            let mut threshold = coverage_threshold(rng.gen_range(0.0..1.0));
+            threshold.gap_tolerance = gap_tolerance;
+            threshold.min_overlap_bytes = rng.gen_bool(0.5).then(|| rng.gen_range(0..100));
+            threshold.mode = if rng.gen_bool(0.5) { ThresholdMode::And } else { ThresholdMode::Or };
+
+            let mut covered = vec![false; doc_size + width];
+            for start in &starts {
+                covered[*start as usize..*start as usize + width].fill(true);
+            }
+            // Gaps of up to gap_tolerance bytes between covered runs count as covered
+            let mut last_end: Option<usize> = None;
+            for pos in 0..covered.len() {
+                if covered[pos] {
+                    if let Some(end) = last_end.filter(|end| pos > *end && pos - end <= gap_tolerance) {
+                        covered[end..pos].fill(true);
+                    }
+                } else if pos > 0 && covered[pos - 1] {
+                    last_end = Some(pos);
+                }
+            }
+            let num_covered = covered.iter().filter(|c| **c).count();
+            let checks = [Some(num_covered >= (doc_size as f64 * threshold.fraction.unwrap()).ceil() as usize),
+                          threshold.min_overlap_bytes.map(|min_bytes| num_covered >= min_bytes)];
+            let passes = match threshold.mode {
+                ThresholdMode::And => checks.iter().flatten().all(|ok| *ok),
+                ThresholdMode::Or => checks.iter().flatten().any(|ok| *ok)
+            };
+            let naive = passes.then(|| num_covered as f64 / doc_size as f64);
+            assert_eq!(_check_threshold(&starts, &WindowSpec::Bytes(width), &[], doc_size, 0, 0, &threshold), naive,
+                       "starts {:?} of width {} over {} bytes, {:?}", starts, width, doc_size, threshold);
+        }
+    }
+
+    #[test]
+    fn windows_across_two_tiny_docs_are_dropped() {
+        // "defghi" is only in the SA as the end of one 6-byte doc run into the start of the next
+        let data_file = write_test_sa("crossing", &["abcdef", "ghijkl"]);
+        let dir = data_file.parent().unwrap().to_path_buf();
+        std::fs::create_dir_all(dir.join("train")).unwrap();
+        std::fs::write(dir.join("train/train.jsonl"), "{\"text\": \"defghi\"}\n{\"text\": \"abcdef\"}\n").unwrap();
+        let path = |p: &PathBuf| p.display().to_string();
+        let args: BuildMatchesArgs = parse_args(&["test", "--data-file", &path(&data_file), "--trainset", &path(&dir.join("train")),
+                                                  "--output", &path(&dir.join("matches")), "--match-size", "6"]);
+        build_matches(&args, &AtomicBool::new(false)).unwrap();
+        let mut matches = Vec::new();
+        flate2::read::MultiGzDecoder::new(File::open(dir.join("matches/matches.bin.gz")).unwrap()).read_to_end(&mut matches).unwrap();
+        assert_eq!(deserialize_matches(&matches, true).unwrap(), vec![(0, 0, 3), (0, 1, 0)]);
+        let args: MarkContaminatesArgs = parse_args(&["test", "--data-file", &path(&data_file),
+                                                      "--match-location", &path(&dir.join("matches/matches.bin.gz")),
+                                                      "--output", &path(&dir.join("contaminates")), "--threshold", "0.5",
+                                                      "--format", "jsonl"]);
+        mark_contaminates(&args).unwrap();
+        let mut jsonl = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(dir.join("contaminates/contaminates.jsonl.gz")).unwrap())
+            .read_to_string(&mut jsonl)
+            .unwrap();
+        let found: Vec<(u64, u64)> = jsonl.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .map(|record| (record["val_doc_id"].as_u64().unwrap(), record["line_num"].as_u64().unwrap()))
+            .collect();
+        // Only the real copy of doc 0, and neither doc from the crossing window
+        assert_eq!(found, vec![(0, 1)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conversation_content_is_joined_across_turns() {
+        let conversation = json!({"messages": [{"role": "user", "content": "What is the boiling point of water?"},
+                                               {"role": "assistant", "content": "100 degrees Celsius at sea level."},
+                                               {"role": "user"}]});
+        let joined = "What is the boiling point of water?\n\n100 degrees Celsius at sea level.";
+        assert_eq!(extract_text(&conversation, "messages[].content", "\n\n").as_deref(), Some(joined));
+        assert_eq!(extract_text(&json!({"messages": []}), "messages[].content", "\n\n"), None);
+        assert_eq!(missing_text(&json!({"messages": []}), &["messages[].content".to_string()]), MissingText::Absent);
+        // An SA built from the joined turns lines up with train lines extracted the same way
+        let data_file = write_test_sa("messages", &[joined]);
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["messages[].content".to_string()];
+        let train = format!("{}\n{}\n", conversation, json!({"messages": []}));
+        let (matches, _, stats) = collect_matches_from(Box::new(Cursor::new(train.into_bytes())), &PathBuf::from("train.jsonl"),
+                                                       0, &sas, &test_match_params(joined.len(), &text_fields)).unwrap();
+        assert_eq!(matches[0], vec![(0, 0, 0)]);
+        assert_eq!(stats.text_absent, 1);
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn joined_turns_longer_than_their_line_pass_min_doc_len() {
+        // A long separator makes the joined text longer than the json line it came from
+        let separator = "-".repeat(80);
+        let conversation = json!({"messages": [{"content": "first turn"}, {"content": "second turn"}]}).to_string();
+        let joined = format!("first turn{}second turn", separator);
+        assert!(conversation.len() < joined.len());
+        let data_file = write_test_sa("joined_min_len", &[&joined]);
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["messages[].content".to_string()];
+        let params = MatchParams {field_separator: &separator, min_doc_len: conversation.len() + 1,
+                                  ..test_match_params(joined.len(), &text_fields)};
+        let (matches, _, stats) = collect_matches_from(Box::new(Cursor::new(format!("{}\n", conversation).into_bytes())),
+                                                       &PathBuf::from("train.jsonl"), 0, &sas, &params).unwrap();
+        assert_eq!(matches[0], vec![(0, 0, 0)]);
+        assert_eq!(stats.skipped_min_len, 0);
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn latin1_round_trips_through_the_sa_and_matching() {
+        let dir = std::env::temp_dir().join(format!("sa_decontamination_latin1_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("val")).unwrap();
+        // "café naïve résumé" with each accented letter a single latin-1 byte
+        let latin1: Vec<u8> = [&b"{\"text\": \"caf"[..], &[0xe9], b" na", &[0xef], b"ve r", &[0xe9], b"sum", &[0xe9], b"\"}\n"].concat();
+        std::fs::write(dir.join("val/val.jsonl"), &latin1).unwrap();
+        let data_file = dir.join("val.bin");
+        let path = |p: &PathBuf| p.display().to_string();
+        let args: BuildSaArgs = parse_args(&["test", "--input", &path(&dir.join("val")), "--output", &path(&data_file),
+                                             "--encoding", "latin-1"]);
+        build_sa(&args).unwrap();
+        // Positions are in the decoded UTF-8, where each accented letter is two bytes
+        let decoded = "café naïve résumé";
+        assert_eq!(std::fs::read(&data_file).unwrap(), decoded.as_bytes());
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["text".to_string()];
+        let params = MatchParams {encoding: TextEncoding::Latin1, ..test_match_params(decoded.len(), &text_fields)};
+        let (matches, _, _) = collect_matches_from(Box::new(Cursor::new(latin1)), &PathBuf::from("train.jsonl"), 0, &sas, &params).unwrap();
+        assert_eq!(matches[0], vec![(0, 0, 0)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn min_distinct_windows_counts_distinct_starts() {
+        let window = WindowSpec::Bytes(4);
+        let mut threshold = coverage_threshold(0.0);
+        (threshold.fraction, threshold.min_distinct_windows) = (None, Some(3));
+        // K - 1 distinct hits, however often they repeat, then K
+        assert_eq!(_check_threshold(&[0, 0, 10, 10], &window, &[], 100, 0, 0, &threshold), None);
+        assert_eq!(_check_threshold(&[0, 10, 20], &window, &[], 100, 0, 0, &threshold), Some(0.12));
+        // Combined with a fraction the K hits don't reach
+        threshold.fraction = Some(0.5);
+        assert_eq!(_check_threshold(&[0, 10, 20], &window, &[], 100, 0, 0, &threshold), None);
+        threshold.mode = ThresholdMode::Or;
+        assert_eq!(_check_threshold(&[0, 10, 20], &window, &[], 100, 0, 0, &threshold), Some(0.12));
+        assert_eq!(_check_threshold(&[0, 10], &window, &[], 100, 0, 0, &threshold), None);
+    }
+
+    #[test]
+    fn non_object_lines_are_malformed_unless_taken_as_text() {
+        let data_file = write_test_sa("json_kinds", &["hello world"]);
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["text".to_string()];
+        // One line of each kind of top-level json
+        let train = "{\"text\": \"hello world\"}\n\"hello world\"\n42\ntrue\nnull\n[\"hello world\"]\n";
+        let collect = |json_string_as_text: bool| {
+            let params = MatchParams {json_string_as_text, ..test_match_params(11, &text_fields)};
+            collect_matches_from(Box::new(Cursor::new(train.as_bytes().to_vec())), &PathBuf::from("train.jsonl"), 0, &sas, &params).unwrap()
+        };
+        let (matches, _, stats) = collect(false);
+        assert_eq!(matches[0], vec![(0, 0, 0)]);
+        assert_eq!(stats.malformed, 5);
+        // A bare string is then the doc's text, and the rest are still skipped
+        let (matches, _, stats) = collect(true);
+        assert_eq!(matches[0], vec![(0, 0, 0), (0, 1, 0)]);
+        assert_eq!(stats.malformed, 4);
+        std::fs::remove_dir_all(data_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn min_span_drops_isolated_windows() {
+        // Eight isolated 4-byte hits cover 32 of 64 bytes, but none is part of a longer copied passage
+        let window = WindowSpec::Bytes(4);
+        let scattered: Vec<u64> = (0..8).map(|i| i * 8).collect();
+        let mut threshold = coverage_threshold(0.5);
+        assert_eq!(_check_threshold(&scattered, &window, &[], 64, 0, 0, &threshold), Some(0.5));
+        // A --min-span of match_size changes nothing
+        threshold.min_span = Some(4);
+        assert_eq!(_check_threshold(&scattered, &window, &[], 64, 0, 0, &threshold), Some(0.5));
+        threshold.min_span = Some(5);
+        assert_eq!(_check_threshold(&scattered, &window, &[], 64, 0, 0, &threshold), None);
+        // One contiguous 32-byte passage still passes
+        let contiguous: Vec<u64> = (0..=28).collect();
+        threshold.min_span = Some(16);
+        assert_eq!(_check_threshold(&contiguous, &window, &[], 64, 0, 0, &threshold), Some(0.5));
+    }
+
+    #[test]
+    fn case_folded_offsets_show_the_original_text() {
+        let dir = std::env::temp_dir().join(format!("sa_decontamination_case_fold_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("val")).unwrap();
+        std::fs::create_dir_all(dir.join("train")).unwrap();
+        let docs = ["The Quick Brown Fox", "Jumps Over THE lazy dog"];
+        let val: String = docs.iter().map(|doc| format!("{}\n", json!({"text": doc}))).collect();
+        std::fs::write(dir.join("val/val.jsonl"), val).unwrap();
+        let data_file = dir.join("val.bin");
+        let path = |p: &PathBuf| p.display().to_string();
+        let args: BuildSaArgs = parse_args(&["test", "--input", &path(&dir.join("val")), "--output", &path(&data_file),
+                                             "--case-insensitive"]);
+        build_sa(&args).unwrap();
+        // The table's text is folded and the original kept beside it, byte for byte the same length
+        let folded = std::fs::read(&data_file).unwrap();
+        let original = std::fs::read(original_text_path(&data_file)).unwrap();
+        assert_eq!(original, docs.concat().into_bytes());
+        assert_eq!(folded.len(), original.len());
+        assert_eq!(folded, original.to_ascii_lowercase());
+
+        // A lowercase train line finds doc 1 where it starts in either text
+        let train = format!("{}\n", json!({"text": "jumps over the lazy dog"}));
+        let sas = vec![LoadedSa::load(&data_file, false).unwrap()];
+        let text_fields = vec!["text".to_string()];
+        let params = MatchParams {case_insensitive: true, ..test_match_params(docs[1].len(), &text_fields)};
+        let (matches, _, _) = collect_matches_from(Box::new(Cursor::new(train.clone().into_bytes())), &PathBuf::from("train.jsonl"),
+                                                   0, &sas, &params).unwrap();
+        assert_eq!(matches[0], vec![(0, 0, docs[0].len() as u64)]);
+        assert_eq!(&original[docs[0].len()..], docs[1].as_bytes());
+
+        // Spans and spotchecks are cut from the original text at the folded text's offsets
+        std::fs::write(dir.join("train/train.jsonl"), train).unwrap();
+        let args: BuildMatchesArgs = parse_args(&["test", "--data-file", &path(&data_file), "--trainset", &path(&dir.join("train")),
+                                                  "--output", &path(&dir.join("matches")), "--match-size", "10",
+                                                  "--case-insensitive"]);
+        build_matches(&args, &AtomicBool::new(false)).unwrap();
+        let args: MarkContaminatesArgs = parse_args(&["test", "--data-file", &path(&data_file),
+                                                      "--match-location", &path(&dir.join("matches/matches.bin.gz")),
+                                                      "--output", &path(&dir.join("contaminates")), "--threshold", "0.5",
+                                                      "--dump-spans", &path(&dir.join("spans.jsonl")),
+                                                      "--spotcheck", "1", "--spotcheck-output", &path(&dir.join("spotcheck.jsonl"))]);
+        mark_contaminates(&args).unwrap();
+        let spans: Value = serde_json::from_slice(&std::fs::read(dir.join("spans.jsonl")).unwrap()).unwrap();
+        assert_eq!(spans["val_doc_id"], json!(1));
+        assert_eq!(spans["spans"], json!([{"start": 0, "end": docs[1].len(), "text": docs[1]}]));
+        let spotcheck: Value = serde_json::from_slice(&std::fs::read(dir.join("spotcheck.jsonl")).unwrap()).unwrap();
+        assert_eq!(spotcheck["val_span"], json!([0, docs[1].len()]));
+        assert_eq!(spotcheck["val_match"], json!(docs[1]));
+        assert_eq!(spotcheck["val_context"], json!(docs[1]));
+        assert_eq!(spotcheck["train_offset"], json!(0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
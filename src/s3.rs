@@ -1,10 +1,13 @@
 // General tools for interfacing with s3
+use std::cmp;
 use std::path::{PathBuf, Path};
-use anyhow::{Result};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use anyhow::{anyhow, Result};
 
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::{Client, Error as S3Error};
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
 use aws_sdk_s3::operation::put_object::PutObjectOutput;
 use aws_sdk_s3::primitives::ByteStream;
 use async_compression::tokio::bufread::GzipDecoder as asyncGZ;
@@ -14,6 +17,7 @@ use rand::{Rng};
 use tokio::io::AsyncReadExt;
 use tokio::io::BufReader as tBufReader;
 use tokio::time::{Duration, sleep};
+use tracing::{error, warn};
 
 
 /*==========================================================
@@ -49,29 +53,52 @@ pub(crate) fn split_s3_path<P: AsRef<Path>>(path: P) -> (String, String) {
 ============================================================*/
 
 
-async fn s3_retry<T, F, Fut>(max_retries: usize, mut operation: F) -> Result<T, S3Error> 
+static S3_RETRIES: AtomicUsize = AtomicUsize::new(5);
+static S3_INITIAL_BACKOFF_MS: AtomicU64 = AtomicU64::new(100);
+
+pub fn set_s3_retry_policy(retries: usize, initial_backoff_ms: u64) {
+    // Process-wide, since GETs and PUTs happen deep inside every subcommand
+    S3_RETRIES.store(retries, Ordering::Relaxed);
+    S3_INITIAL_BACKOFF_MS.store(initial_backoff_ms, Ordering::Relaxed);
+}
+
+
+fn is_retryable<E: ProvideErrorMetadata>(err: &SdkError<E>) -> bool {
+    // Timeouts, dropped connections, throttling and 5xxs are worth another go. Anything else
+    // (missing key, access denied, bad request) will fail the same way every time
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(service_err) => {
+            let status = service_err.raw().status().as_u16();
+            status == 429 || status >= 500 ||
+                matches!(service_err.err().code(), Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestTimeout"))
+        },
+        _ => false
+    }
+}
+
+
+async fn s3_retry<T, E, F, Fut>(s3_uri: &str, max_retries: usize, mut operation: F) -> Result<T> 
 where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
     F: FnMut() -> Fut,
-    Fut: std::future::Future<Output = Result<T, S3Error>>,
+    Fut: std::future::Future<Output = Result<T, SdkError<E>>>,
 {
-    let mut rng = rand::thread_rng();
-    let base_delay = Duration::from_millis(100);
-    let max_delay = Duration::from_millis(2000);    
+    let base_delay = Duration::from_millis(S3_INITIAL_BACKOFF_MS.load(Ordering::Relaxed));
+    let max_delay = cmp::max(Duration::from_millis(2000), base_delay);
     let mut attempts = 0;
     loop {
         match operation().await {
             Ok(result) => return Ok(result),
-            Err(err) if attempts < max_retries => {
-                println!("Error {}/{}: {}", err, attempts, max_retries);
-                let random_delay =  rng.gen_range(Duration::from_millis(0)..Duration::from_millis(1000));
-                let mut exponential_delay = base_delay * 2u32.pow(attempts as u32);
-                if exponential_delay > max_delay {
-                    exponential_delay = max_delay;
-                }
+            Err(err) if attempts < max_retries && is_retryable(&err) => {
+                warn!("Error on {} (retry {}/{}): {}", s3_uri, attempts + 1, max_retries, DisplayErrorContext(&err));
+                // A fresh handle each time, since ThreadRng can't be held across an await in a Send future
+                let random_delay = rand::thread_rng().gen_range(Duration::from_millis(0)..=base_delay);
+                let exponential_delay = cmp::min(base_delay * 2u32.pow(attempts as u32), max_delay);
                 sleep(exponential_delay + random_delay).await;
                 attempts += 1;
             }, 
-            Err(err) => return Err(err.into())
+            Err(err) => return Err(anyhow!("{} failed after {} attempt(s): {}", s3_uri, attempts + 1, DisplayErrorContext(&err)))
         }
     }
 }
@@ -88,45 +115,31 @@ pub(crate) async fn get_s3_client() -> Result<Client, S3Error> {
 }
 
 
-pub(crate) async fn expand_s3_dir(s3_uri: &PathBuf, valid_exts: &[&str]) -> Result<Vec<PathBuf>, S3Error> {
-    // Collects all .json.gz/.jsonl.gz files prefixed by the provided s3_uri 
-    let mut s3_files: Vec<PathBuf> = Vec::new();
+pub(crate) async fn list_s3_page(bucket: &str, prefix: &str, continuation_token: Option<String>
+                                 ) -> Result<(Vec<String>, Option<String>), S3Error> {
+    // One page of the keys under bucket/prefix, starting from continuation_token, and the token for the next
+    // page if the listing was cut short
     let client = get_s3_client().await?;
-    let (bucket, prefix) = split_s3_path(s3_uri);
-
-    //let ext = ext.unwrap_or(".jsonl.gz");
-    let mut response = client
-        .list_objects_v2()    
-        .bucket(bucket.to_owned())
-        .prefix(prefix.to_owned())
-        .into_paginator()
-        .send();
-
-    while let Some(result) = response.next().await {
-        match result {
-            Ok(output) => {
-                for object in output.contents() {
-                    let key = object.key().unwrap_or_default();     
-                    if valid_exts.iter().any(|ext| key.ends_with(ext)) {
-	                    let mut s3_file = PathBuf::from("s3://");
-	                    s3_file.push(bucket.clone());
-	                    s3_file.push(key);
-	                    s3_files.push(s3_file);
-                    }
-                }
-            }
-            Err(err) => {
-                eprintln!("Error collecting S3 files | {err:?}");
-                return Err(err.into());
-            }
+    let output = match client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .set_continuation_token(continuation_token)
+        .send()
+        .await {
+        Ok(output) => output,
+        Err(err) => {
+            error!("Error collecting S3 files | {err:?}");
+            return Err(err.into());
         }
-    }
-    Ok(s3_files)
+    };
+    let keys = output.contents().iter().map(|object| object.key().unwrap_or_default().to_string()).collect();
+    Ok((keys, output.next_continuation_token().map(String::from)))
 }
 
-async fn get_object_with_retry(bucket: &str, key: &str, num_retries: usize) -> Result<ByteStream, S3Error> {
+async fn get_object_with_retry(bucket: &str, key: &str, num_retries: usize) -> Result<ByteStream> {
     let client = get_s3_client().await?;
-    s3_retry(num_retries, || async {
+    s3_retry(&format!("GET s3://{}/{}", bucket, key), num_retries, || async {
         let output = client.get_object().bucket(bucket).key(key).send().await?;
         Ok(output.body)        
     })
@@ -135,20 +148,25 @@ async fn get_object_with_retry(bucket: &str, key: &str, num_retries: usize) -> R
 
 
 
-pub(crate) async fn get_reader_from_s3<P: AsRef<Path>>(path: P, num_retries: Option<usize>) -> Result<BufReader<Cursor<Vec<u8>>>, S3Error>{
-    // Gets all the data from an S3 file and loads it into memory and returns a Bufreader over it
+pub(crate) async fn get_reader_from_s3<P: AsRef<Path>>(path: P, num_retries: Option<usize>) -> Result<BufReader<Cursor<Vec<u8>>>>{
+    // Gets all the data from an S3 file and loads it into memory and returns a Bufreader over it.
+    // num_retries defaults to the process-wide --s3-retries
     let (s3_bucket, s3_key) = split_s3_path(&path);
-    let object_body = get_object_with_retry(&s3_bucket, &s3_key, num_retries.unwrap_or(5)).await?;
+    let num_retries = num_retries.unwrap_or_else(|| S3_RETRIES.load(Ordering::Relaxed));
+    let object_body = get_object_with_retry(&s3_bucket, &s3_key, num_retries).await?;
     let body_stream = object_body.into_async_read();
     let mut data = Vec::new();
 
     if (path.as_ref().extension().unwrap() == "zstd") || (path.as_ref().extension().unwrap() == "zst") {
-        let zstd = asyncZstd::new(body_stream);
+        // Concatenated frames/members (e.g. appended match shards) should all be read, not just the first
+        let mut zstd = asyncZstd::new(body_stream);
+        zstd.multiple_members(true);
         let mut reader = tBufReader::with_capacity(1024 * 1024, zstd);
         reader.read_to_end(&mut data).await.expect("Failed to read data {:path}");
 
     } else if path.as_ref().extension().unwrap() == "gz" {
-        let gz = asyncGZ::new(body_stream);
+        let mut gz = asyncGZ::new(body_stream);
+        gz.multiple_members(true);
         let mut reader = tBufReader::with_capacity(1024 * 1024, gz);
         reader.read_to_end(&mut data).await.expect("Failed to read data {:path}");        
     } else {
@@ -162,20 +180,106 @@ pub(crate) async fn get_reader_from_s3<P: AsRef<Path>>(path: P, num_retries: Opt
 }
 
 
+pub(crate) async fn get_bytes_from_s3<P: AsRef<Path>>(path: P, num_retries: Option<usize>) -> Result<Vec<u8>> {
+    // An S3 file's bytes as stored, compressed or not, for callers that decompress as they read.
+    // num_retries defaults to the process-wide --s3-retries
+    let (s3_bucket, s3_key) = split_s3_path(&path);
+    let num_retries = num_retries.unwrap_or_else(|| S3_RETRIES.load(Ordering::Relaxed));
+    let object_body = get_object_with_retry(&s3_bucket, &s3_key, num_retries).await?;
+    let mut reader = tBufReader::with_capacity(1024 * 1024, object_body.into_async_read());
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+
+
+pub(crate) async fn s3_object_exists(s3_uri: &Path) -> Result<bool, S3Error> {
+    // HEADs the object, treating a NotFound as a clean `false` rather than an error
+    let (s3_bucket, s3_key) = split_s3_path(s3_uri);
+    let client = get_s3_client().await?;
+    match client.head_object().bucket(s3_bucket).key(s3_key).send().await {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().is_some_and(|e| e.is_not_found()) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+
+pub(crate) async fn s3_object_size(s3_uri: &PathBuf) -> Result<u64> {
+    // HEADs the object for its size in bytes, retried like a GET
+    let (s3_bucket, s3_key) = split_s3_path(s3_uri);
+    let client = get_s3_client().await?;
+    s3_retry(&format!("HEAD {}", s3_uri.display()), S3_RETRIES.load(Ordering::Relaxed), || async {
+        let output = client.head_object().bucket(&s3_bucket).key(&s3_key).send().await?;
+        Ok(output.content_length().unwrap_or(0) as u64)
+    })
+    .await
+}
+
 
-pub(crate) async fn write_cursor_to_s3(s3_uri: &PathBuf, cursor: Cursor<Vec<u8>>) -> Result<PutObjectOutput, S3Error> {
+pub(crate) async fn write_cursor_to_s3(s3_uri: &PathBuf, cursor: Cursor<Vec<u8>>) -> Result<PutObjectOutput> {
     let (s3_bucket, s3_key) = split_s3_path(s3_uri);
     let client = get_s3_client().await?;
-    let bytes = ByteStream::from(cursor.into_inner());
-    let response = client
+    let bytes = cursor.into_inner();
+    // A ByteStream is consumed by the send, so each attempt gets its own copy of the body
+    let response = s3_retry(&format!("PUT s3://{}/{}", s3_bucket, s3_key), S3_RETRIES.load(Ordering::Relaxed), || async {
+        client
             .put_object()
-            .bucket(s3_bucket)
-            .key(s3_key)
-            .body(bytes)
+            .bucket(&s3_bucket)
+            .key(&s3_key)
+            .body(ByteStream::from(bytes.clone()))
             .send()
-            .await?;   
+            .await
+    })
+    .await?;
 
     Ok(response)
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::operation::get_object::GetObjectError;
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_and_others_are_not() {
+        // Times out twice, then succeeds
+        let mut calls = 0;
+        let result = s3_retry("GET s3://bucket/flaky", 3, || {
+            calls += 1;
+            let attempt = calls;
+            async move {
+                match attempt {
+                    1 | 2 => Err(SdkError::<GetObjectError>::timeout_error("timed out")),
+                    _ => Ok(attempt)
+                }
+            }
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+        // A request that can't be built fails the same way every time, so it isn't retried
+        let mut calls = 0;
+        let result: Result<()> = s3_retry("GET s3://bucket/broken", 3, || {
+            calls += 1;
+            async { Err(SdkError::<GetObjectError>::construction_failure("bad request")) }
+        }).await;
+        assert_eq!(calls, 1);
+        assert!(result.unwrap_err().to_string().contains("s3://bucket/broken failed after 1 attempt(s)"));
+        // Out of retries, the last error comes back with the key
+        let mut calls = 0;
+        let result: Result<()> = s3_retry("GET s3://bucket/down", 1, || {
+            calls += 1;
+            async { Err(SdkError::<GetObjectError>::timeout_error("timed out")) }
+        }).await;
+        assert_eq!(calls, 2);
+        assert!(result.unwrap_err().to_string().contains("s3://bucket/down failed after 2 attempt(s)"));
+    }
+}
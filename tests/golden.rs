@@ -0,0 +1,191 @@
+// End to end over a tiny fixture: build_sa over three val docs, build_matches against a trainset holding one
+// full copy, one partial copy and one clean doc, then mark_contaminates at a known threshold
+
+use clap::Parser;
+use sa_decontamination::{build_sa, build_matches, mark_contaminates, BuildSaArgs, BuildMatchesArgs, MarkContaminatesArgs};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::AtomicBool;
+
+
+#[derive(Parser)]
+struct BuildSaCli {
+    #[command(flatten)]
+    args: BuildSaArgs
+}
+
+#[derive(Parser)]
+struct BuildMatchesCli {
+    #[command(flatten)]
+    args: BuildMatchesArgs
+}
+
+#[derive(Parser)]
+struct MarkContaminatesCli {
+    #[command(flatten)]
+    args: MarkContaminatesArgs
+}
+
+
+const VAL_DOCS: [&str; 3] = ["The capital of France is Paris and it is lovely in spring.",
+                             "Water boils at one hundred degrees Celsius at sea level.",
+                             "Photosynthesis converts light energy into chemical energy."];
+
+const TRAIN_DOCS: [&str; 3] = [
+    // All of val doc 0
+    "The capital of France is Paris and it is lovely in spring.",
+    // The first 35 bytes of val doc 1, "Water boils at one hundred degrees "
+    "Water boils at one hundred degrees and then something else entirely happens.",
+    // Nothing from the val set
+    "Zebras are known for their black and white stripes."
+];
+
+
+fn write_jsonl(path: &PathBuf, docs: &[&str]) {
+    let lines: Vec<String> = docs.iter().enumerate()
+        .map(|(idx, doc)| serde_json::json!({"id": format!("question-{}", idx), "text": doc}).to_string())
+        .collect();
+    std::fs::write(path, lines.join("\n") + "\n").unwrap();
+}
+
+
+fn build_fixture(name: &str, build_sa_args: &[&str]) -> PathBuf {
+    // Builds the fixture's SA (with any extra build_sa_args) and matches in a fresh temp dir, and returns the dir
+    let dir = std::env::temp_dir().join(format!("sa_decontamination_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("val")).unwrap();
+    std::fs::create_dir_all(dir.join("train")).unwrap();
+    std::fs::create_dir_all(dir.join("sa")).unwrap();
+    write_jsonl(&dir.join("val/val.jsonl"), &VAL_DOCS);
+    write_jsonl(&dir.join("train/train.jsonl"), &TRAIN_DOCS);
+    let (input, output) = (path(&dir.join("val")), path(&dir.join("sa/val.bin")));
+    let argv = ["build-sa", "--input", &input, "--output", &output].into_iter().chain(build_sa_args.iter().copied());
+    build_sa(&BuildSaCli::parse_from(argv).args).unwrap();
+    build_matches(&BuildMatchesCli::parse_from(["build-matches", "--data-file", &path(&dir.join("sa/val.bin")),
+                                                "--trainset", &path(&dir.join("train")), "--output", &path(&dir.join("matches")),
+                                                "--match-size", "10"]).args,
+                  &AtomicBool::new(false))
+        .unwrap();
+    dir
+}
+
+
+fn mark_fixture(dir: &PathBuf, output: &str, format: &str, group_mode: &str) -> PathBuf {
+    // Marks the fixture's matches at a 0.5 threshold into dir/output
+    let contaminates = dir.join(output);
+    mark_contaminates(&MarkContaminatesCli::parse_from(["mark-contaminates", "--data-file", &path(&dir.join("sa/val.bin")),
+                                                        "--match-location", &path(&dir.join("matches/matches.bin.gz")),
+                                                        "--output", &path(&contaminates), "--threshold", "0.5",
+                                                        "--format", format, "--group-mode", group_mode]).args)
+        .unwrap();
+    contaminates
+}
+
+
+fn path(p: &PathBuf) -> String {
+    p.display().to_string()
+}
+
+
+fn read_gz(path: &PathBuf) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    flate2::read::MultiGzDecoder::new(std::fs::File::open(path).unwrap()).read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+
+fn read_contaminates(contaminates: &PathBuf) -> Vec<(u64, u64, f64)> {
+    // (val_doc_id, line_num, coverage) of each contaminate in a jsonl mark_contaminates output, sorted
+    let jsonl = String::from_utf8(read_gz(&contaminates.join("contaminates.jsonl.gz"))).unwrap();
+    let mut found: Vec<(u64, u64, f64)> = jsonl.lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .map(|record| (record["val_doc_id"].as_u64().unwrap(), record["line_num"].as_u64().unwrap(),
+                       record["coverage"].as_f64().unwrap()))
+        .collect();
+    found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    found
+}
+
+
+#[test]
+fn golden_build_sa_matches_and_contaminates() {
+    let dir = build_fixture("golden", &[]);
+    let contaminates = mark_fixture(&dir, "contaminates", "jsonl", "map");
+    let found = read_contaminates(&contaminates);
+    // The full copy covers all of val doc 0 and the partial one 35 of val doc 1's 56 bytes. The clean train doc
+    // and val doc 2 never come up
+    assert_eq!(found.iter().map(|(val_doc_id, line_num, _)| (*val_doc_id, *line_num)).collect::<Vec<_>>(), vec![(0, 0), (1, 1)]);
+    assert!((found[0].2 - 1.0).abs() < 1e-6, "{:?}", found);
+    assert!((found[1].2 - 35.0 / 56.0).abs() < 1e-6, "{:?}", found);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn reruns_write_identical_matches_and_contaminates() {
+    // Two build_matches runs over the same input write the same matches
+    let (first, second) = (build_fixture("rerun_a", &[]), build_fixture("rerun_b", &[]));
+    let matches = std::fs::read(first.join("matches/matches.bin.gz")).unwrap();
+    assert!(!matches.is_empty());
+    assert_eq!(matches, std::fs::read(second.join("matches/matches.bin.gz")).unwrap());
+    // The contaminates file carries its matches' run_id, so reruns are compared over the same matches
+    let (marked_a, marked_b) = (mark_fixture(&first, "contaminates_a", "bincode", "map"), mark_fixture(&first, "contaminates_b", "bincode", "map"));
+    let contaminates = std::fs::read(marked_a.join("contaminates.bin.gz")).unwrap();
+    assert!(!contaminates.is_empty());
+    assert_eq!(contaminates, std::fs::read(marked_b.join("contaminates.bin.gz")).unwrap());
+    std::fs::remove_dir_all(&first).unwrap();
+    std::fs::remove_dir_all(&second).unwrap();
+}
+
+
+#[test]
+fn trainset_from_stdin_matches_the_same_file() {
+    let dir = build_fixture("stdin", &[]);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sa_decontamination"))
+        .args(["build-matches", "--data-file", &path(&dir.join("sa/val.bin")), "--trainset", "-",
+               "--output", &path(&dir.join("stdin_matches")), "--match-size", "10"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&std::fs::read(dir.join("train/train.jsonl")).unwrap()).unwrap();
+    assert!(child.wait().unwrap().success());
+    // stdin is the one path, at path id 0 like the file was, with the same line numbers
+    assert_eq!(std::fs::read(dir.join("stdin_matches/matches.bin.gz")).unwrap(),
+               std::fs::read(dir.join("matches/matches.bin.gz")).unwrap());
+    let paths: Value = serde_json::from_slice(&read_gz(&dir.join("stdin_matches/paths.json.gz"))).unwrap();
+    assert_eq!(paths["paths"], serde_json::json!(["-"]));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn prefixed_docs_are_covered_by_body_length() {
+    // Each doc is "question-N\n" then its text, and coverage should still be of the text alone
+    let dir = build_fixture("prefix_field", &["--prefix-field", "id"]);
+    let header_lens: Vec<u64> = std::fs::read(dir.join("sa/val.bin.body_offsets")).unwrap()
+        .chunks(8)
+        .map(|len| u64::from_ne_bytes(len.try_into().unwrap()))
+        .collect();
+    assert_eq!(header_lens, vec![11, 11, 11]);
+    let found = read_contaminates(&mark_fixture(&dir, "contaminates", "jsonl", "map"));
+    assert_eq!(found.iter().map(|(val_doc_id, line_num, _)| (*val_doc_id, *line_num)).collect::<Vec<_>>(), vec![(0, 0), (1, 1)]);
+    assert!((found[0].2 - 1.0).abs() < 1e-6, "{:?}", found);
+    assert!((found[1].2 - 35.0 / 56.0).abs() < 1e-6, "{:?}", found);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn group_modes_agree() {
+    let dir = build_fixture("group_modes", &[]);
+    let by_map = std::fs::read(mark_fixture(&dir, "map", "bincode", "map").join("contaminates.bin.gz")).unwrap();
+    for group_mode in ["sort", "external"] {
+        let contaminates = mark_fixture(&dir, group_mode, "bincode", group_mode);
+        assert_eq!(std::fs::read(contaminates.join("contaminates.bin.gz")).unwrap(), by_map, "--group-mode {}", group_mode);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}